@@ -0,0 +1,168 @@
+//! Comparison of two easy-fs images: the geometry in their super blocks,
+//! their bitmap usage, and the files added, removed or changed from the first
+//! to the second, with the byte ranges in which changed files differ.
+
+use easy_fs::Inode;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+/// Size of the chunks files are compared in
+const CHUNK_SZ: usize = 64 * 512;
+/// Ranges shown per changed file, the others are only counted
+const MAX_RANGES_SHOWN: usize = 8;
+
+/// Collect the inodes under a directory by path, those of directories end with '/'.
+/// A directory already `visited` is listed but not entered again, as directories
+/// may be hard linked into their own subtree.
+fn collect(
+    dir: &Inode,
+    path: &str,
+    entries: &mut BTreeMap<String, Arc<Inode>>,
+    visited: &mut BTreeSet<u32>,
+) {
+    for entry in dir.read_dir(0) {
+        let inode = match dir.find(&entry.name) {
+            Some(inode) => inode,
+            None => continue,
+        };
+        if inode.is_dir() {
+            let dir_path = format!("{}{}/", path, entry.name);
+            if visited.insert(inode.inode_id) {
+                collect(&inode, &dir_path, entries, visited);
+            }
+            entries.insert(dir_path, inode);
+        } else {
+            entries.insert(format!("{}{}", path, entry.name), inode);
+        }
+    }
+}
+
+/// Get the byte ranges `[start, end)` in which two files differ,
+/// the tail of the longer one included
+fn differing_ranges(a: &Inode, b: &Inode) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut chunk_a = vec![0u8; CHUNK_SZ];
+    let mut chunk_b = vec![0u8; CHUNK_SZ];
+    let mut offset = 0;
+    loop {
+        let len_a = a.read_at(offset, &mut chunk_a);
+        let len_b = b.read_at(offset, &mut chunk_b);
+        let len = len_a.max(len_b);
+        if len == 0 {
+            break;
+        }
+        for i in 0..len {
+            if i < len_a && i < len_b && chunk_a[i] == chunk_b[i] {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end == offset + i => *end += 1,
+                _ => ranges.push((offset + i, offset + i + 1)),
+            }
+        }
+        offset += len;
+    }
+    ranges
+}
+
+/// Describe the size and link count of a file
+fn describe(inode: &Inode) -> String {
+    format!("size {}, links {}", inode.size(), inode.link_cnt())
+}
+
+/// Describe a value which may have changed
+fn change<T: PartialEq + std::fmt::Display>(a: T, b: T) -> String {
+    if a == b {
+        format!("{}", a)
+    } else {
+        format!("{} -> {}", a, b)
+    }
+}
+
+/// Write the differences from the image of `root_a` to that of `root_b`,
+/// return their number
+pub fn diff(root_a: &Inode, root_b: &Inode, mut writer: impl Write) -> io::Result<usize> {
+    let mut count = 0;
+    let (fs_a, fs_b) = (root_a.filesystem(), root_b.filesystem());
+    // one lock at a time, both roots may be in the same filesystem
+    let super_a = fs_a.lock().super_block();
+    let super_b = fs_b.lock().super_block();
+    let geometry = [
+        ("total blocks", super_a.total_blocks, super_b.total_blocks),
+        ("inode bitmap blocks", super_a.inode_bitmap_blocks, super_b.inode_bitmap_blocks),
+        ("inode area blocks", super_a.inode_area_blocks, super_b.inode_area_blocks),
+        ("data bitmap blocks", super_a.data_bitmap_blocks, super_b.data_bitmap_blocks),
+        ("data area blocks", super_a.data_area_blocks, super_b.data_area_blocks),
+    ];
+    let (inodes_a, data_blocks_a) = {
+        let mut fs = fs_a.lock();
+        (fs.used_inodes(), fs.used_data_blocks())
+    };
+    let (inodes_b, data_blocks_b) = {
+        let mut fs = fs_b.lock();
+        (fs.used_inodes(), fs.used_data_blocks())
+    };
+    let usage = [
+        ("inodes used", inodes_a, inodes_b),
+        ("data blocks used", data_blocks_a, data_blocks_b),
+    ];
+    for (name, a, b) in geometry.iter() {
+        if a != b {
+            writeln!(writer, "{}: {} -> {}", name, a, b)?;
+            count += 1;
+        }
+    }
+    for (name, a, b) in usage.iter() {
+        if a != b {
+            writeln!(writer, "{}: {} -> {}", name, a, b)?;
+            count += 1;
+        }
+    }
+    let (mut entries_a, mut entries_b) = (BTreeMap::new(), BTreeMap::new());
+    collect(root_a, "/", &mut entries_a, &mut BTreeSet::from([root_a.inode_id]));
+    collect(root_b, "/", &mut entries_b, &mut BTreeSet::from([root_b.inode_id]));
+    for (path, inode) in entries_a.iter() {
+        if !entries_b.contains_key(path) {
+            writeln!(writer, "- {} ({})", path, describe(inode))?;
+            count += 1;
+        }
+    }
+    for (path, inode_b) in entries_b.iter() {
+        let inode_a = match entries_a.get(path) {
+            Some(inode_a) => inode_a,
+            None => {
+                writeln!(writer, "+ {} ({})", path, describe(inode_b))?;
+                count += 1;
+                continue;
+            }
+        };
+        // the entries of a directory are compared one by one
+        let ranges = if inode_b.is_dir() {
+            Vec::new()
+        } else {
+            differing_ranges(inode_a, inode_b)
+        };
+        let (size_a, size_b) = (inode_a.size(), inode_b.size());
+        let (links_a, links_b) = (inode_a.link_cnt(), inode_b.link_cnt());
+        if ranges.is_empty() && size_a == size_b && links_a == links_b {
+            continue;
+        }
+        write!(writer, "~ {}: size {}, links {}", path, change(size_a, size_b), change(links_a, links_b))?;
+        if !ranges.is_empty() {
+            let shown: Vec<String> = ranges
+                .iter()
+                .take(MAX_RANGES_SHOWN)
+                .map(|(start, end)| format!("{}..{}", start, end))
+                .collect();
+            write!(writer, ", bytes {}", shown.join(", "))?;
+            if ranges.len() > MAX_RANGES_SHOWN {
+                write!(writer, " and {} more ranges", ranges.len() - MAX_RANGES_SHOWN)?;
+            }
+            write!(writer, " differ")?;
+        }
+        writeln!(writer)?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -1,5 +1,10 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+mod diff;
+mod tar;
+
+use clap::{App, Arg, SubCommand};
+use easy_fs::{BlockDevice, EasyFileSystem, Inode, MirrorBlockDevice, KEY_SZ};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::fs::{read_dir, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
@@ -27,14 +32,28 @@ impl BlockDevice for BlockFile {
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
     }
+    /// Read consecutive blocks from file with a single seek
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.read_exact(buf).expect("Not complete blocks!");
+    }
+    fn batched_reads(&self) -> bool {
+        true
+    }
 }
 
 fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+    easy_fs_pack(std::env::args_os()).expect("Error when packing easy-fs!");
 }
 
-/// Pack a directory into a easy-fs disk image
-fn easy_fs_pack() -> std::io::Result<()> {
+/// Pack a directory into a easy-fs disk image, as told by the command line `args`
+fn easy_fs_pack<I, T>(args: I) -> std::io::Result<()>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
     let matches = App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
@@ -50,30 +69,307 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
-        .get_matches();
-    let src_path = matches.value_of("source").unwrap();
+        .arg(
+            Arg::with_name("compress")
+                .short("c")
+                .long("compress")
+                .takes_value(true)
+                .multiple(true)
+                .help("Apps stored compressed, \"*\" for all of them"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .takes_value(true)
+                .help("Take a snapshot with this name after packing"),
+        )
+        .arg(
+            Arg::with_name("list-snapshots")
+                .long("list-snapshots")
+                .help("List the snapshots of an existing image"),
+        )
+        .arg(
+            Arg::with_name("ls-snapshot")
+                .long("ls-snapshot")
+                .takes_value(true)
+                .help("Mount a snapshot of an existing image read-only and list it"),
+        )
+        .arg(
+            Arg::with_name("rollback")
+                .long("rollback")
+                .takes_value(true)
+                .help("Roll an existing image back to a snapshot"),
+        )
+        .arg(
+            Arg::with_name("delete-snapshot")
+                .long("delete-snapshot")
+                .takes_value(true)
+                .help("Delete a snapshot of an existing image"),
+        )
+        .arg(
+            Arg::with_name("resize")
+                .long("resize")
+                .takes_value(true)
+                .help("Grow or shrink an existing image to this number of blocks, while it is not in use; os6 grows its mounted disk itself with growfs"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .help("Master key of encrypted files, as 64 hex digits"),
+        )
+        .arg(
+            Arg::with_name("secrets")
+                .long("secrets")
+                .takes_value(true)
+                .requires("key")
+                .help("Pack files of this host directory encrypted into directory secrets"),
+        )
+        .arg(
+            Arg::with_name("owner")
+                .long("owner")
+                .takes_value(true)
+                .help("Uid owning the packed files"),
+        )
+        .arg(
+            Arg::with_name("quota")
+                .long("quota")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Set limits of an existing image as uid:block_soft:block_hard:inode_soft:inode_hard"),
+        )
+        .arg(
+            Arg::with_name("quota-report")
+                .long("quota-report")
+                .help("Show the usage and limits of the users of an existing image"),
+        )
+        .arg(
+            Arg::with_name("import")
+                .long("import")
+                .takes_value(true)
+                .help("Read a tar archive, - for stdin, into an existing or a new image"),
+        )
+        .arg(
+            Arg::with_name("export")
+                .long("export")
+                .takes_value(true)
+                .help("Write the tree of an existing image as a tar archive, - for stdout"),
+        )
+        .arg(
+            Arg::with_name("frag-report")
+                .long("frag-report")
+                .help("Show how scattered the files of an existing image are"),
+        )
+        .arg(
+            Arg::with_name("defrag")
+                .long("defrag")
+                .help("Defragment an existing image, compacting its files to the front"),
+        )
+        .arg(
+            Arg::with_name("mirror")
+                .long("mirror")
+                .help("Mirror the image to fs-mirror.img, both with checksums at their tail"),
+        )
+        .arg(
+            Arg::with_name("reproducible")
+                .long("reproducible")
+                .help("Pack inputs in sorted order into a fully rewritten image and print its SHA-256"),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Show how the new image differs from the old one, exit with 1 if it does")
+                .arg(Arg::with_name("old").required(true).index(1))
+                .arg(Arg::with_name("new").required(true).index(2)),
+        )
+        .get_matches_from(args);
+    let key = matches.value_of("key").map(|key| parse_key(key).expect("Invalid key!"));
+    if let Some(diff_matches) = matches.subcommand_matches("diff") {
+        let count = diff_images(
+            diff_matches.value_of("old").unwrap(),
+            diff_matches.value_of("new").unwrap(),
+            key,
+        )?;
+        if count > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     let target_path = matches.value_of("target").unwrap();
+    let mirror = matches.is_present("mirror");
+    let image_ops = [
+        "list-snapshots",
+        "ls-snapshot",
+        "rollback",
+        "delete-snapshot",
+        "resize",
+        "quota",
+        "quota-report",
+        "import",
+        "export",
+        "frag-report",
+        "defrag",
+    ];
+    if image_ops.iter().any(|op| matches.is_present(op)) {
+        let image_path = format!("{}{}", target_path, "fs.img");
+        // importing may start a new image
+        let new_image = matches.is_present("import") && !std::path::Path::new(&image_path).exists();
+        let block_file = Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(new_image)
+                .truncate(new_image)
+                .open(&image_path)?,
+        )));
+        if new_image {
+            block_file.0.lock().unwrap().set_len((BLOCK_NUM * BLOCK_SZ) as u64)?;
+        }
+        let (block_device, total_blocks) = open_device(block_file.clone(), target_path, mirror, new_image)?;
+        if new_image {
+            EasyFileSystem::create(block_device.clone(), total_blocks as u32, 1);
+        }
+        if let Some(name) = matches.value_of("ls-snapshot") {
+            let efs = EasyFileSystem::open_snapshot(block_device, name).expect("No such snapshot!");
+            for app in EasyFileSystem::root_inode(&efs).ls() {
+                println!("{}", app);
+            }
+            return Ok(());
+        }
+        let efs = EasyFileSystem::open(block_device);
+        if let Some(key) = key {
+            efs.lock().set_master_key(key).expect("Wrong key!");
+        }
+        if let Some(path) = matches.value_of("import") {
+            let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+            let count = if path == "-" {
+                tar::import(&root_inode, std::io::stdin().lock())?
+            } else {
+                tar::import(&root_inode, std::io::BufReader::new(File::open(path)?))?
+            };
+            println!("{} entries imported", count);
+            return Ok(());
+        }
+        if let Some(path) = matches.value_of("export") {
+            let root_inode = EasyFileSystem::root_inode(&efs);
+            let count = if path == "-" {
+                tar::export(&root_inode, std::io::stdout().lock())?
+            } else {
+                tar::export(&root_inode, std::io::BufWriter::new(File::create(path)?))?
+            };
+            eprintln!("{} entries exported", count);
+            return Ok(());
+        }
+        if matches.is_present("quota") || matches.is_present("quota-report") {
+            for quota in matches.values_of("quota").into_iter().flatten() {
+                let limits: Vec<u32> = quota
+                    .split(':')
+                    .map(|limit| limit.parse().expect("Invalid quota!"))
+                    .collect();
+                assert_eq!(limits.len(), 5, "Invalid quota!");
+                efs.lock()
+                    .set_quota(limits[0], limits[1], limits[2], limits[3], limits[4])
+                    .expect("Error when setting quota!");
+            }
+            println!("uid\tblocks\tsoft\thard\tinodes\tsoft\thard");
+            for quota in efs.lock().quotas() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}{}",
+                    quota.uid,
+                    quota.blocks,
+                    quota.block_soft,
+                    quota.block_hard,
+                    quota.inodes,
+                    quota.inode_soft,
+                    quota.inode_hard,
+                    if quota.over_soft_limit() { "\tover soft limit" } else { "" },
+                );
+            }
+            return Ok(());
+        }
+        if matches.is_present("defrag") || matches.is_present("frag-report") {
+            if matches.is_present("frag-report") {
+                println!("score\textents\tblocks\tpath");
+                frag_report(&EasyFileSystem::root_inode(&efs), "/", &mut HashSet::new());
+            }
+            let before = efs.lock().volume_fragmentation();
+            println!("volume: {} files, {} extents, {} blocks, score {}", before.files, before.extents, before.blocks, before.score());
+            if matches.is_present("defrag") {
+                let moved = efs.lock().defragment().expect("Error when defragmenting!");
+                let after = efs.lock().volume_fragmentation();
+                println!("{} files moved, {} extents, score {}", moved, after.extents, after.score());
+            }
+            return Ok(());
+        }
+        if let Some(blocks) = matches.value_of("resize") {
+            // the checksums of a mirror are at the tail, which moves
+            assert!(!mirror, "Mirrored images can not be resized!");
+            let blocks: u32 = blocks.parse().expect("Invalid number of blocks!");
+            let file_len = (blocks as usize * BLOCK_SZ) as u64;
+            let mut efs = efs.lock();
+            if blocks > efs.total_blocks() {
+                block_file.0.lock().unwrap().set_len(file_len)?;
+                efs.grow(blocks).expect("Error when growing easy-fs!");
+            } else {
+                efs.shrink(blocks).expect("Error when shrinking easy-fs!");
+                block_file.0.lock().unwrap().set_len(file_len)?;
+            }
+            println!("{} blocks, {} data blocks used", blocks, efs.used_data_blocks());
+            return Ok(());
+        }
+        if let Some(name) = matches.value_of("rollback") {
+            efs.lock().rollback(name).expect("No such snapshot!");
+        }
+        if let Some(name) = matches.value_of("delete-snapshot") {
+            efs.lock().delete_snapshot(name).expect("No such snapshot!");
+        }
+        for name in efs.lock().snapshots() {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    let src_path = matches.value_of("source").unwrap();
+    let compress: Vec<&str> = matches
+        .values_of("compress")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    let reproducible = matches.is_present("reproducible");
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    let image_path = format!("{}{}", target_path, "fs.img");
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
+            // nothing of a previous image may survive, not even outside the filesystem
+            .truncate(reproducible)
+            .open(&image_path)?;
         f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
         f
     })));
-    let efs = EasyFileSystem::create(block_file.clone(), BLOCK_NUM as u32, 1);
+    let (block_device, total_blocks) = open_device(block_file.clone(), target_path, mirror, true)?;
+    let efs = EasyFileSystem::create(block_device, total_blocks as u32, 1);
+    if let Some(key) = key {
+        efs.lock().set_master_key(key).unwrap();
+    }
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
+    if let Some(owner) = matches.value_of("owner") {
+        let owner: u32 = owner.parse().expect("Invalid uid!");
+        root_inode.chown(owner).unwrap();
+    }
+    let mut apps: Vec<_> = read_dir(src_path)
         .unwrap()
-        .into_iter()
         .map(|dir_entry| {
             let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
             name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
             name_with_ext
         })
         .collect();
+    // read_dir gives no particular order, which decides the layout of the image
+    if reproducible {
+        apps.sort();
+        apps.dedup();
+    }
     for app in apps {
         // load app data (elf) from host file system
         let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
@@ -81,27 +377,148 @@ fn easy_fs_pack() -> std::io::Result<()> {
         host_file.read_to_end(&mut all_data).unwrap();
         // create a file in easy-fs
         let inode = root_inode.create(app.as_str()).unwrap();
+        if compress.iter().any(|name| *name == "*" || *name == app) {
+            inode.set_compressed(true).unwrap();
+        }
         // write data to easy-fs
         inode.write_at(0, all_data.as_slice());
     }
+    if let Some(secrets_path) = matches.value_of("secrets") {
+        let secrets = root_inode.mkdir("secrets").unwrap();
+        secrets.set_encrypted().unwrap();
+        let mut dir_entries = read_dir(secrets_path)?.collect::<std::io::Result<Vec<_>>>()?;
+        if reproducible {
+            dir_entries.sort_by_key(|dir_entry| dir_entry.file_name());
+        }
+        for dir_entry in dir_entries {
+            let name = dir_entry.file_name().into_string().unwrap();
+            let mut all_data: Vec<u8> = Vec::new();
+            File::open(dir_entry.path())?.read_to_end(&mut all_data)?;
+            secrets.create(name.as_str()).unwrap().write_at(0, all_data.as_slice());
+        }
+    }
     // list apps
     for app in root_inode.ls() {
         println!("{}", app);
     }
+    if let Some(name) = matches.value_of("snapshot") {
+        efs.lock().snapshot(name).expect("Error when taking snapshot!");
+    }
+    println!("{} data blocks used", efs.lock().used_data_blocks());
+    if reproducible {
+        drop(block_file);
+        println!("sha256 {}", image_sha256(&image_path)?);
+    }
     Ok(())
 }
 
+/// Get the block device over an image of `BLOCK_NUM` blocks with its number of blocks,
+/// a mirror with fs-mirror.img if `mirror` is set, which `create` starts afresh
+fn open_device(
+    block_file: Arc<BlockFile>,
+    target_path: &str,
+    mirror: bool,
+    create: bool,
+) -> std::io::Result<(Arc<dyn BlockDevice>, usize)> {
+    if !mirror {
+        return Ok((block_file, BLOCK_NUM));
+    }
+    let mirror_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(create)
+        .open(format!("{}{}", target_path, "fs-mirror.img"))?;
+    mirror_file.set_len((BLOCK_NUM * BLOCK_SZ) as u64)?;
+    if create {
+        // stale checksums of a previous image must not survive in the tail
+        block_file.0.lock().unwrap().set_len(0)?;
+        block_file.0.lock().unwrap().set_len((BLOCK_NUM * BLOCK_SZ) as u64)?;
+    }
+    let mirror_file = Arc::new(BlockFile(Mutex::new(mirror_file)));
+    let device = MirrorBlockDevice::new(block_file, mirror_file, BLOCK_NUM);
+    let total_blocks = device.total_blocks();
+    Ok((Arc::new(device), total_blocks))
+}
+
+/// Print how the image at `new_path` differs from the one at `old_path`, get the number of differences.
+/// Both images are opened read-only and left as they are, orphans included.
+fn diff_images(old_path: &str, new_path: &str, key: Option<[u8; KEY_SZ]>) -> std::io::Result<usize> {
+    let open = |path: &str| -> std::io::Result<_> {
+        let efs = EasyFileSystem::open_read_only(Arc::new(BlockFile(Mutex::new(File::open(path)?))));
+        if let Some(key) = key {
+            efs.lock().set_master_key(key).expect("Wrong key!");
+        }
+        Ok(efs)
+    };
+    let old = open(old_path)?;
+    let new = open(new_path)?;
+    let count = diff::diff(
+        &EasyFileSystem::root_inode(&old),
+        &EasyFileSystem::root_inode(&new),
+        std::io::stdout().lock(),
+    )?;
+    println!("{} differences", count);
+    Ok(count)
+}
+
+/// Get the SHA-256 of an image file as hex digits
+fn image_sha256(path: &str) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * BLOCK_SZ];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Print the fragmentation of every inode under a directory
+fn frag_report(dir: &Inode, path: &str, visited: &mut HashSet<u32>) {
+    let fragmentation = dir.fragmentation();
+    println!("{}\t{}\t{}\t{}", fragmentation.score(), fragmentation.extents, fragmentation.blocks, path);
+    visited.insert(dir.inode_id);
+    for entry in dir.read_dir(0) {
+        if !visited.insert(entry.inode_id) {
+            continue;
+        }
+        let inode = match dir.find(&entry.name) {
+            Some(inode) => inode,
+            None => continue,
+        };
+        let entry_path = format!("{}{}", path, entry.name);
+        if inode.is_dir() {
+            frag_report(&inode, &format!("{}/", entry_path), visited);
+        } else {
+            let fragmentation = inode.fragmentation();
+            println!("{}\t{}\t{}\t{}", fragmentation.score(), fragmentation.extents, fragmentation.blocks, entry_path);
+        }
+    }
+}
+
+/// Parse a master key from 64 hex digits
+fn parse_key(hex: &str) -> Option<[u8; KEY_SZ]> {
+    if hex.len() != KEY_SZ * 2 {
+        return None;
+    }
+    let mut key = [0u8; KEY_SZ];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
+    let block_file = image_file("target/fs.img", BLOCK_NUM);
     EasyFileSystem::create(block_file.clone(), 4096, 1);
     let efs = EasyFileSystem::open(block_file.clone());
     let root_inode = EasyFileSystem::root_inode(&efs);
@@ -125,7 +542,7 @@ fn efs_test() -> std::io::Result<()> {
         use rand;
         // random digit
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
         filea.write_at(0, str.as_bytes());
         let mut read_buffer = [0u8; 127];
@@ -153,3 +570,1205 @@ fn efs_test() -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Create or truncate an image file of `blocks` blocks, for tests that
+/// need the image on the host as well
+#[cfg(test)]
+fn image_file(path: &str, blocks: usize) -> Arc<BlockFile> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .expect("Cannot create the image file!");
+    f.set_len((blocks * BLOCK_SZ) as u64).unwrap();
+    Arc::new(BlockFile(Mutex::new(f)))
+}
+
+#[test]
+fn efs_read_dir_test() {
+    use easy_fs::{DiskInodeType, RamBlockDevice};
+    let device: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::new(4096));
+    let efs = EasyFileSystem::create(Arc::clone(&device), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("filea").unwrap();
+    let dir = root_inode.mkdir("dir").unwrap();
+    root_inode.create("fileb").unwrap();
+    root_inode.link("linka", "filea").unwrap();
+    let entries: Vec<(String, u32, DiskInodeType)> = root_inode
+        .read_dir(0)
+        .map(|entry| (entry.name, entry.inode_id, entry.type_))
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            (String::from("filea"), 1, DiskInodeType::File),
+            (String::from("dir"), 2, DiskInodeType::Directory),
+            (String::from("fileb"), 3, DiskInodeType::File),
+            (String::from("linka"), 1, DiskInodeType::File),
+        ]
+    );
+    // resume from the middle of the directory, as getdents does once its buffer is full
+    let mut read_dir = root_inode.read_dir(0);
+    read_dir.next().unwrap();
+    let second = read_dir.next().unwrap();
+    let rest: Vec<String> = root_inode
+        .read_dir(second.next_offset)
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(rest, ["fileb", "linka"]);
+    let last = root_inode.read_dir(second.next_offset).last().unwrap();
+    assert_eq!(root_inode.read_dir(last.next_offset).count(), 0);
+    assert_eq!(dir.read_dir(0).count(), 0);
+    // entries behind an unlinked one move up
+    root_inode.unlink("dir").unwrap();
+    let names: Vec<String> = root_inode.read_dir(0).map(|entry| entry.name).collect();
+    assert_eq!(names, ["filea", "fileb", "linka"]);
+}
+
+#[test]
+fn efs_alloc_test() {
+    use easy_fs::RamBlockDevice;
+    let device: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::new(4096));
+    let efs = EasyFileSystem::create(Arc::clone(&device), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    let fileb = root_inode.create("fileb").unwrap();
+    // large enough to need indirect1 and indirect2 blocks
+    let data: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    assert_eq!(filea.write_at(0, &data), data.len());
+    let fragmentation = filea.fragmentation();
+    assert!(fragmentation.blocks > 300);
+    assert_eq!((fragmentation.extents, fragmentation.score()), (1, 0));
+    // the next file takes the blocks right after the first one
+    assert_eq!(fileb.write_at(0, &data[..100 * BLOCK_SZ]), 100 * BLOCK_SZ);
+    assert_eq!(fileb.fragmentation().extents, 1);
+    assert_eq!(efs.lock().volume_fragmentation().extents, 2);
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(filea.read_at(0, &mut buf), data.len());
+    assert!(buf == data);
+}
+
+#[test]
+fn efs_readahead_test() {
+    use easy_fs::RamBlockDevice;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    /// Count the requests reaching a device
+    struct Counting {
+        inner: RamBlockDevice,
+        batched: bool,
+        requests: AtomicUsize,
+    }
+    impl BlockDevice for Counting {
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.inner.read_block(block_id, buf);
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) {
+            self.inner.write_block(block_id, buf);
+        }
+        fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+            if !self.batched {
+                for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+                    self.read_block(block_id + i, block);
+                }
+                return;
+            }
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.inner.read_blocks(block_id, buf);
+        }
+        fn batched_reads(&self) -> bool {
+            self.batched
+        }
+    }
+    let device = Arc::new(RamBlockDevice::new(4096));
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1);
+    let data: Vec<u8> = (0..20 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    EasyFileSystem::root_inode(&efs).create("file").unwrap().write_at(0, &data);
+    drop(efs);
+    let image = device.image();
+    for batched in [false, true] {
+        let counting = Arc::new(Counting {
+            inner: RamBlockDevice::from_image(&image),
+            batched,
+            requests: AtomicUsize::new(0),
+        });
+        let efs = EasyFileSystem::open(counting.clone());
+        let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+        let before = counting.requests.load(Ordering::SeqCst);
+        file.readahead(0, data.len());
+        let requests = counting.requests.load(Ordering::SeqCst) - before;
+        // the 20 blocks of the file are one run, read at once or not at all
+        assert_eq!(requests, usize::from(batched));
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut buf), data.len());
+        assert!(buf == data);
+    }
+}
+
+#[test]
+fn efs_version_test() {
+    use easy_fs::{RamBlockDevice, EFS_VERSION};
+    let device = Arc::new(RamBlockDevice::new(4096));
+    EasyFileSystem::create(device.clone(), 4096, 1);
+    let image = device.image();
+    // devices stay alive so that no later one reuses an address known to the block cache
+    let mut devices = Vec::new();
+    let mut opens = |magic: u32, version: u32| {
+        let mut image = image.clone();
+        image[0..4].copy_from_slice(&magic.to_le_bytes());
+        image[4..8].copy_from_slice(&version.to_le_bytes());
+        let device = Arc::new(RamBlockDevice::from_image(&image));
+        devices.push(device.clone());
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            EasyFileSystem::open(device);
+        }))
+        .is_ok()
+    };
+    let magic = u32::from_le_bytes([image[0], image[1], image[2], image[3]]);
+    assert!(opens(magic, EFS_VERSION));
+    assert!(!opens(magic, EFS_VERSION + 1));
+    // an image from before the version field
+    assert!(!opens(0x3b800001, 1024));
+    assert!(!opens(0, EFS_VERSION));
+}
+
+#[test]
+fn efs_compression_test() {
+    let device = Arc::new(easy_fs::RamBlockDevice::new(8192));
+    let efs = EasyFileSystem::create(device.clone(), 8192, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("compressed").unwrap();
+    file.set_compressed(true).unwrap();
+    assert!(file.is_compressed());
+    // compressible text with some noise
+    let mut data: Vec<u8> = Vec::new();
+    while data.len() < 300 * BLOCK_SZ {
+        let line = format!("line {} of a compressible test file\n", data.len() % 97);
+        data.extend_from_slice(line.as_bytes());
+    }
+    let used_before = efs.lock().used_data_blocks();
+    let mut offset = 0;
+    for chunk in data.chunks(1000) {
+        assert_eq!(file.write_at(offset, chunk), chunk.len());
+        offset += chunk.len();
+    }
+    let used = efs.lock().used_data_blocks() - used_before;
+    assert!(used < data.len() / BLOCK_SZ / 2, "{} blocks used", used);
+    // partial overwrites, some spanning cluster boundaries
+    for _ in 0..50 {
+        let offset = rand::random::<usize>() % data.len();
+        let len = (rand::random::<usize>() % 3000).min(data.len() - offset);
+        let patch: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+        assert_eq!(file.write_at(offset, &patch), len);
+        data[offset..offset + len].copy_from_slice(&patch);
+    }
+    // random reads
+    for _ in 0..100 {
+        let offset = rand::random::<usize>() % data.len();
+        let mut buf = vec![0u8; rand::random::<usize>() % 5000];
+        let len = file.read_at(offset, &mut buf);
+        assert_eq!(len, buf.len().min(data.len() - offset));
+        assert_eq!(&buf[..len], &data[offset..offset + len]);
+    }
+    // remount and read everything back
+    let efs = EasyFileSystem::open(device);
+    let file = EasyFileSystem::root_inode(&efs).find("compressed").unwrap();
+    let mut buf = vec![0u8; data.len() + 100];
+    assert_eq!(file.read_at(0, &mut buf), data.len());
+    assert!(buf[..data.len()] == data[..]);
+    file.clear();
+    assert_eq!(efs.lock().used_data_blocks(), used_before);
+}
+
+#[test]
+fn efs_corrupt_cluster_test() -> std::io::Result<()> {
+    let path = "target/fs_corrupt_cluster.img";
+    let block_file = image_file(path, 4096);
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let file = EasyFileSystem::root_inode(&efs).create("compressed").unwrap();
+    file.set_compressed(true).unwrap();
+    let data = b"a compressible cluster ".repeat(3 * 8 * BLOCK_SZ / 23);
+    assert_eq!(file.write_at(0, &data), data.len());
+    drop(efs);
+    // garble the length header and the payload of the first stored cluster
+    let mut image = std::fs::read(path)?;
+    let block = image
+        .chunks(BLOCK_SZ)
+        .position(|block| block.windows(10).any(|w| w == b"compressib"))
+        .expect("compressed cluster not found");
+    image[block * BLOCK_SZ..(block + 1) * BLOCK_SZ].fill(0xff);
+    std::fs::write(path, &image)?;
+    // remount through a new device so that no stale cache is used
+    let corrupted = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open(path)?,
+    )));
+    let efs = EasyFileSystem::open(corrupted.clone());
+    let file = EasyFileSystem::root_inode(&efs).find("compressed").unwrap();
+    let mut buf = vec![0u8; data.len()];
+    // the first cluster is unreadable, so is everything after it
+    assert_eq!(file.read_at(0, &mut buf), 0);
+    assert_eq!(file.write_at(0, b"overwrite"), 0);
+    // the following clusters are still fine
+    let cluster = 8 * BLOCK_SZ;
+    assert_eq!(file.read_at(cluster, &mut buf[..cluster]), cluster);
+    assert!(buf[..cluster] == data[cluster..2 * cluster]);
+    drop(block_file);
+    Ok(())
+}
+
+#[test]
+fn efs_inline_test() {
+    let device = Arc::new(easy_fs::RamBlockDevice::new(8192));
+    let efs = EasyFileSystem::create(device.clone(), 8192, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_before = efs.lock().used_data_blocks();
+    let file = root_inode.create("tiny").unwrap();
+    let mut data = b"hello".to_vec();
+    file.write_at(0, &data);
+    assert_eq!(efs.lock().used_data_blocks(), used_before);
+    // grow past the inline area, with a gap that reads as zero
+    data.resize(100, 0);
+    data.extend_from_slice(&[b'x'; 100]);
+    file.write_at(100, &data[100..]);
+    assert!(efs.lock().used_data_blocks() > used_before);
+    let mut buf = [0u8; 300];
+    assert_eq!(file.read_at(0, &mut buf), data.len());
+    assert_eq!(&buf[..data.len()], &data[..]);
+    file.clear();
+    assert_eq!(efs.lock().used_data_blocks(), used_before);
+    file.write_at(0, b"again");
+    assert_eq!(efs.lock().used_data_blocks(), used_before);
+    let efs = EasyFileSystem::open(device);
+    let file = EasyFileSystem::root_inode(&efs).find("tiny").unwrap();
+    assert_eq!(file.read_at(0, &mut buf), 5);
+    assert_eq!(&buf[..5], b"again");
+}
+
+#[test]
+fn efs_snapshot_test() {
+    let device = Arc::new(easy_fs::RamBlockDevice::new(8192));
+    let efs = EasyFileSystem::create(device.clone(), 8192, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let big: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    root_inode.create("big").unwrap().write_at(0, &big);
+    root_inode.create("small").unwrap().write_at(0, b"small file");
+    // the snapshot table stays once created
+    efs.lock().snapshot("tmp").unwrap();
+    efs.lock().delete_snapshot("tmp").unwrap();
+    let used_before = efs.lock().used_data_blocks();
+    efs.lock().snapshot("base").unwrap();
+    assert!(efs.lock().snapshot("base").is_err());
+    assert_eq!(efs.lock().snapshots(), vec![String::from("base")]);
+    // modify the live tree
+    let file = root_inode.find("big").unwrap();
+    file.write_at(100 * BLOCK_SZ + 7, b"changed");
+    file.write_at(big.len(), &big);
+    root_inode.find("small").unwrap().clear();
+    root_inode.create("new").unwrap().write_at(0, b"new file");
+    let mut buf = vec![0u8; 2 * big.len()];
+    assert_eq!(file.read_at(100 * BLOCK_SZ + 7, &mut buf[..7]), 7);
+    assert_eq!(&buf[..7], b"changed");
+    // the snapshot still sees the old tree
+    let snapshot = EasyFileSystem::open_snapshot(device.clone(), "base").unwrap();
+    let snapshot_root = EasyFileSystem::root_inode(&snapshot);
+    assert!(snapshot_root.find("new").is_none());
+    let old = snapshot_root.find("big").unwrap();
+    assert_eq!(old.read_at(0, &mut buf), big.len());
+    assert!(buf[..big.len()] == big[..]);
+    assert_eq!(old.write_at(0, b"read-only"), 0);
+    // roll back and forget the snapshot
+    efs.lock().rollback("base").unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(root_inode.find("new").is_none());
+    assert_eq!(root_inode.find("big").unwrap().read_at(0, &mut buf), big.len());
+    assert!(buf[..big.len()] == big[..]);
+    assert_eq!(root_inode.find("small").unwrap().read_at(0, &mut buf), 10);
+    assert_eq!(&buf[..10], b"small file");
+    efs.lock().delete_snapshot("base").unwrap();
+    assert!(efs.lock().snapshots().is_empty());
+    assert_eq!(efs.lock().used_data_blocks(), used_before);
+}
+
+#[test]
+fn efs_resize_test() -> std::io::Result<()> {
+    let block_file = image_file("target/fs_resize.img", 4096);
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data = |seed: usize, len: usize| -> Vec<u8> {
+        (0..len).map(|i| ((i * 7 + seed) % 251) as u8).collect()
+    };
+    for i in 0..8 {
+        let name = format!("file{}", i);
+        root_inode.create(&name).unwrap().write_at(0, &data(i, 150 * BLOCK_SZ + i));
+    }
+    efs.lock().snapshot("base").unwrap();
+    root_inode.find("file0").unwrap().write_at(0, b"after snapshot");
+    // growing past the data bitmap moves the first data blocks away
+    block_file.0.lock().unwrap().set_len((20000 * BLOCK_SZ) as u64)?;
+    efs.lock().grow(20000).unwrap();
+    for i in 8..40 {
+        let name = format!("file{}", i);
+        root_inode.create(&name).unwrap().write_at(0, &data(i, 150 * BLOCK_SZ + i));
+    }
+    let check = |root_inode: &easy_fs::Inode, files: usize| {
+        let mut buf = vec![0u8; 151 * BLOCK_SZ];
+        for i in 1..files {
+            let file = root_inode.find(&format!("file{}", i)).unwrap();
+            assert_eq!(file.read_at(0, &mut buf), 150 * BLOCK_SZ + i);
+            assert!(buf[..150 * BLOCK_SZ + i] == data(i, 150 * BLOCK_SZ + i)[..]);
+        }
+        let file0 = root_inode.find("file0").unwrap();
+        assert_eq!(file0.read_at(0, &mut buf[..14]), 14);
+        assert_eq!(&buf[..14], b"after snapshot");
+    };
+    check(&root_inode, 40);
+    // shrinking moves the data out of the tail
+    assert!(efs.lock().shrink(4000).is_err());
+    for i in 8..40 {
+        root_inode.find(&format!("file{}", i)).unwrap().clear();
+    }
+    let used = efs.lock().used_data_blocks();
+    efs.lock().shrink(4000).unwrap();
+    block_file.0.lock().unwrap().set_len((4000 * BLOCK_SZ) as u64)?;
+    // only the reference counts of the snapshot shrink with the data area
+    assert!(efs.lock().used_data_blocks() <= used);
+    let efs = EasyFileSystem::open(block_file.clone());
+    assert_eq!(efs.lock().total_blocks(), 4000);
+    check(&EasyFileSystem::root_inode(&efs), 8);
+    let snapshot = EasyFileSystem::open_snapshot(block_file, "base").unwrap();
+    let mut buf = vec![0u8; 151 * BLOCK_SZ];
+    let file0 = EasyFileSystem::root_inode(&snapshot).find("file0").unwrap();
+    assert_eq!(file0.read_at(0, &mut buf), 150 * BLOCK_SZ);
+    assert!(buf[..150 * BLOCK_SZ] == data(0, 150 * BLOCK_SZ)[..]);
+    efs.lock().delete_snapshot("base").unwrap();
+    // a tiny image whose new data bitmap reaches past its old end
+    let block_file = image_file("target/fs_resize_tiny.img", 1180);
+    let efs = EasyFileSystem::create(block_file.clone(), 1180, 1);
+    let old_end = efs.lock().total_blocks();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("file").unwrap().write_at(0, &data(0, 10 * BLOCK_SZ));
+    block_file.0.lock().unwrap().set_len((120000 * BLOCK_SZ) as u64)?;
+    efs.lock().grow(120000).unwrap();
+    let super_block = efs.lock().super_block();
+    let new_data_start = 1
+        + super_block.inode_bitmap_blocks
+        + super_block.inode_area_blocks
+        + super_block.data_bitmap_blocks;
+    assert!(new_data_start > old_end);
+    let efs = EasyFileSystem::open(block_file);
+    let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+    assert_eq!(file.read_at(0, &mut buf), 10 * BLOCK_SZ);
+    assert!(buf[..10 * BLOCK_SZ] == data(0, 10 * BLOCK_SZ)[..]);
+    file.write_at(10 * BLOCK_SZ, &data(1, 100 * BLOCK_SZ));
+    assert_eq!(file.read_at(0, &mut buf[..10 * BLOCK_SZ]), 10 * BLOCK_SZ);
+    assert!(buf[..10 * BLOCK_SZ] == data(0, 10 * BLOCK_SZ)[..]);
+    // growing within the spare bits of the data bitmap leaves all but the super block alone
+    let device = Arc::new(easy_fs::RamBlockDevice::new(4500));
+    let efs = EasyFileSystem::create(device.clone(), 4000, 1);
+    let file = EasyFileSystem::root_inode(&efs).create("file").unwrap();
+    file.write_at(0, &data(2, 50 * BLOCK_SZ));
+    let before = device.image();
+    let old_data_area_blocks = efs.lock().super_block().data_area_blocks as usize;
+    efs.lock().grow(4500).unwrap();
+    let after = device.image();
+    assert!(before[BLOCK_SZ..] == after[BLOCK_SZ..]);
+    assert_eq!(efs.lock().super_block().total_blocks, 4500);
+    // the new blocks can be used by the open file right away
+    let more = data(3, old_data_area_blocks * BLOCK_SZ);
+    assert_eq!(file.write_at(50 * BLOCK_SZ, &more), more.len());
+    assert!(efs.lock().used_data_blocks() > old_data_area_blocks);
+    let efs = EasyFileSystem::open(device);
+    let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+    assert_eq!(file.read_at(0, &mut buf[..50 * BLOCK_SZ]), 50 * BLOCK_SZ);
+    assert!(buf[..50 * BLOCK_SZ] == data(2, 50 * BLOCK_SZ)[..]);
+    Ok(())
+}
+
+#[test]
+fn chacha20_test() {
+    use easy_fs::ChaCha20;
+    // RFC 8439 2.3.2
+    let mut key = [0u8; KEY_SZ];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let block = ChaCha20::new(&key, &nonce).block(1);
+    let expected: [u8; 64] = [
+        0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20, 0x71,
+        0xc4, 0xc7, 0xd1, 0xf4, 0xc7, 0x33, 0xc0, 0x68, 0x03, 0x04, 0x22, 0xaa, 0x9a, 0xc3, 0xd4,
+        0x6c, 0x4e, 0xd2, 0x82, 0x64, 0x46, 0x07, 0x9f, 0xaa, 0x09, 0x14, 0xc2, 0xd7, 0x05, 0xd9,
+        0x8b, 0x02, 0xa2, 0xb5, 0x12, 0x9c, 0xd1, 0xde, 0x16, 0x4e, 0xb9, 0xcb, 0xd0, 0x83, 0xe8,
+        0xa2, 0x50, 0x3c, 0x4e,
+    ];
+    assert_eq!(block, expected);
+    // RFC 8439 2.4.2, the initial counter 1 is the keystream at byte 64
+    let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let mut text = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+    let plain = text.clone();
+    let cipher = ChaCha20::new(&key, &nonce);
+    cipher.apply_keystream(64, &mut text);
+    let expected: [u8; 114] = [
+        0x6e, 0x2e, 0x35, 0x9a, 0x25, 0x68, 0xf9, 0x80, 0x41, 0xba, 0x07, 0x28, 0xdd, 0x0d, 0x69,
+        0x81, 0xe9, 0x7e, 0x7a, 0xec, 0x1d, 0x43, 0x60, 0xc2, 0x0a, 0x27, 0xaf, 0xcc, 0xfd, 0x9f,
+        0xae, 0x0b, 0xf9, 0x1b, 0x65, 0xc5, 0x52, 0x47, 0x33, 0xab, 0x8f, 0x59, 0x3d, 0xab, 0xcd,
+        0x62, 0xb3, 0x57, 0x16, 0x39, 0xd6, 0x24, 0xe6, 0x51, 0x52, 0xab, 0x8f, 0x53, 0x0c, 0x35,
+        0x9f, 0x08, 0x61, 0xd8, 0x07, 0xca, 0x0d, 0xbf, 0x50, 0x0d, 0x6a, 0x61, 0x56, 0xa3, 0x8e,
+        0x08, 0x8a, 0x22, 0xb6, 0x5e, 0x52, 0xbc, 0x51, 0x4d, 0x16, 0xcc, 0xf8, 0x06, 0x81, 0x8c,
+        0xe9, 0x1a, 0xb7, 0x79, 0x37, 0x36, 0x5a, 0xf9, 0x0b, 0xbf, 0x74, 0xa3, 0x5b, 0xe6, 0xb4,
+        0x0b, 0x8e, 0xed, 0xf2, 0x78, 0x5e, 0x42, 0x87, 0x4d,
+    ];
+    assert_eq!(text[..], expected[..]);
+    // decrypting in pieces from unaligned offsets gives the text back
+    cipher.apply_keystream(64, &mut text[..10]);
+    cipher.apply_keystream(74, &mut text[10..100]);
+    cipher.apply_keystream(164, &mut text[100..]);
+    assert_eq!(text, plain);
+}
+
+#[test]
+fn efs_encryption_test() {
+    let device = Arc::new(easy_fs::RamBlockDevice::new(8192));
+    let key = [0x5au8; KEY_SZ];
+    let efs = EasyFileSystem::create(device.clone(), 8192, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // encrypting needs a master key
+    let secrets = root_inode.mkdir("secrets").unwrap();
+    assert!(secrets.set_encrypted().is_err());
+    efs.lock().set_master_key(key).unwrap();
+    secrets.set_encrypted().unwrap();
+    assert!(secrets.is_encrypted());
+    let password = b"hunter2 is the password of the test fixture";
+    let big: Vec<u8> = (0..20 * BLOCK_SZ).map(|i| (i % 13) as u8).collect();
+    secrets.create("password").unwrap().write_at(0, password);
+    secrets.create("big").unwrap().write_at(0, &big);
+    root_inode.create("plain").unwrap().write_at(0, b"not secret");
+    // neither the contents nor the names are on the disk in plaintext
+    let image = device.image();
+    let contains = |needle: &[u8]| image.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"not secret"));
+    assert!(!contains(b"hunter2"));
+    assert!(!contains(b"password"));
+    // a wrong key is rejected and no key reads nothing
+    assert!(EasyFileSystem::open_with_key(device.clone(), [0xa5; KEY_SZ]).is_err());
+    let locked = EasyFileSystem::open(device.clone());
+    let locked_secrets = EasyFileSystem::root_inode(&locked).find("secrets").unwrap();
+    assert!(locked_secrets.ls().is_empty());
+    assert!(locked_secrets.find("password").is_none());
+    assert!(locked_secrets.create("new").is_none());
+    // the right key reads everything back
+    let device_for_images = device.clone();
+    let efs = EasyFileSystem::open_with_key(device, key).unwrap();
+    let secrets = EasyFileSystem::root_inode(&efs).find("secrets").unwrap();
+    let mut names = secrets.ls();
+    names.sort();
+    assert_eq!(names, ["big", "password"]);
+    let mut buf = vec![0u8; 21 * BLOCK_SZ];
+    let len = secrets.find("password").unwrap().read_at(0, &mut buf);
+    assert_eq!(&buf[..len], &password[..]);
+    let big_file = secrets.find("big").unwrap();
+    assert_eq!(big_file.read_at(0, &mut buf), big.len());
+    assert!(buf[..big.len()] == big[..]);
+    assert_eq!(big_file.read_at(1000, &mut buf[..77]), 77);
+    assert!(buf[..77] == big[1000..1077]);
+    secrets.unlink("password").unwrap();
+    assert_eq!(secrets.ls(), ["big"]);
+    // no keystream is used twice: overwriting data or reusing a directory slot
+    // must not leave the xor of the plaintexts in the xor of two images
+    let read_image = || device_for_images.image();
+    let keystream_reused = |before: &[u8], after: &[u8], plain_xor: &[u8]| {
+        let xor: Vec<u8> = before.iter().zip(after).map(|(a, b)| a ^ b).collect();
+        xor.windows(plain_xor.len()).any(|w| w == plain_xor)
+    };
+    let file = secrets.create("overwritten").unwrap();
+    file.write_at(0, &[0u8; 200]);
+    let before = read_image();
+    file.write_at(0, &[0xffu8; 200]);
+    assert!(!keystream_reused(&before, &read_image(), &[0xff; 64]));
+    assert_eq!(file.read_at(0, &mut buf[..201]), 200);
+    assert!(buf[..200] == [0xff; 200]);
+    secrets.create("secret-one").unwrap();
+    let before = read_image();
+    secrets.unlink("secret-one").unwrap();
+    secrets.create("secret-two").unwrap();
+    let mut name_xor = [0u8; 27];
+    for (x, (a, b)) in name_xor.iter_mut().zip(b"secret-one".iter().zip(b"secret-two")) {
+        *x = a ^ b;
+    }
+    assert!(!keystream_reused(&before, &read_image(), &name_xor));
+    let mut names = secrets.ls();
+    names.sort();
+    assert_eq!(names, ["big", "overwritten", "secret-two"]);
+}
+
+#[test]
+fn efs_quota_test() -> std::io::Result<()> {
+    let block_file = image_file("target/fs_quota.img", 8192);
+    let efs = EasyFileSystem::create(block_file.clone(), 8192, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let home = root_inode.mkdir("alice").unwrap();
+    home.chown(1000).unwrap();
+    assert_eq!(home.owner(), 1000);
+    efs.lock().set_quota(1000, 20, 40, 0, 5).unwrap();
+    let quota = |uid: u32| efs.lock().quotas().into_iter().find(|quota| quota.uid == uid).unwrap();
+    // files under the directory belong to its owner, up to the hard inode limit
+    for i in 0..4 {
+        let file = home.create(&format!("file{}", i)).unwrap();
+        assert_eq!(file.owner(), 1000);
+    }
+    assert!(home.create("file4").is_none());
+    assert_eq!(quota(1000).inodes, 5);
+    // blocks pass the soft limit, but not the hard one
+    let file0 = home.find("file0").unwrap();
+    assert_eq!(file0.write_at(0, &[1u8; 25 * BLOCK_SZ]), 25 * BLOCK_SZ);
+    assert!(quota(1000).over_soft_limit());
+    let file1 = home.find("file1").unwrap();
+    assert_eq!(file1.write_at(0, &[2u8; 20 * BLOCK_SZ]), 0);
+    assert_eq!(file1.write_at(0, &[2u8; 10 * BLOCK_SZ]), 10 * BLOCK_SZ);
+    let used = quota(1000);
+    assert!(used.blocks <= 40);
+    // root is not limited, and not charged for the files of alice
+    assert_eq!(quota(0).inodes, 1);
+    assert!(root_inode.create("big").unwrap().write_at(0, &[3u8; 100 * BLOCK_SZ]) > 0);
+    assert_eq!(quota(1000), used);
+    // deleting a file gives its blocks and inode back, once it is not open any more
+    home.unlink("file0").unwrap();
+    assert_eq!(quota(1000).inodes, used.inodes);
+    assert!(quota(1000).blocks > used.blocks - 25);
+    let mut buf = [0u8; BLOCK_SZ];
+    assert_eq!(file0.read_at(24 * BLOCK_SZ, &mut buf), BLOCK_SZ);
+    assert!(buf == [1u8; BLOCK_SZ]);
+    drop(file0);
+    assert_eq!(quota(1000).inodes, 4);
+    assert!(quota(1000).blocks < used.blocks - 25);
+    assert!(!quota(1000).over_soft_limit());
+    assert_eq!(home.find("file1").unwrap().write_at(10 * BLOCK_SZ, &[2u8; 20 * BLOCK_SZ]), 20 * BLOCK_SZ);
+    // a soft limit can only be passed by a few more allocations
+    efs.lock().set_quota(1000, 0, 0, 2, 0).unwrap();
+    let mut created = 0;
+    while home.create(&format!("new{}", created)).is_some() {
+        created += 1;
+    }
+    assert_eq!(created, easy_fs::QUOTA_GRACE as usize);
+    // usage is counted again from the inodes after a rollback
+    efs.lock().set_quota(1000, 0, 0, 0, 0).unwrap();
+    let before = quota(1000);
+    efs.lock().snapshot("before").unwrap();
+    home.create("after").unwrap().write_at(0, &[4u8; 5 * BLOCK_SZ]);
+    efs.lock().rollback("before").unwrap();
+    assert_eq!(quota(1000), before);
+    // the usage stays on disk
+    let efs = EasyFileSystem::open(block_file);
+    let reopened = efs.lock().quotas();
+    assert!(reopened.contains(&before));
+    // a directory is only deleted once empty
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let home = root_inode.find("alice").unwrap();
+    assert!(root_inode.unlink("alice").is_err());
+    // a file unlinked while open is freed when the image is mounted again
+    let file2 = home.find("file2").unwrap();
+    home.unlink("file2").unwrap();
+    let inodes = |quotas: Vec<easy_fs::QuotaEntry>| {
+        quotas.into_iter().find(|quota| quota.uid == 1000).unwrap().inodes
+    };
+    let orphaned = inodes(efs.lock().quotas());
+    let copy = Arc::new(easy_fs::RamBlockDevice::from_image(&std::fs::read("target/fs_quota.img")?));
+    assert_eq!(inodes(EasyFileSystem::open(copy).lock().quotas()), orphaned - 1);
+    drop(file2);
+    assert_eq!(inodes(efs.lock().quotas()), orphaned - 1);
+    Ok(())
+}
+
+#[test]
+fn faulty_block_device_test() {
+    use easy_fs::{FaultMode, FaultTrigger, FaultyBlockDevice, RamBlockDevice};
+    let block = |byte: u8| [byte; BLOCK_SZ];
+    let read = |device: &dyn BlockDevice, block_id: usize| {
+        let mut buf = [0u8; BLOCK_SZ];
+        device.read_block(block_id, &mut buf);
+        buf[0]
+    };
+    // lost writes after 2 operations
+    let ram = Arc::new(RamBlockDevice::new(8));
+    let faulty = FaultyBlockDevice::new(ram.clone(), FaultTrigger::AfterOps(2), FaultMode::Drop);
+    faulty.write_block(0, &block(1));
+    assert_eq!(read(&faulty, 0), 1);
+    faulty.write_block(1, &block(2));
+    assert!(faulty.triggered());
+    faulty.write_block(2, &block(3));
+    assert_eq!((read(&*ram, 1), read(&*ram, 2)), (0, 0));
+    assert_eq!(faulty.faulty_writes(), 2);
+    // swapped writes from block 3 on, the last one is held back
+    let ram = Arc::new(RamBlockDevice::new(8));
+    let faulty = FaultyBlockDevice::new(ram.clone(), FaultTrigger::AtBlock(3), FaultMode::Reorder);
+    faulty.write_block(1, &block(1));
+    faulty.write_block(3, &block(3));
+    assert_eq!((read(&*ram, 1), read(&*ram, 3)), (1, 0));
+    assert_eq!(read(&faulty, 3), 3);
+    faulty.write_block(4, &block(4));
+    assert_eq!((read(&*ram, 3), read(&*ram, 4)), (3, 4));
+    faulty.write_block(5, &block(5));
+    assert_eq!(read(&*ram, 5), 0);
+    assert_eq!(ram.image()[3 * BLOCK_SZ..6 * BLOCK_SZ].iter().filter(|b| **b != 0).count(), 2 * BLOCK_SZ);
+    // failing writes
+    let faulty = FaultyBlockDevice::new(ram, FaultTrigger::AtBlock(6), FaultMode::Fail);
+    faulty.write_block(7, &block(7));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        faulty.write_block(6, &block(6));
+    }));
+    assert!(result.is_err());
+}
+
+/// Reference model of the files in a directory for [`efs_model_test`]
+#[cfg(test)]
+#[derive(Default)]
+struct ModelDir {
+    /// File names and the files they link to
+    names: std::collections::BTreeMap<String, usize>,
+    /// Content and link count of every file ever created
+    files: Vec<(Vec<u8>, u32)>,
+}
+
+#[cfg(test)]
+impl ModelDir {
+    fn file(&mut self, name: &str) -> Option<&mut (Vec<u8>, u32)> {
+        let index = *self.names.get(name)?;
+        Some(&mut self.files[index])
+    }
+    /// Compare the model with a directory of easy-fs
+    fn check(&self, dir: &easy_fs::Inode) {
+        let mut names = dir.ls();
+        names.sort();
+        assert_eq!(names, self.names.keys().cloned().collect::<Vec<_>>());
+        for (name, index) in self.names.iter() {
+            let (data, links) = &self.files[*index];
+            let inode = dir.find(name).unwrap();
+            assert_eq!(inode.link_cnt(), *links, "link count of {}", name);
+            let mut buf = vec![0u8; data.len() + 1];
+            assert_eq!(inode.read_at(0, &mut buf), data.len(), "size of {}", name);
+            assert!(buf[..data.len()] == data[..], "content of {}", name);
+        }
+    }
+}
+
+#[test]
+fn efs_model_test() {
+    use easy_fs::RamBlockDevice;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    const NAMES: usize = 12;
+    for seed in 0..6u64 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut device = Arc::new(RamBlockDevice::new(4096));
+        let mut efs = EasyFileSystem::create(device.clone(), 4096, 1);
+        let mut root_inode = EasyFileSystem::root_inode(&efs);
+        let mut model = ModelDir::default();
+        for step in 0..300 {
+            let name = format!("file{}", rng.gen_range(0..NAMES));
+            match rng.gen_range(0..10) {
+                0 | 1 => {
+                    let created = root_inode.create(&name);
+                    assert_eq!(created.is_some(), !model.names.contains_key(&name));
+                    if let Some(inode) = created {
+                        if rng.gen_bool(0.25) {
+                            inode.set_compressed(true).unwrap();
+                        }
+                        model.names.insert(name, model.files.len());
+                        model.files.push((Vec::new(), 1));
+                    }
+                }
+                2..=4 => {
+                    let max_len = if rng.gen_bool(0.1) { 100 * BLOCK_SZ } else { 3 * BLOCK_SZ };
+                    let (len, offset) = match model.file(&name) {
+                        Some((data, _)) => (
+                            rng.gen_range(1..max_len),
+                            rng.gen_range(0..data.len() + 2 * BLOCK_SZ),
+                        ),
+                        None => continue,
+                    };
+                    // compressible and random data
+                    let buf: Vec<u8> = if rng.gen_bool(0.5) {
+                        (0..len).map(|i| (i / 64) as u8).collect()
+                    } else {
+                        (0..len).map(|_| rng.gen()).collect()
+                    };
+                    let inode = root_inode.find(&name).unwrap();
+                    assert_eq!(inode.write_at(offset, &buf), len);
+                    let (data, _) = model.file(&name).unwrap();
+                    if data.len() < offset + len {
+                        data.resize(offset + len, 0);
+                    }
+                    data[offset..offset + len].copy_from_slice(&buf);
+                }
+                5 => {
+                    let target = format!("file{}", rng.gen_range(0..NAMES));
+                    let linked = root_inode.link(&name, &target).is_ok();
+                    assert_eq!(
+                        linked,
+                        !model.names.contains_key(&name) && model.names.contains_key(&target),
+                    );
+                    if linked {
+                        let index = model.names[&target];
+                        model.files[index].1 += 1;
+                        model.names.insert(name, index);
+                    }
+                }
+                6 | 7 => {
+                    assert_eq!(root_inode.unlink(&name).is_ok(), model.names.contains_key(&name));
+                    if let Some(index) = model.names.remove(&name) {
+                        model.files[index].1 -= 1;
+                    }
+                }
+                8 => {
+                    if let Some(inode) = root_inode.find(&name) {
+                        inode.clear();
+                        model.file(&name).unwrap().0.clear();
+                    }
+                }
+                _ => {
+                    if let Some((data, _)) = model.file(&name) {
+                        let start = rng.gen_range(0..data.len() + 1);
+                        let end = rng.gen_range(start..data.len() + BLOCK_SZ);
+                        let mut buf = vec![0u8; end - start];
+                        let read = root_inode.find(&name).unwrap().read_at(start, &mut buf);
+                        assert_eq!(read, data.len().min(end) - start);
+                        assert!(buf[..read] == data[start..start + read]);
+                    }
+                }
+            }
+            // carry on with a copy of the device mounted again from time to time
+            if step % 100 == 99 {
+                model.check(&root_inode);
+                device = Arc::new(RamBlockDevice::from_image(&device.image()));
+                efs = EasyFileSystem::open(device.clone());
+                root_inode = EasyFileSystem::root_inode(&efs);
+            }
+        }
+        model.check(&root_inode);
+        // everything freed is reused: no blocks leak
+        for name in model.names.keys() {
+            root_inode.unlink(name).unwrap();
+        }
+        assert!(root_inode.ls().is_empty());
+        assert_eq!(efs.lock().quotas()[0].inodes, 1);
+    }
+}
+
+#[test]
+fn efs_crash_test() {
+    use easy_fs::{FaultMode, FaultTrigger, FaultyBlockDevice, RamBlockDevice};
+    let data = |seed: usize, len: usize| -> Vec<u8> {
+        (0..len).map(|i| ((i * 13 + seed) % 251) as u8).collect()
+    };
+    let check = |root_inode: &easy_fs::Inode, files: usize| {
+        let mut buf = vec![0u8; 40 * BLOCK_SZ];
+        for i in 0..files {
+            let file = root_inode.find(&format!("old{}", i)).unwrap();
+            let len = file.read_at(0, &mut buf);
+            assert!(buf[..len] == data(i, (i + 1) * 5 * BLOCK_SZ + i)[..]);
+        }
+    };
+    for mode in [FaultMode::Drop, FaultMode::Reorder] {
+        // crash at every few operations, until the device no longer faults
+        for ops in (0..).step_by(3) {
+            // files written before the crash
+            let ram = Arc::new(RamBlockDevice::new(2048));
+            let efs = EasyFileSystem::create(ram.clone(), 2048, 1);
+            let root_inode = EasyFileSystem::root_inode(&efs);
+            for i in 0..4 {
+                let name = format!("old{}", i);
+                root_inode.create(&name).unwrap().write_at(0, &data(i, (i + 1) * 5 * BLOCK_SZ + i));
+            }
+            // only creating files when the device starts to fault
+            let faulty = Arc::new(FaultyBlockDevice::new(ram.clone(), FaultTrigger::AfterOps(ops), mode));
+            let efs = EasyFileSystem::open(faulty.clone());
+            let root_inode = EasyFileSystem::root_inode(&efs);
+            for i in 0..6 {
+                let name = format!("new{}", i);
+                root_inode.create(&name).unwrap().write_at(0, &data(i + 10, 3 * BLOCK_SZ));
+            }
+            // what reached the disk mounts, keeps the old files and takes new ones
+            let crashed = Arc::new(RamBlockDevice::from_image(&ram.image()));
+            let efs = EasyFileSystem::open(crashed);
+            let root_inode = EasyFileSystem::root_inode(&efs);
+            check(&root_inode, 4);
+            let file = root_inode.create("after").unwrap();
+            assert_eq!(file.write_at(0, &data(99, 2 * BLOCK_SZ)), 2 * BLOCK_SZ);
+            check(&root_inode, 4);
+            if !faulty.triggered() {
+                assert!(ops > 0);
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn efs_reproducible_test() -> std::io::Result<()> {
+    let apps = ["cat", "initproc", "user_shell"];
+    let data = |app: &str| -> Vec<u8> { app.bytes().cycle().take(app.len() * 300).collect() };
+    // pack `apps` created in this order with `--reproducible`, over whatever is already there
+    let pack = |dir: &str, order: &[usize]| -> std::io::Result<String> {
+        std::fs::create_dir_all(format!("{}src", dir))?;
+        for &i in order {
+            File::create(format!("{}src/{}.rs", dir, apps[i]))?;
+            File::create(format!("{}{}", dir, apps[i]))?.write_all(&data(apps[i]))?;
+        }
+        easy_fs_pack(vec![
+            "easy-fs-fuse".to_string(),
+            "-s".to_string(),
+            format!("{}src/", dir),
+            "-t".to_string(),
+            dir.to_string(),
+            "--reproducible".to_string(),
+        ])?;
+        image_sha256(&format!("{}fs.img", dir))
+    };
+    // what packing in sorted order must give, whatever order the host lists the inputs in
+    let expected = {
+        let block_file = image_file("target/fs_repro.img", BLOCK_NUM);
+        let efs = EasyFileSystem::create(block_file, BLOCK_NUM as u32, 1);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        for app in apps.iter() {
+            root_inode.create(app).unwrap().write_at(0, &data(app));
+        }
+        drop(root_inode);
+        drop(efs);
+        image_sha256("target/fs_repro.img")?
+    };
+    let _ = std::fs::remove_dir_all("target/repro_a/");
+    let _ = std::fs::remove_dir_all("target/repro_b/");
+    // a dirty image, larger than packed ones and with garbage all over it
+    std::fs::create_dir_all("target/repro_a/")?;
+    let garbage: Vec<u8> = (0..(BLOCK_NUM + 16) * BLOCK_SZ).map(|i| (i * 7 + 1) as u8).collect();
+    std::fs::write("target/repro_a/fs.img", &garbage)?;
+    assert_eq!(pack("target/repro_a/", &[2, 0, 1])?, expected);
+    // packing again over the image just packed
+    assert_eq!(pack("target/repro_a/", &[])?, expected);
+    // the same inputs created in another order, one of them twice under another extension
+    std::fs::create_dir_all("target/repro_b/src")?;
+    File::create("target/repro_b/src/initproc.S")?;
+    assert_eq!(pack("target/repro_b/", &[1, 2, 0])?, expected);
+    // different contents must not hash the same
+    File::create("target/repro_b/cat")?.write_all(b"meow")?;
+    assert_ne!(pack("target/repro_b/", &[])?, expected);
+    // SHA-256 of the empty input
+    File::create("target/empty.img")?;
+    assert_eq!(
+        image_sha256("target/empty.img")?,
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    Ok(())
+}
+
+#[test]
+fn efs_tar_test() -> std::io::Result<()> {
+    use easy_fs::RamBlockDevice;
+    let new_fs = || {
+        let device = Arc::new(RamBlockDevice::new(4096));
+        let efs = EasyFileSystem::create(device, 4096, 1);
+        let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+        (efs, root_inode)
+    };
+    let (_efs, root_inode) = new_fs();
+    let bin = root_inode.mkdir("bin").unwrap();
+    let etc = root_inode.mkdir("etc").unwrap();
+    let deep = etc.mkdir("init.d").unwrap().mkdir("very_long_directory_name_27").unwrap();
+    let shell = bin.create("user_shell").unwrap();
+    let data: Vec<u8> = (0..70 * BLOCK_SZ + 3).map(|i| (i % 253) as u8).collect();
+    shell.write_at(0, &data);
+    bin.create("empty").unwrap();
+    deep.create("rc").unwrap().write_at(0, b"#!/bin/sh\n");
+    // hard links in the same and in another directory
+    bin.link("sh", "user_shell").unwrap();
+    etc.link_inode("shell", &shell).unwrap();
+    etc.chown(1000).unwrap();
+    let mut archive = Vec::new();
+    assert_eq!(tar::export(&root_inode, &mut archive)?, 9);
+    assert_eq!(archive.len() % 512, 0);
+    // another image gets the same tree back
+    let (_efs2, root2) = new_fs();
+    assert_eq!(tar::import(&root2, archive.as_slice())?, 9);
+    let mut names = root2.ls();
+    names.sort();
+    assert_eq!(names, ["bin", "etc"]);
+    let shell2 = root2.find("bin").unwrap().find("user_shell").unwrap();
+    assert_eq!(shell2.link_cnt(), 3);
+    let mut buf = vec![0u8; data.len() + 1];
+    assert_eq!(shell2.read_at(0, &mut buf), data.len());
+    assert!(buf[..data.len()] == data[..]);
+    let etc2 = root2.find("etc").unwrap();
+    assert_eq!(etc2.owner(), 1000);
+    assert_eq!(etc2.find("shell").unwrap().inode_id, shell2.inode_id);
+    let rc = etc2.find("init.d").unwrap().find("very_long_directory_name_27").unwrap().find("rc").unwrap();
+    assert_eq!(rc.read_at(0, &mut buf), 10);
+    let mut archive2 = Vec::new();
+    tar::export(&root2, &mut archive2)?;
+    assert!(archive == archive2);
+    // importing again replaces the files, which keep their links
+    shell2.write_at(0, b"changed");
+    tar::import(&root2, archive.as_slice())?;
+    assert_eq!(shell2.read_at(0, &mut buf), data.len());
+    assert_eq!(&buf[..7], &data[..7]);
+    // an encrypted file skipped without the key leaves no hard link to it behind
+    let device = Arc::new(RamBlockDevice::new(4096));
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1);
+    efs.lock().set_master_key([7u8; KEY_SZ]).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let secrets = root_inode.mkdir("secrets").unwrap();
+    secrets.set_encrypted().unwrap();
+    let note = secrets.create("note").unwrap();
+    note.write_at(0, b"secret");
+    // without the key only the links outside the encrypted directory are found
+    root_inode.link_inode("a_link", &note).unwrap();
+    root_inode.link_inode("z_link", &note).unwrap();
+    root_inode.create("plain").unwrap().write_at(0, b"plain");
+    let no_key = EasyFileSystem::root_inode(&EasyFileSystem::open(device));
+    let mut archive = Vec::new();
+    assert_eq!(tar::export(&no_key, &mut archive)?, 2);
+    let (_efs3, root3) = new_fs();
+    assert_eq!(tar::import(&root3, archive.as_slice())?, 2);
+    let mut names = root3.ls();
+    names.sort();
+    assert_eq!(names, ["plain", "secrets"]);
+    Ok(())
+}
+
+#[test]
+fn efs_defrag_test() {
+    use easy_fs::RamBlockDevice;
+    let device: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::new(4096));
+    let efs = EasyFileSystem::create(Arc::clone(&device), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    let fileb = root_inode.create("fileb").unwrap();
+    let filec = root_inode.create("filec").unwrap();
+    let block = |seed: usize| -> Vec<u8> { (0..BLOCK_SZ).map(|i| ((i + seed) % 251) as u8).collect() };
+    // growing the files in turn interleaves their blocks
+    for i in 0..60 {
+        filea.write_at(i * BLOCK_SZ, &block(i));
+        fileb.write_at(i * BLOCK_SZ, &block(i + 1));
+        filec.write_at(i * BLOCK_SZ, &block(i + 2));
+    }
+    fileb.clear();
+    let before = filea.fragmentation();
+    assert_eq!(before.files, 1);
+    assert_eq!(before.blocks, 61);
+    assert!(before.extents > 1 && before.score() > 0);
+    efs.lock().snapshot("before").unwrap();
+    let used = efs.lock().used_data_blocks();
+    assert!(filea.defragment().unwrap());
+    let after = filea.fragmentation();
+    assert_eq!((after.blocks, after.extents, after.score()), (61, 1, 0));
+    assert!(!filea.defragment().unwrap());
+    assert!(efs.lock().volume_fragmentation().score() > 0);
+    assert!(efs.lock().defragment().unwrap() >= 1);
+    let volume = efs.lock().volume_fragmentation();
+    // the root directory and the cleared file are stored inline
+    assert_eq!((volume.files, volume.extents, volume.score()), (2, 2, 0));
+    assert_eq!(efs.lock().used_data_blocks(), used);
+    let check = |inode: &Inode, seed: usize| {
+        let mut buf = [0u8; BLOCK_SZ];
+        for i in 0..60 {
+            assert_eq!(inode.read_at(i * BLOCK_SZ, &mut buf), BLOCK_SZ);
+            assert!(buf[..] == block(i + seed)[..]);
+        }
+    };
+    check(&filea, 0);
+    check(&filec, 2);
+    // blocks still shared with the snapshot are copied on write
+    filea.write_at(0, &block(7));
+    let snapshot = EasyFileSystem::open_snapshot(Arc::clone(&device), "before").unwrap();
+    check(&EasyFileSystem::root_inode(&snapshot).find("filea").unwrap(), 0);
+    let efs2 = EasyFileSystem::open(device);
+    check(&EasyFileSystem::root_inode(&efs2).find("filec").unwrap(), 2);
+}
+
+#[test]
+fn mirror_block_device_test() {
+    use easy_fs::{FaultMode, FaultTrigger, FaultyBlockDevice, MirrorBlockDevice, RamBlockDevice};
+    let block = |n: u8| [n; BLOCK_SZ];
+    let read = |device: &dyn BlockDevice, block_id: usize| {
+        let mut buf = [0u8; BLOCK_SZ];
+        device.read_block(block_id, &mut buf);
+        buf[0]
+    };
+    assert_eq!(MirrorBlockDevice::usable_blocks(129), 128);
+    assert_eq!(MirrorBlockDevice::usable_blocks(130), 128);
+    let (a, b) = (Arc::new(RamBlockDevice::new(258)), Arc::new(RamBlockDevice::new(258)));
+    let mirror = MirrorBlockDevice::new(a.clone(), b.clone(), 258);
+    assert_eq!(mirror.total_blocks(), 256);
+    for i in 0..256 {
+        mirror.write_block(i, &block((i % 200) as u8 + 1));
+    }
+    assert!(a.image() == b.image());
+    // copies gone bad behind the back of the mirror are rewritten from the good ones
+    b.write_block(7, &block(99));
+    a.write_block(8, &block(99));
+    for block_id in [7, 7, 8, 8] {
+        assert_eq!(read(&mirror, block_id), block_id as u8 + 1);
+    }
+    assert_eq!(mirror.repaired(), 2);
+    assert!(a.image() == b.image());
+    // the checksums are kept on the devices
+    b.write_block(9, &block(99));
+    let mirror = MirrorBlockDevice::new(a.clone(), b.clone(), 258);
+    assert_eq!(mirror.resync(), 0);
+    assert_eq!(mirror.repaired(), 1);
+    assert!(a.image() == b.image());
+    // copies which agree are taken even if their checksum did not make it
+    a.write_block(10, &block(98));
+    b.write_block(10, &block(98));
+    assert_eq!(read(&mirror, 10), 98);
+    a.write_block(11, &block(97));
+    b.write_block(11, &block(99));
+    assert!(mirror.try_read_block(11, &mut [0u8; BLOCK_SZ]).is_err());
+    mirror.write_block(11, &block(12));
+    // a disk dying leaves the mirror degraded, a blank one takes its place
+    let faulty = Arc::new(FaultyBlockDevice::new(b.clone(), FaultTrigger::AtBlock(20), FaultMode::Error));
+    let mirror = MirrorBlockDevice::new(a.clone(), faulty.clone(), 258);
+    mirror.write_block(19, &block(50));
+    assert!(!mirror.degraded());
+    mirror.write_block(20, &block(51));
+    assert!(mirror.degraded());
+    for block_id in [19, 19, 20, 20] {
+        assert_eq!(read(&mirror, block_id), block_id as u8 + 31);
+    }
+    assert_eq!(read(&*b, 20), 21);
+    let c = Arc::new(RamBlockDevice::new(258));
+    let mirror = MirrorBlockDevice::new(a.clone(), c.clone(), 258);
+    assert_eq!(mirror.resync(), 0);
+    assert!(!mirror.degraded());
+    assert!(a.image() == c.image());
+}
+
+#[test]
+fn concat_block_device_test() {
+    use easy_fs::{ConcatBlockDevice, RamBlockDevice};
+    let parts: Vec<Arc<RamBlockDevice>> = [1200, 300, 1000]
+        .iter()
+        .map(|blocks| Arc::new(RamBlockDevice::new(*blocks)))
+        .collect();
+    let concat = Arc::new(ConcatBlockDevice::new(
+        parts
+            .iter()
+            .map(|part| (part.clone() as Arc<dyn BlockDevice>, part.total_blocks()))
+            .collect(),
+    ));
+    assert_eq!(concat.total_blocks(), 2500);
+    let efs = EasyFileSystem::create(concat.clone(), 2500, 1);
+    let file = EasyFileSystem::root_inode(&efs).create("file").unwrap();
+    let data: Vec<u8> = (0..1300 * BLOCK_SZ).map(|i| (i % 241) as u8).collect();
+    assert_eq!(file.write_at(0, &data), data.len());
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(file.read_at(0, &mut buf), data.len());
+    assert!(buf == data);
+    for part in parts.iter() {
+        assert!(part.image().iter().any(|byte| *byte != 0));
+    }
+    // a batched read across the devices reads the same as single blocks
+    let mut batch = vec![0u8; 600 * BLOCK_SZ];
+    concat.read_blocks(1000, &mut batch);
+    for (i, block) in batch.chunks(BLOCK_SZ).enumerate() {
+        let mut single = [0u8; BLOCK_SZ];
+        concat.read_block(1000 + i, &mut single);
+        assert!(block == single);
+    }
+}
+
+#[test]
+fn efs_mirror_test() {
+    use easy_fs::{FaultMode, FaultTrigger, FaultyBlockDevice, MirrorBlockDevice, RamBlockDevice};
+    let (a, b) = (Arc::new(RamBlockDevice::new(4096)), Arc::new(RamBlockDevice::new(4096)));
+    // the second disk dies while files are written
+    let faulty = Arc::new(FaultyBlockDevice::new(b, FaultTrigger::AfterOps(9000), FaultMode::Error));
+    let mirror = Arc::new(MirrorBlockDevice::new(a.clone(), faulty.clone(), 4096));
+    let efs = EasyFileSystem::create(mirror.clone(), mirror.total_blocks() as u32, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let content = |i: usize| -> Vec<u8> { (0..20 * BLOCK_SZ).map(|j| ((i * 7 + j) % 251) as u8).collect() };
+    for i in 0..30 {
+        let file = root_inode.create(&format!("file{}", i)).unwrap();
+        assert_eq!(file.write_at(0, &content(i)), 20 * BLOCK_SZ);
+    }
+    assert!(faulty.triggered() && mirror.degraded());
+    // the surviving disk is copied to a new one, which has everything on its own
+    let c = Arc::new(RamBlockDevice::new(4096));
+    assert_eq!(MirrorBlockDevice::new(a.clone(), c.clone(), 4096).resync(), 0);
+    let mirror = Arc::new(MirrorBlockDevice::new(c, Arc::new(RamBlockDevice::new(4096)), 4096));
+    let efs = EasyFileSystem::open(mirror);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut buf = vec![0u8; 20 * BLOCK_SZ + 1];
+    for i in 0..30 {
+        let file = root_inode.find(&format!("file{}", i)).unwrap();
+        assert_eq!(file.read_at(0, &mut buf), 20 * BLOCK_SZ);
+        assert!(buf[..20 * BLOCK_SZ] == content(i)[..]);
+    }
+}
+
+#[test]
+fn efs_diff_test() -> std::io::Result<()> {
+    use easy_fs::RamBlockDevice;
+    let new_fs = |blocks: u32| {
+        let device = Arc::new(RamBlockDevice::new(blocks as usize));
+        let efs = EasyFileSystem::create(device, blocks, 1);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        (efs, root_inode)
+    };
+    let data: Vec<u8> = (0..3 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let (_efs_a, root_a) = new_fs(4096);
+    let (_efs_b, root_b) = new_fs(8192);
+    for root_inode in [&root_a, &root_b] {
+        root_inode.create("same").unwrap().write_at(0, &data);
+        root_inode.create("changed").unwrap().write_at(0, &data);
+        root_inode.mkdir("bin").unwrap().create("sh").unwrap().write_at(0, b"#!");
+    }
+    let (mut no_diff, mut output) = (Vec::new(), Vec::new());
+    assert_eq!(diff::diff(&root_a, &root_a, &mut no_diff)?, 0);
+    assert!(no_diff.is_empty());
+    root_a.create("removed").unwrap().write_at(0, b"old");
+    root_b.mkdir("etc").unwrap().create("added").unwrap();
+    let changed = root_b.find("changed").unwrap();
+    changed.write_at(10, b"xyz");
+    changed.write_at(700, b"a");
+    changed.write_at(3 * BLOCK_SZ, b"tail");
+    root_b.link("same2", "same").unwrap();
+    assert_eq!(diff::diff(&root_a, &root_b, &mut output)?, 11);
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "total blocks: 4096 -> 8192",
+            "data bitmap blocks: 1 -> 2",
+            "data area blocks: 2941 -> 7036",
+            "inodes used: 6 -> 7",
+            "data blocks used: 7 -> 8",
+            "- /removed (size 3, links 1)",
+            "~ /changed: size 1536 -> 1540, links 1, bytes 10..13, 700..701, 1536..1540 differ",
+            "+ /etc/ (size 32, links 1)",
+            "+ /etc/added (size 0, links 1)",
+            "~ /same: size 1536, links 1 -> 2",
+            "+ /same2 (size 1536, links 2)",
+        ][..]
+    );
+    // a directory linked into its own subtree is listed once, not followed forever
+    root_b.find("bin").unwrap().link_inode("up", &root_b).unwrap();
+    let mut output = Vec::new();
+    diff::diff(&root_a, &root_b, &mut output)?;
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.lines().any(|line| line.starts_with("+ /bin/up/ ")));
+    assert!(!output.contains("/bin/up/bin/"));
+    // images are inspected as they are, an orphan left in one is not reclaimed
+    let path = "target/fs_diff.img";
+    let efs = EasyFileSystem::create(image_file(path, 4096), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let orphan = root_inode.create("orphan").unwrap();
+    orphan.write_at(0, &data);
+    root_inode.unlink("orphan").unwrap();
+    let image = std::fs::read(path)?;
+    assert_eq!(diff_images(path, path, None)?, 0);
+    assert!(std::fs::read(path)? == image);
+    drop(orphan);
+    Ok(())
+}
@@ -0,0 +1,397 @@
+//! Import and export of easy-fs trees as ustar archives.
+//! easy-fs keeps no modes, times or symlinks: modes are fixed when exporting
+//! and ignored when importing, symlinks and special files are skipped.
+
+use easy_fs::{DiskInodeType, Inode, NAME_LENGTH_LIMIT};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+/// Size of a header or data record of a tar archive
+const RECORD_SZ: usize = 512;
+/// Size of the chunks file data is copied in
+const CHUNK_SZ: usize = 64 * RECORD_SZ;
+const TYPE_FILE: u8 = b'0';
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_CONTIGUOUS: u8 = b'7';
+const TYPE_PAX: u8 = b'x';
+const TYPE_PAX_GLOBAL: u8 = b'g';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_GNU_LONG_LINK: u8 = b'K';
+const FILE_MODE: u32 = 0o644;
+const DIRECTORY_MODE: u32 = 0o755;
+
+/// The fields of a tar header easy-fs has a use for
+struct Header {
+    path: String,
+    uid: u32,
+    size: u64,
+    typeflag: u8,
+    link_path: String,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Get a NUL terminated string field
+fn field_str(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// Parse an octal number field, padded by spaces or NULs
+fn field_octal(field: &[u8]) -> io::Result<u64> {
+    let digits = field_str(field);
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid("bad number in tar header"))
+}
+
+/// Write `value` as octal digits filling `field` but its last byte, which is NUL
+fn put_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
+}
+
+/// Sum of the header bytes, with the checksum field counted as spaces
+fn checksum(record: &[u8; RECORD_SZ]) -> u64 {
+    record
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' as u64 } else { *b as u64 })
+        .sum()
+}
+
+/// Parse a header record, `None` for the zero record ending the archive
+fn parse_header(record: &[u8; RECORD_SZ]) -> io::Result<Option<Header>> {
+    if record.iter().all(|b| *b == 0) {
+        return Ok(None);
+    }
+    if field_octal(&record[148..156])? != checksum(record) {
+        return Err(invalid("bad tar header checksum"));
+    }
+    let mut path = field_str(&record[..100]);
+    if &record[257..262] == b"ustar" {
+        let prefix = field_str(&record[345..500]);
+        if !prefix.is_empty() {
+            path = format!("{}/{}", prefix, path);
+        }
+    }
+    Ok(Some(Header {
+        path,
+        uid: field_octal(&record[108..116])? as u32,
+        size: field_octal(&record[124..136])?,
+        typeflag: record[156],
+        link_path: field_str(&record[157..257]),
+    }))
+}
+
+/// Make a ustar header record
+fn make_header(
+    path: &str,
+    uid: u32,
+    size: u64,
+    typeflag: u8,
+    link_path: &str,
+) -> io::Result<[u8; RECORD_SZ]> {
+    let mut record = [0u8; RECORD_SZ];
+    // split long paths between the prefix and the name fields
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path
+            .char_indices()
+            .filter(|(i, c)| *c == '/' && *i <= 155 && path.len() - i - 1 <= 100)
+            .map(|(i, _)| i)
+            .next()
+            .ok_or_else(|| invalid("path too long for tar"))?;
+        (&path[..split], &path[split + 1..])
+    };
+    if link_path.len() > 100 {
+        return Err(invalid("link path too long for tar"));
+    }
+    let mode = if typeflag == TYPE_DIRECTORY { DIRECTORY_MODE } else { FILE_MODE };
+    record[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut record[100..108], mode as u64);
+    put_octal(&mut record[108..116], uid as u64);
+    put_octal(&mut record[116..124], 0);
+    put_octal(&mut record[124..136], size);
+    // no times in easy-fs, which also keeps archives reproducible
+    put_octal(&mut record[136..148], 0);
+    record[156] = typeflag;
+    record[157..157 + link_path.len()].copy_from_slice(link_path.as_bytes());
+    record[257..263].copy_from_slice(b"ustar\0");
+    record[263..265].copy_from_slice(b"00");
+    record[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let sum = checksum(&record);
+    record[148..154].copy_from_slice(format!("{:06o}", sum).as_bytes());
+    record[154] = 0;
+    record[155] = b' ';
+    Ok(record)
+}
+
+/// Split a path of an archive into names, `None` if it leaves the tree
+fn components(path: &str) -> Option<Vec<&str>> {
+    let names: Vec<&str> = path
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect();
+    if names.contains(&"..") {
+        return None;
+    }
+    Some(names)
+}
+
+/// Find an inode by the names along its path
+fn lookup(root: &Arc<Inode>, names: &[&str]) -> Option<Arc<Inode>> {
+    let mut inode = Arc::clone(root);
+    for name in names {
+        if !inode.is_dir() {
+            return None;
+        }
+        inode = inode.find(name)?;
+    }
+    Some(inode)
+}
+
+/// Find a directory by path, creating what is missing of it
+fn make_dirs(root: &Arc<Inode>, names: &[&str]) -> io::Result<Arc<Inode>> {
+    let mut dir = Arc::clone(root);
+    for name in names {
+        dir = match dir.find(name) {
+            Some(inode) if inode.is_dir() => inode,
+            Some(_) => return Err(invalid("not a directory")),
+            None => dir.mkdir(name).ok_or_else(|| invalid("cannot create directory"))?,
+        };
+    }
+    Ok(dir)
+}
+
+/// Round a size up to whole records
+fn padded(size: u64) -> u64 {
+    let record_sz = RECORD_SZ as u64;
+    size + (record_sz - size % record_sz) % record_sz
+}
+
+/// Read the data of an entry, rounded up to whole records
+fn read_data(reader: &mut impl Read, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; padded(size) as usize];
+    reader.read_exact(&mut data)?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+/// Drop the data of an entry
+fn skip_data(reader: &mut impl Read, size: u64) -> io::Result<()> {
+    io::copy(&mut reader.take(padded(size)), &mut io::sink())?;
+    Ok(())
+}
+
+/// Get the path and link path set by a pax extended header
+fn parse_pax(data: &[u8]) -> (Option<String>, Option<String>) {
+    let (mut path, mut link_path) = (None, None);
+    // records of "<length> <key>=<value>\n"
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let len: usize = match std::str::from_utf8(&rest[..space]).ok().and_then(|len| len.parse().ok()) {
+            Some(len) if len > space && len <= rest.len() => len,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len - 1]).into_owned();
+        if let Some((key, value)) = record.split_once('=') {
+            match key {
+                "path" => path = Some(String::from(value)),
+                "linkpath" => link_path = Some(String::from(value)),
+                _ => {}
+            }
+        }
+        rest = &rest[len..];
+    }
+    (path, link_path)
+}
+
+/// Read a tar stream into the tree under `root`, replacing the content of files
+/// which exist already. Return the number of entries imported.
+pub fn import(root: &Arc<Inode>, mut reader: impl Read) -> io::Result<usize> {
+    let mut count = 0;
+    let (mut long_path, mut long_link_path): (Option<String>, Option<String>) = (None, None);
+    let mut record = [0u8; RECORD_SZ];
+    loop {
+        reader.read_exact(&mut record)?;
+        let mut header = match parse_header(&record)? {
+            Some(header) => header,
+            None => break,
+        };
+        match header.typeflag {
+            TYPE_PAX => {
+                let (path, link_path) = parse_pax(&read_data(&mut reader, header.size)?);
+                long_path = path.or(long_path);
+                long_link_path = link_path.or(long_link_path);
+                continue;
+            }
+            TYPE_GNU_LONG_NAME | TYPE_GNU_LONG_LINK => {
+                let name = field_str(&read_data(&mut reader, header.size)?);
+                if header.typeflag == TYPE_GNU_LONG_NAME {
+                    long_path = Some(name);
+                } else {
+                    long_link_path = Some(name);
+                }
+                continue;
+            }
+            TYPE_PAX_GLOBAL => {
+                skip_data(&mut reader, header.size)?;
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(path) = long_path.take() {
+            header.path = path;
+        }
+        if let Some(link_path) = long_link_path.take() {
+            header.link_path = link_path;
+        }
+        let names = match components(&header.path) {
+            Some(names) if names.iter().all(|name| name.len() <= NAME_LENGTH_LIMIT) => names,
+            _ => {
+                eprintln!("skipping {}: path not allowed in easy-fs", header.path);
+                skip_data(&mut reader, header.size)?;
+                continue;
+            }
+        };
+        match header.typeflag {
+            TYPE_DIRECTORY => {
+                let dir = make_dirs(root, &names)?;
+                if !names.is_empty() && dir.chown(header.uid).is_err() {
+                    eprintln!("{}: owner {} over quota", header.path, header.uid);
+                }
+                skip_data(&mut reader, header.size)?;
+            }
+            0 | TYPE_FILE | TYPE_CONTIGUOUS if !names.is_empty() => {
+                let (name, parent) = names.split_last().unwrap();
+                let dir = make_dirs(root, parent)?;
+                let file = match dir.find(name) {
+                    Some(file) if file.is_dir() => return Err(invalid("is a directory")),
+                    Some(file) => {
+                        file.clear();
+                        file
+                    }
+                    None => dir.create(name).ok_or_else(|| invalid("cannot create file"))?,
+                };
+                // own the file first, so that its blocks are accounted to its owner
+                if file.chown(header.uid).is_err() {
+                    eprintln!("{}: owner {} over quota", header.path, header.uid);
+                }
+                let mut chunk = vec![0u8; CHUNK_SZ];
+                let mut offset = 0usize;
+                while offset < header.size as usize {
+                    let len = CHUNK_SZ.min(header.size as usize - offset);
+                    reader.read_exact(&mut chunk[..len])?;
+                    if file.write_at(offset, &chunk[..len]) != len {
+                        return Err(io::Error::new(ErrorKind::WriteZero, "image full or over quota"));
+                    }
+                    offset += len;
+                }
+                let padding = (RECORD_SZ - offset % RECORD_SZ) % RECORD_SZ;
+                io::copy(&mut (&mut reader).take(padding as u64), &mut io::sink())?;
+            }
+            TYPE_HARD_LINK if !names.is_empty() => {
+                let target = components(&header.link_path)
+                    .and_then(|target| lookup(root, &target))
+                    .ok_or_else(|| invalid("hard link to a missing file"))?;
+                let (name, parent) = names.split_last().unwrap();
+                let dir = make_dirs(root, parent)?;
+                if dir.find(name).is_some() {
+                    eprintln!("skipping {}: exists already", header.path);
+                } else {
+                    dir.link_inode(name, &target)
+                        .map_err(|_| invalid("cannot create hard link"))?;
+                }
+                skip_data(&mut reader, header.size)?;
+            }
+            typeflag => {
+                let kind = if typeflag == TYPE_SYMLINK { "symlink" } else { "special file" };
+                eprintln!("skipping {}: {} not supported by easy-fs", header.path, kind);
+                skip_data(&mut reader, header.size)?;
+                continue;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Write the entries of a directory, and those under it
+fn export_dir(
+    dir: &Inode,
+    path: &str,
+    exported: &mut HashMap<u32, String>,
+    writer: &mut impl Write,
+) -> io::Result<usize> {
+    let mut count = 0;
+    let mut entries: Vec<_> = dir.read_dir(0).collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        let inode = match dir.find(&entry.name) {
+            Some(inode) => inode,
+            None => continue,
+        };
+        let entry_path = format!("{}{}", path, entry.name);
+        if let Some(link_path) = exported.get(&entry.inode_id) {
+            if entry.type_ == DiskInodeType::Directory {
+                eprintln!("skipping {}: hard link to a directory", entry_path);
+                continue;
+            }
+            writer.write_all(&make_header(&entry_path, inode.owner(), 0, TYPE_HARD_LINK, link_path)?)?;
+            count += 1;
+            continue;
+        }
+        if entry.type_ == DiskInodeType::Directory {
+            let dir_path = format!("{}/", entry_path);
+            writer.write_all(&make_header(&dir_path, inode.owner(), 0, TYPE_DIRECTORY, "")?)?;
+            exported.insert(entry.inode_id, entry_path);
+            count += 1 + export_dir(&inode, &dir_path, exported, writer)?;
+            continue;
+        }
+        if inode.is_encrypted() && !inode.filesystem().lock().has_master_key() {
+            eprintln!("skipping {}: encrypted, no key", entry_path);
+            continue;
+        }
+        let size = inode.size() as usize;
+        writer.write_all(&make_header(&entry_path, inode.owner(), size as u64, TYPE_FILE, "")?)?;
+        let mut chunk = vec![0u8; CHUNK_SZ];
+        let mut offset = 0;
+        while offset < size {
+            let want = CHUNK_SZ.min(size - offset);
+            // a short read, e.g. of a corrupt compressed cluster, would break the archive
+            if inode.read_at(offset, &mut chunk[..want]) != want {
+                return Err(invalid(&format!("cannot read {}", entry_path)));
+            }
+            writer.write_all(&chunk[..want])?;
+            offset += want;
+        }
+        writer.write_all(&vec![0u8; (RECORD_SZ - size % RECORD_SZ) % RECORD_SZ])?;
+        // only a file in the archive can be the target of later hard links
+        exported.insert(entry.inode_id, entry_path);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Write the tree under `root` as a tar stream, entries sorted by name.
+/// Return the number of entries exported.
+pub fn export(root: &Inode, mut writer: impl Write) -> io::Result<usize> {
+    let mut exported = HashMap::new();
+    exported.insert(root.inode_id, String::new());
+    let count = export_dir(root, "", &mut exported, &mut writer)?;
+    // two zero records end an archive
+    writer.write_all(&[0u8; 2 * RECORD_SZ])?;
+    writer.flush()?;
+    Ok(count)
+}
@@ -1,4 +1,5 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use super::{
     BlockDevice,
     BLOCK_SZ,
@@ -15,6 +16,12 @@ const BLOCK_BITS: usize = BLOCK_SZ * 8;
 pub struct Bitmap {
     start_block_id: usize,
     blocks: usize,
+    /// Number of allocatable bits, the bits after them are never allocated
+    bits: usize,
+    /// Next-fit hint: searching for free bits starts from here
+    hint: usize,
+    /// Number of free bits in each bitmap block, loaded on first use
+    free_counts: Vec<u16>,
 }
 
 /// Decompose bits into (block_pos, bits64_pos, inner_pos)
@@ -24,43 +31,225 @@ fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     (block_pos, bit / 64, bit % 64)
 }
 
+/// Collect the allocated bits of bitmap block `block_id` into `v`,
+/// numbered from `first_bit`
+pub fn block_allocated_bits(
+    block_id: usize,
+    first_bit: usize,
+    block_device: &Arc<dyn BlockDevice>,
+    v: &mut Vec<usize>,
+) {
+    get_block_cache(block_id, Arc::clone(block_device))
+        .lock()
+        .read(0, |bitmap_block: &BitmapBlock| {
+            for (bits64_pos, bits64) in bitmap_block.iter().enumerate() {
+                let mut bits64 = *bits64;
+                while bits64 != 0 {
+                    v.push(first_bit + bits64_pos * 64 + bits64.trailing_zeros() as usize);
+                    bits64 &= bits64 - 1;
+                }
+            }
+        });
+}
+
 impl Bitmap {
     /// A new bitmap from start block id and number of blocks
     pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        Self::with_bits(start_block_id, blocks, blocks * BLOCK_BITS)
+    }
+    /// A new bitmap of which only the first `bits` bits are allocatable
+    pub fn with_bits(start_block_id: usize, blocks: usize, bits: usize) -> Self {
+        assert!(bits <= blocks * BLOCK_BITS);
         Self {
             start_block_id,
             blocks,
+            bits,
+            hint: 0,
+            free_counts: Vec::new(),
         }
     }
-    /// Allocate a new block from a block device
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id as usize,
+    /// Count the free bits of every bitmap block once, so that full blocks
+    /// can be skipped without going through the block cache
+    fn load_free_counts(&mut self, block_device: &Arc<dyn BlockDevice>) {
+        if self.free_counts.len() == self.blocks {
+            return;
+        }
+        self.free_counts = (0..self.blocks)
+            .map(|block_pos| {
+                get_block_cache(
+                    block_pos + self.start_block_id,
+                    Arc::clone(block_device),
+                ).lock().read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block
+                        .iter()
+                        .map(|bits64| bits64.count_zeros() as u16)
+                        .sum()
+                })
+            })
+            .collect();
+    }
+    /// Find the first free bit in `[from, to)`
+    fn find_free(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        from: usize,
+        to: usize,
+    ) -> Option<usize> {
+        let mut bit = from;
+        while bit < to {
+            let block_pos = bit / BLOCK_BITS;
+            let block_start = block_pos * BLOCK_BITS;
+            let block_end = (block_start + BLOCK_BITS).min(to);
+            if self.free_counts[block_pos] > 0 {
+                let (inner_from, inner_to) = (bit - block_start, block_end - block_start);
+                let found = get_block_cache(
+                    block_pos + self.start_block_id,
+                    Arc::clone(block_device),
+                ).lock().read(0, |bitmap_block: &BitmapBlock| {
+                    let mut inner = inner_from;
+                    while inner < inner_to {
+                        // treat the bits before `inner` as used
+                        let bits64 = bitmap_block[inner / 64] | ((1u64 << (inner % 64)) - 1);
+                        if bits64 != u64::MAX {
+                            let pos = inner / 64 * 64 + bits64.trailing_ones() as usize;
+                            return if pos < inner_to { Some(pos) } else { None };
+                        }
+                        inner = (inner / 64 + 1) * 64;
+                    }
+                    None
+                });
+                if let Some(inner) = found {
+                    return Some(block_start + inner);
+                }
+            }
+            bit = block_end;
+        }
+        None
+    }
+    /// Length of the run of free bits starting at `start`, up to `limit`
+    fn free_run_len(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        start: usize,
+        limit: usize,
+    ) -> usize {
+        let maximum = self.maximum();
+        let mut len = 0usize;
+        while len < limit && start + len < maximum {
+            let bit = start + len;
+            let block_pos = bit / BLOCK_BITS;
+            let inner_from = bit % BLOCK_BITS;
+            if inner_from == 0 && self.free_counts[block_pos] as usize == BLOCK_BITS {
+                len += BLOCK_BITS;
+                continue;
+            }
+            let want = (limit - len).min(BLOCK_BITS - inner_from);
+            let run = get_block_cache(
+                block_pos + self.start_block_id,
+                Arc::clone(block_device),
+            ).lock().read(0, |bitmap_block: &BitmapBlock| {
+                (inner_from..inner_from + want)
+                    .take_while(|inner| bitmap_block[inner / 64] & (1u64 << (inner % 64)) == 0)
+                    .count()
+            });
+            len += run;
+            if run < want {
+                break;
+            }
+        }
+        // whole free blocks may have been counted past the allocatable bits
+        len.min(limit).min(maximum - start)
+    }
+    /// Mark `len` free bits starting at `start` as used
+    fn set_range(&mut self, block_device: &Arc<dyn BlockDevice>, start: usize, len: usize) {
+        let mut bit = start;
+        while bit < start + len {
+            let block_pos = bit / BLOCK_BITS;
+            let inner_from = bit % BLOCK_BITS;
+            let inner_to = (inner_from + start + len - bit).min(BLOCK_BITS);
+            get_block_cache(
+                block_pos + self.start_block_id,
                 Arc::clone(block_device),
             ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    .iter()
-                    .enumerate()
-                    .find(|(_, bits64)| **bits64 != u64::MAX)
-                    .map(|(bits64_pos, bits64)| {
-                        (bits64_pos, bits64.trailing_ones() as usize)
-                    }) {
-                    // modify cache
-                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                } else {
-                    None
+                for inner in inner_from..inner_to {
+                    assert!(bitmap_block[inner / 64] & (1u64 << (inner % 64)) == 0);
+                    bitmap_block[inner / 64] |= 1u64 << (inner % 64);
                 }
             });
-            if pos.is_some() {
-                return pos;
+            self.free_counts[block_pos] -= (inner_to - inner_from) as u16;
+            bit += inner_to - inner_from;
+        }
+    }
+    /// Find the first free bit at or after the hint, wrapping around once
+    fn find_free_from_hint(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+        self.find_free(block_device, self.hint, self.maximum())
+            .or_else(|| self.find_free(block_device, 0, self.hint))
+    }
+    /// Allocate a new block from a block device
+    pub fn alloc(&mut self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+        self.alloc_extent(block_device, 1).map(|(start, _)| start)
+    }
+    /// Allocate `n` contiguous blocks, returning the first one
+    pub fn alloc_contiguous(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        n: usize,
+    ) -> Option<usize> {
+        if n == 0 {
+            return None;
+        }
+        self.load_free_counts(block_device);
+        let (hint, maximum) = (self.hint, self.maximum());
+        let start = self
+            .find_run(block_device, n, hint, maximum)
+            .or_else(|| self.find_run(block_device, n, 0, hint))?;
+        self.set_range(block_device, start, n);
+        self.hint = (start + n) % maximum;
+        Some(start)
+    }
+    /// Find a run of `n` free bits whose first bit lies in `[from, to)`
+    fn find_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        n: usize,
+        from: usize,
+        to: usize,
+    ) -> Option<usize> {
+        let mut pos = from;
+        while pos < to {
+            let start = self.find_free(block_device, pos, to)?;
+            let len = self.free_run_len(block_device, start, n);
+            if len == n {
+                return Some(start);
             }
+            pos = start + len;
         }
         None
     }
+    /// Allocate the run of free blocks starting at the first free block
+    /// after the hint, at most `max` blocks long.
+    /// Returns the first block and the length of the run.
+    pub fn alloc_extent(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        max: usize,
+    ) -> Option<(usize, usize)> {
+        if max == 0 {
+            return None;
+        }
+        self.load_free_counts(block_device);
+        let start = self.find_free_from_hint(block_device)?;
+        let len = self.free_run_len(block_device, start, max);
+        self.set_range(block_device, start, len);
+        self.hint = (start + len) % self.maximum();
+        Some((start, len))
+    }
+    /// Search for free bits from the first one again
+    pub fn rewind(&mut self) {
+        self.hint = 0;
+    }
     /// Deallocate a block
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+    pub fn dealloc(&mut self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
         get_block_cache(
             block_pos + self.start_block_id,
@@ -69,9 +258,155 @@ impl Bitmap {
             assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
         });
+        if let Some(free_count) = self.free_counts.get_mut(block_pos) {
+            *free_count += 1;
+        }
+    }
+    /// Get all allocated bits
+    pub fn allocated(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<usize> {
+        let mut v: Vec<usize> = Vec::new();
+        for block_pos in 0..self.blocks {
+            block_allocated_bits(
+                block_pos + self.start_block_id,
+                block_pos * BLOCK_BITS,
+                block_device,
+                &mut v,
+            );
+        }
+        v
+    }
+    /// Get the number of allocated bits
+    pub fn used(&mut self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        self.load_free_counts(block_device);
+        self.blocks * BLOCK_BITS - self.free_counts.iter().map(|c| *c as usize).sum::<usize>()
+    }
+    /// Clear the whole bitmap, then mark `bits` as allocated
+    pub fn reset(&mut self, block_device: &Arc<dyn BlockDevice>, bits: &[usize]) {
+        for block_pos in 0..self.blocks {
+            get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .modify(0, |bitmap_block: &mut BitmapBlock| bitmap_block.fill(0));
+        }
+        for bit in bits {
+            let (block_pos, bits64_pos, inner_pos) = decomposition(*bit);
+            get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .modify(0, |bitmap_block: &mut BitmapBlock| {
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                });
+        }
+        self.free_counts = Vec::new();
+        self.hint = 0;
     }
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
-        self.blocks * BLOCK_BITS
+        self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RamBlockDevice;
+
+    /// A bitmap of `blocks` blocks at the start of a zeroed device
+    fn bitmap(blocks: usize) -> (Arc<dyn BlockDevice>, Bitmap) {
+        let block_device: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::new(blocks));
+        (block_device, Bitmap::new(0, blocks))
+    }
+
+    /// Whether a bit is set, read from the device through the block cache
+    fn is_allocated(bitmap: &Bitmap, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        bitmap.allocated(block_device).contains(&bit)
+    }
+
+    /// The free counts kept in memory must match a recount of the device
+    fn assert_free_counts(bitmap: &mut Bitmap, block_device: &Arc<dyn BlockDevice>) {
+        let used = bitmap.used(block_device);
+        assert_eq!(used, bitmap.allocated(block_device).len());
+        let cached = bitmap.free_counts.clone();
+        bitmap.free_counts = Vec::new();
+        bitmap.load_free_counts(block_device);
+        assert_eq!(cached, bitmap.free_counts);
+    }
+
+    #[test]
+    fn alloc_contiguous_test() {
+        let (block_device, mut bitmap) = bitmap(2);
+        // leave holes of 1, 2 and 3 free bits between used ones
+        for _ in 0..12 {
+            bitmap.alloc(&block_device).unwrap();
+        }
+        for bit in [1, 3, 4, 6, 7, 8] {
+            bitmap.dealloc(&block_device, bit);
+        }
+        bitmap.rewind();
+        let start = bitmap.alloc_contiguous(&block_device, 3).unwrap();
+        assert_eq!(start, 6);
+        for bit in start..start + 3 {
+            assert!(is_allocated(&bitmap, &block_device, bit));
+        }
+        // a run crossing the boundary between two bitmap blocks
+        let start = bitmap.alloc_contiguous(&block_device, BLOCK_BITS).unwrap();
+        assert_eq!(start, 12);
+        assert!((start..start + BLOCK_BITS).all(|bit| is_allocated(&bitmap, &block_device, bit)));
+        // only the holes of 1 and 2 bits and the tail are left
+        let tail = 2 * BLOCK_BITS - 12 - BLOCK_BITS;
+        assert!(bitmap.alloc_contiguous(&block_device, tail + 1).is_none());
+        assert_eq!(bitmap.alloc_contiguous(&block_device, tail), Some(12 + BLOCK_BITS));
+        assert!(bitmap.alloc_contiguous(&block_device, 3).is_none());
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 2), Some(3));
+        assert!(bitmap.alloc_contiguous(&block_device, 0).is_none());
+        assert_free_counts(&mut bitmap, &block_device);
+        // runs never reach past the allocatable bits
+        let block_device: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::new(2));
+        let mut bitmap = Bitmap::with_bits(0, 2, BLOCK_BITS + 4);
+        assert!(bitmap.alloc_contiguous(&block_device, BLOCK_BITS + 5).is_none());
+        assert_eq!(bitmap.alloc_contiguous(&block_device, BLOCK_BITS + 4), Some(0));
+        assert!(bitmap.alloc(&block_device).is_none());
+    }
+
+    #[test]
+    fn next_fit_wrap_test() {
+        let (block_device, mut bitmap) = bitmap(1);
+        for bit in 0..BLOCK_BITS {
+            assert_eq!(bitmap.alloc(&block_device), Some(bit));
+        }
+        assert!(bitmap.alloc(&block_device).is_none());
+        // the hint is back at 0 and the first free bit is found after it
+        bitmap.dealloc(&block_device, 100);
+        assert_eq!(bitmap.alloc(&block_device), Some(100));
+        // free bits before the hint are found by wrapping around
+        bitmap.dealloc(&block_device, 5);
+        bitmap.dealloc(&block_device, 200);
+        bitmap.dealloc(&block_device, 201);
+        assert_eq!(bitmap.alloc(&block_device), Some(200));
+        assert_eq!(bitmap.alloc_extent(&block_device, 8), Some((201, 1)));
+        assert_eq!(bitmap.alloc(&block_device), Some(5));
+        assert!(bitmap.alloc(&block_device).is_none());
+        // so are runs
+        bitmap.dealloc(&block_device, 10);
+        bitmap.dealloc(&block_device, 11);
+        bitmap.dealloc(&block_device, BLOCK_BITS - 1);
+        assert_eq!(bitmap.alloc(&block_device), Some(10));
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 1), Some(11));
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 1), Some(BLOCK_BITS - 1));
+    }
+
+    #[test]
+    fn free_counts_test() {
+        let (block_device, mut bitmap) = bitmap(2);
+        assert_eq!(bitmap.used(&block_device), 0);
+        let start = bitmap.alloc_contiguous(&block_device, BLOCK_BITS + 10).unwrap();
+        assert_eq!(bitmap.free_counts, [0, BLOCK_BITS as u16 - 10]);
+        for bit in (start..start + BLOCK_BITS + 10).step_by(3) {
+            bitmap.dealloc(&block_device, bit);
+        }
+        assert_free_counts(&mut bitmap, &block_device);
+        bitmap.reset(&block_device, &[0, 1, BLOCK_BITS + 5]);
+        assert_eq!(bitmap.used(&block_device), 3);
+        assert_eq!(bitmap.free_counts, [BLOCK_BITS as u16 - 2, BLOCK_BITS as u16 - 1]);
+        assert_eq!(bitmap.alloc(&block_device), Some(2));
+        assert_free_counts(&mut bitmap, &block_device);
     }
 }
@@ -3,6 +3,7 @@ use super::{
     BlockDevice,
 };
 use alloc::collections::VecDeque;
+use alloc::vec;
 use alloc::sync::Arc;
 use lazy_static::*;
 use spin::Mutex;
@@ -34,6 +35,21 @@ impl BlockCache {
             modified: false,
         }
     }
+    /// Create a BlockCache from data already read from disk.
+    pub fn from_data(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        data: &[u8],
+    ) -> Self {
+        let mut cache = [0u8; BLOCK_SZ];
+        cache.copy_from_slice(data);
+        Self {
+            cache,
+            block_id,
+            block_device,
+            modified: false,
+        }
+    }
     /// Get the address of an offset inside the cached block data
     fn addr_of_offset(&self, offset: usize) -> usize {
         &self.cache[offset] as *const _ as usize
@@ -76,11 +92,19 @@ impl Drop for BlockCache {
     }
 }
 
-/// Use a block cache of 16 blocks
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Use a block cache of 64 blocks
+const BLOCK_CACHE_SIZE: usize = 64;
+/// At most half of the cache may be filled by a single prefetch
+const PREFETCH_LIMIT: usize = BLOCK_CACHE_SIZE / 2;
+
+/// Identify a block device by the address of its shared instance
+pub(crate) fn device_key(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
 
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    /// (device key, block id, cache)
+    queue: VecDeque<(usize, usize, Arc<Mutex<BlockCache>>)>,
 }
 
 impl BlockCacheManager {
@@ -93,31 +117,77 @@ impl BlockCacheManager {
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
+        let device = device_key(&block_device);
         if let Some(pair) = self.queue
             .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
+            .find(|pair| pair.0 == device && pair.1 == block_id) {
+                Arc::clone(&pair.2)
         } else {
             // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self.queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+            if self.queue.len() == BLOCK_CACHE_SIZE && !self.evict_one() {
+                panic!("Run out of BlockCache!");
             }
             // load block into mem and push back
             let block_cache = Arc::new(Mutex::new(
                 BlockCache::new(block_id, Arc::clone(&block_device))
             ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
+            self.queue.push_back((device, block_id, Arc::clone(&block_cache)));
             block_cache
         }
     }
+
+    /// Drop the oldest block cache nobody else holds, return false if all are in use
+    fn evict_one(&mut self) -> bool {
+        // from front to tail
+        if let Some((idx, _)) = self.queue
+            .iter()
+            .enumerate()
+            .find(|(_, pair)| Arc::strong_count(&pair.2) == 1) {
+            self.queue.drain(idx..=idx);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn contains(&self, device: usize, block_id: usize) -> bool {
+        self.queue.iter().any(|pair| pair.0 == device && pair.1 == block_id)
+    }
+
+    /// Load blocks `[start_block_id, start_block_id + count)` which are not
+    /// cached yet, reading each uncached run with one device request.
+    /// Stops early instead of panicking when the cache is exhausted.
+    pub fn prefetch(
+        &mut self,
+        start_block_id: usize,
+        count: usize,
+        block_device: Arc<dyn BlockDevice>,
+    ) {
+        let device = device_key(&block_device);
+        let end_block_id = start_block_id + count.min(PREFETCH_LIMIT);
+        let mut block_id = start_block_id;
+        while block_id < end_block_id {
+            if self.contains(device, block_id) {
+                block_id += 1;
+                continue;
+            }
+            let run_end = (block_id..end_block_id)
+                .find(|id| self.contains(device, *id))
+                .unwrap_or(end_block_id);
+            let mut data = vec![0u8; (run_end - block_id) * BLOCK_SZ];
+            block_device.read_blocks(block_id, &mut data);
+            for (i, block) in data.chunks(BLOCK_SZ).enumerate() {
+                if self.queue.len() == BLOCK_CACHE_SIZE && !self.evict_one() {
+                    return;
+                }
+                let block_cache = Arc::new(Mutex::new(
+                    BlockCache::from_data(block_id + i, Arc::clone(&block_device), block)
+                ));
+                self.queue.push_back((device, block_id + i, block_cache));
+            }
+            block_id = run_end;
+        }
+    }
 }
 
 lazy_static! {
@@ -135,10 +205,19 @@ pub fn get_block_cache(
     BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
 }
 
+/// Prefetch consecutive blocks into the block cache
+pub fn block_cache_prefetch(
+    start_block_id: usize,
+    count: usize,
+    block_device: Arc<dyn BlockDevice>
+) {
+    BLOCK_CACHE_MANAGER.lock().prefetch(start_block_id, count, block_device)
+}
+
 /// Sync all block cache to block device
 pub fn block_cache_sync_all() {
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    for (_, _, cache) in manager.queue.iter() {
         cache.lock().sync();
     }
 }
@@ -1,8 +1,37 @@
 use core::any::Any;
+use super::BLOCK_SZ;
 
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
 pub trait BlockDevice : Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     fn write_block(&self, block_id: usize, buf: &[u8]);
+    /// Read a block, returning an error instead of panicking if the device fails.
+    /// Devices able to report errors should override it.
+    #[allow(clippy::result_unit_err)]
+    fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), ()> {
+        self.read_block(block_id, buf);
+        Ok(())
+    }
+    /// Write a block, returning an error instead of panicking if the device fails.
+    /// Devices able to report errors should override it.
+    #[allow(clippy::result_unit_err)]
+    fn try_write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), ()> {
+        self.write_block(block_id, buf);
+        Ok(())
+    }
+    /// Read consecutive blocks starting from `block_id`,
+    /// `buf.len()` must be a multiple of the block size.
+    /// Devices able to batch requests should override it.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id + i, block);
+        }
+    }
+    /// Whether `read_blocks` takes fewer requests than one per block.
+    /// Readahead is skipped on devices where it does not, as it could
+    /// only add reads there.
+    fn batched_reads(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,87 @@
+//! The ChaCha20 stream cipher of RFC 8439, used to encrypt file data and names.
+
+/// Size of a key in bytes
+pub const KEY_SZ: usize = 32;
+/// Size of a nonce in bytes
+pub const NONCE_SZ: usize = 12;
+/// Size of a keystream block in bytes
+const BLOCK_LEN: usize = 64;
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// A ChaCha20 keystream given by a key and a nonce
+pub struct ChaCha20 {
+    key: [u32; 8],
+    nonce: [u32; 3],
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn read_words(bytes: &[u8], words: &mut [u32]) {
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+}
+
+impl ChaCha20 {
+    /// A keystream from a key and a nonce
+    pub fn new(key: &[u8; KEY_SZ], nonce: &[u8; NONCE_SZ]) -> Self {
+        let mut cipher = Self {
+            key: [0; 8],
+            nonce: [0; 3],
+        };
+        read_words(key, &mut cipher.key);
+        read_words(nonce, &mut cipher.nonce);
+        cipher
+    }
+    /// Get the keystream block at `counter`
+    pub fn block(&self, counter: u32) -> [u8; BLOCK_LEN] {
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&CONSTANTS);
+        state[4..12].copy_from_slice(&self.key);
+        state[12] = counter;
+        state[13..].copy_from_slice(&self.nonce);
+        let mut working = state;
+        for _ in 0..10 {
+            // column rounds
+            quarter_round(&mut working, 0, 4, 8, 12);
+            quarter_round(&mut working, 1, 5, 9, 13);
+            quarter_round(&mut working, 2, 6, 10, 14);
+            quarter_round(&mut working, 3, 7, 11, 15);
+            // diagonal rounds
+            quarter_round(&mut working, 0, 5, 10, 15);
+            quarter_round(&mut working, 1, 6, 11, 12);
+            quarter_round(&mut working, 2, 7, 8, 13);
+            quarter_round(&mut working, 3, 4, 9, 14);
+        }
+        let mut block = [0u8; BLOCK_LEN];
+        for ((bytes, word), initial) in block.chunks_mut(4).zip(working.iter()).zip(state.iter()) {
+            bytes.copy_from_slice(&word.wrapping_add(*initial).to_le_bytes());
+        }
+        block
+    }
+    /// XOR `data` with the keystream starting at byte `offset` of it,
+    /// which both encrypts and decrypts
+    pub fn apply_keystream(&self, offset: usize, data: &mut [u8]) {
+        let mut pos = 0usize;
+        while pos < data.len() {
+            let stream_pos = offset + pos;
+            let block = self.block((stream_pos / BLOCK_LEN) as u32);
+            let start = stream_pos % BLOCK_LEN;
+            let len = (BLOCK_LEN - start).min(data.len() - pos);
+            for (byte, key) in data[pos..pos + len].iter_mut().zip(block[start..].iter()) {
+                *byte ^= key;
+            }
+            pos += len;
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use super::{BlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Several block devices one after the other, used as a single larger one
+pub struct ConcatBlockDevice {
+    /// Devices with their number of blocks
+    devices: Vec<(Arc<dyn BlockDevice>, usize)>,
+}
+
+impl ConcatBlockDevice {
+    /// Concatenate devices, each given with its number of blocks
+    pub fn new(devices: Vec<(Arc<dyn BlockDevice>, usize)>) -> Self {
+        Self { devices }
+    }
+    /// Get the total number of blocks of all devices
+    pub fn total_blocks(&self) -> usize {
+        self.devices.iter().map(|(_, blocks)| *blocks).sum()
+    }
+    /// Find the device holding a block, with the id of the block on it
+    /// and the number of blocks from there to the end of the device
+    fn locate(&self, block_id: usize) -> (&Arc<dyn BlockDevice>, usize, usize) {
+        let mut inner_id = block_id;
+        for (device, blocks) in self.devices.iter() {
+            if inner_id < *blocks {
+                return (device, inner_id, *blocks - inner_id);
+            }
+            inner_id -= *blocks;
+        }
+        panic!("Block {} is past the end of the concatenated devices!", block_id);
+    }
+}
+
+impl BlockDevice for ConcatBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let (device, inner_id, _) = self.locate(block_id);
+        device.read_block(inner_id, buf);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let (device, inner_id, _) = self.locate(block_id);
+        device.write_block(inner_id, buf);
+    }
+    fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), ()> {
+        let (device, inner_id, _) = self.locate(block_id);
+        device.try_read_block(inner_id, buf)
+    }
+    fn try_write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), ()> {
+        let (device, inner_id, _) = self.locate(block_id);
+        device.try_write_block(inner_id, buf)
+    }
+    /// Read the part on each device with a single request
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        let mut block_id = block_id;
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let (device, inner_id, left) = self.locate(block_id);
+            let len = (left * BLOCK_SZ).min(rest.len());
+            let (part, tail) = rest.split_at_mut(len);
+            device.read_blocks(inner_id, part);
+            block_id += len / BLOCK_SZ;
+            rest = tail;
+        }
+    }
+    fn batched_reads(&self) -> bool {
+        self.devices.iter().all(|(device, _)| device.batched_reads())
+    }
+}
@@ -1,11 +1,21 @@
 use super::{
-    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode, DiskInodeType, Inode,
-    SuperBlock,
+    block_allocated_bits, block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode,
+    DiskInodeType, Inode, QuotaEntry, SnapshotEntry, SnapshotTable, SuperBlock, ChaCha20, EFS_VERSION,
+    KEY_CHECK_SZ, KEY_SZ, MAX_SNAPSHOTS, SNAPSHOT_ENTRY_SZ,
 };
+use crate::chacha::NONCE_SZ;
+use crate::vfs::is_inode_open;
 use crate::BLOCK_SZ;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use spin::Mutex;
 
+/// Number of bits in a bitmap block
+const BLOCK_BITS: u32 = BLOCK_SZ as u32 * 8;
+
 /// An easy fs over a block device
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
@@ -13,8 +23,21 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    /// Block holding the snapshot table, 0 if there is none
+    snapshot_block: u32,
+    /// The snapshot mounted read-only, whose image replaces the inode table
+    mounted_snapshot: Option<SnapshotEntry>,
+    /// Master key that the keys of encrypted inodes are derived from
+    master_key: Option<[u8; KEY_SZ]>,
+    /// Opened for inspection only, nothing is written to the block device
+    inspecting: bool,
 }
 
+/// Purposes of the keystreams derived from the master key
+const KEY_PURPOSE_DATA: u8 = 0;
+const KEY_PURPOSE_NAME: u8 = 1;
+const KEY_PURPOSE_CHECK: u8 = 2;
+
 /// A data block of block size
 type DataBlock = [u8; BLOCK_SZ];
 
@@ -34,9 +57,10 @@ impl EasyFileSystem {
         let data_total_blocks = total_blocks - 1 - inode_total_blocks;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
-        let data_bitmap = Bitmap::new(
+        let data_bitmap = Bitmap::with_bits(
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
             data_bitmap_blocks as usize,
+            data_area_blocks as usize,
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
@@ -44,6 +68,10 @@ impl EasyFileSystem {
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            snapshot_block: 0,
+            mounted_snapshot: None,
+            master_key: None,
+            inspecting: false,
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -77,31 +105,100 @@ impl EasyFileSystem {
             .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
                 disk_inode.initialize(DiskInodeType::Directory);
             });
+        efs.force_charge(0, 0, 1);
         block_cache_sync_all();
         Arc::new(Mutex::new(efs))
     }
     /// Open a block device as a filesystem
     pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+        let mut efs = Self::load(block_device);
+        efs.reclaim_orphans();
+        Arc::new(Mutex::new(efs))
+    }
+    /// Open a block device as a read-only filesystem for inspection,
+    /// leaving the orphans of its last use in place
+    pub fn open_read_only(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+        let mut efs = Self::load(block_device);
+        efs.inspecting = true;
+        Arc::new(Mutex::new(efs))
+    }
+    /// Read the super block of a block device and set up the bitmaps
+    fn load(block_device: Arc<dyn BlockDevice>) -> Self {
         // read SuperBlock
         get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
+                assert!(
+                    !super_block.is_unversioned(),
+                    "Error loading EFS: image predates the versioned layout, recreate it"
+                );
                 assert!(super_block.is_valid(), "Error loading EFS!");
+                assert!(
+                    super_block.is_supported(),
+                    "Error loading EFS: layout version {}, expected {}",
+                    super_block.version,
+                    EFS_VERSION
+                );
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
+                Self {
                     block_device,
                     inode_bitmap: Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
-                    data_bitmap: Bitmap::new(
+                    data_bitmap: Bitmap::with_bits(
                         (1 + inode_total_blocks) as usize,
                         super_block.data_bitmap_blocks as usize,
+                        super_block.data_area_blocks as usize,
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
+                    snapshot_block: super_block.snapshot_block,
+                    mounted_snapshot: None,
+                    master_key: None,
+                    inspecting: false,
+                }
             })
     }
+    /// Free the inodes left without any link nor handle, which were unlinked
+    /// while open when the filesystem was last used
+    fn reclaim_orphans(&mut self) {
+        let mut reclaimed = false;
+        for inode_id in self.inode_bitmap.allocated(&self.block_device) {
+            let inode_id = inode_id as u32;
+            if is_inode_open(&self.block_device, inode_id) {
+                continue;
+            }
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            let block_cache = get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+            // only touch orphans, an image without any may be opened read-only
+            if block_cache.lock().read(block_offset, |disk_inode: &DiskInode| disk_inode.link_cnt != 0) {
+                continue;
+            }
+            let (owner, data_blocks_dealloc) = block_cache
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    (disk_inode.owner, disk_inode.clear_size(&self.block_device))
+                });
+            self.release(owner, data_blocks_dealloc.len() as u32, 1);
+            for data_block in data_blocks_dealloc {
+                self.dealloc_data(data_block);
+            }
+            self.dealloc_inode(inode_id);
+            reclaimed = true;
+        }
+        if reclaimed {
+            block_cache_sync_all();
+        }
+    }
+    /// Open a block device as a filesystem with the master key of its encrypted files
+    #[allow(clippy::result_unit_err)]
+    pub fn open_with_key(
+        block_device: Arc<dyn BlockDevice>,
+        key: [u8; KEY_SZ],
+    ) -> Result<Arc<Mutex<Self>>, ()> {
+        let efs = Self::open(block_device);
+        efs.lock().set_master_key(key)?;
+        Ok(efs)
+    }
     /// Get the root inode of the filesystem
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
         let block_device = Arc::clone(&efs.lock().block_device);
@@ -111,16 +208,43 @@ impl EasyFileSystem {
         Inode::new(0, block_id, block_offset, Arc::clone(efs), block_device)
     }
 
+    /// Open a snapshot of a block device as a read-only filesystem
+    pub fn open_snapshot(block_device: Arc<dyn BlockDevice>, name: &str) -> Option<Arc<Mutex<Self>>> {
+        let efs = Self::open(block_device);
+        let entry = efs.lock().find_snapshot(name)?.1;
+        efs.lock().mounted_snapshot = Some(entry);
+        Some(efs)
+    }
+    /// Whether the filesystem is a snapshot mounted read-only or opened for inspection
+    pub fn is_read_only(&self) -> bool {
+        self.inspecting || self.mounted_snapshot.is_some()
+    }
+
     /// Get inode by id
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
+        match &self.mounted_snapshot {
+            Some(entry) => self.image_inode_pos(&entry.image, inode_id),
+            None => {
+                let (inner_block, block_offset) = Self::inode_location(inode_id);
+                (self.inode_area_start_block + inner_block, block_offset)
+            }
+        }
+    }
+    /// Get the block of the inode area and the offset in it of an inode
+    fn inode_location(inode_id: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
         let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
-        let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (
-            block_id,
+            inode_id / inodes_per_block,
             (inode_id % inodes_per_block) as usize * inode_size,
         )
     }
+    /// Get the position of an inode stored in a snapshot image
+    fn image_inode_pos(&self, image: &DiskInode, inode_id: u32) -> (u32, usize) {
+        let (inner_block, block_offset) = Self::inode_location(inode_id);
+        let inner_id = self.inode_area_start_block - 1 + inner_block;
+        (image.get_block_id(inner_id, &self.block_device), block_offset)
+    }
     /// Get data block by id
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
@@ -129,12 +253,50 @@ impl EasyFileSystem {
     pub fn alloc_inode(&mut self) -> u32 {
         self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
     }
+    /// Deallocate an inode
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        self.inode_bitmap.dealloc(&self.block_device, inode_id as usize)
+    }
     /// Allocate a data block
     pub fn alloc_data(&mut self) -> u32 {
         self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
     }
-    /// Deallocate a data block
+    /// Allocate `n` data blocks, laid out as one contiguous run if possible,
+    /// otherwise as a few runs found by next-fit
+    pub fn alloc_data_blocks(&mut self, n: usize) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::with_capacity(n);
+        if let Some(start) = self.data_bitmap.alloc_contiguous(&self.block_device, n) {
+            v.extend((start..start + n).map(|bit| bit as u32 + self.data_area_start_block));
+            return v;
+        }
+        while v.len() < n {
+            let (start, len) = self
+                .data_bitmap
+                .alloc_extent(&self.block_device, n - v.len())
+                .unwrap();
+            v.extend((start..start + len).map(|bit| bit as u32 + self.data_area_start_block));
+        }
+        v
+    }
+    /// Get the number of data blocks in use
+    pub fn used_data_blocks(&mut self) -> usize {
+        self.data_bitmap.used(&self.block_device)
+    }
+    /// Get the number of inodes in use
+    pub fn used_inodes(&mut self) -> usize {
+        self.inode_bitmap.used(&self.block_device)
+    }
+    /// Get a copy of the super block, e.g. to compare the geometry of images
+    pub fn super_block(&self) -> SuperBlock {
+        self.read_super_block(|super_block| *super_block)
+    }
+    /// Deallocate a data block, or drop one reference to it if it is shared
     pub fn dealloc_data(&mut self, block_id: u32) {
+        let refcount = self.refcount(block_id);
+        if refcount > 0 {
+            self.set_refcount(block_id, refcount - 1);
+            return;
+        }
         get_block_cache(block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(0, |data_block: &mut DataBlock| {
@@ -147,4 +309,812 @@ impl EasyFileSystem {
             (block_id - self.data_area_start_block) as usize,
         )
     }
+    /// Call a function over the super block to read it
+    fn read_super_block<V>(&self, f: impl FnOnce(&SuperBlock) -> V) -> V {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, f)
+    }
+    /// Call a function over the snapshot table to read it
+    fn read_snapshot_table<V>(&self, f: impl FnOnce(&SnapshotTable) -> V) -> V {
+        get_block_cache(self.snapshot_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, f)
+    }
+    /// Call a function over the snapshot table to modify it
+    fn modify_snapshot_table<V>(&self, f: impl FnOnce(&mut SnapshotTable) -> V) -> V {
+        get_block_cache(self.snapshot_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, f)
+    }
+    /// Get the number of references to a data block besides its first owner
+    fn refcount(&self, block_id: u32) -> u8 {
+        if self.snapshot_block == 0 {
+            return 0;
+        }
+        let mut refcount = [0u8];
+        let pos = (block_id - self.data_area_start_block) as usize;
+        self.read_snapshot_table(|table| {
+            table.refcounts.read_at(pos, &mut refcount, &self.block_device)
+        });
+        refcount[0]
+    }
+    fn set_refcount(&self, block_id: u32, refcount: u8) {
+        let pos = (block_id - self.data_area_start_block) as usize;
+        self.modify_snapshot_table(|table| {
+            table.refcounts.write_at(pos, &[refcount], &self.block_device)
+        });
+    }
+    /// Give a shared data block a private copy, dropping one reference to it.
+    /// Return `None` if the block is not shared.
+    fn cow_block(&mut self, block_id: u32) -> Option<u32> {
+        let refcount = self.refcount(block_id);
+        if refcount == 0 {
+            return None;
+        }
+        self.set_refcount(block_id, refcount - 1);
+        let new_block_id = self.alloc_data();
+        let data = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |data_block: &DataBlock| *data_block);
+        get_block_cache(new_block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| *data_block = data);
+        Some(new_block_id)
+    }
+    /// Copy the shared blocks that writing `[offset, offset + len)` of an inode
+    /// would modify, must be called before changing its size or data
+    pub(crate) fn unshare(&mut self, disk_inode: &mut DiskInode, offset: usize, len: usize) {
+        if self.snapshot_block == 0 {
+            return;
+        }
+        let block_device = Arc::clone(&self.block_device);
+        disk_inode.unshare(offset, len, &mut |block_id| self.cow_block(block_id), &block_device);
+    }
+    /// Get all blocks used by the live inodes
+    fn live_blocks(&self) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        for inode_id in self.inode_bitmap.allocated(&self.block_device) {
+            let (inner_block, block_offset) = Self::inode_location(inode_id as u32);
+            get_block_cache(
+                (self.inode_area_start_block + inner_block) as usize,
+                Arc::clone(&self.block_device),
+            )
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| {
+                v.extend(disk_inode.block_ids(&self.block_device))
+            });
+        }
+        v
+    }
+    /// Get all blocks used by the inodes of a snapshot
+    fn snapshot_blocks(&self, entry: &SnapshotEntry) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        for inode_id in self.snapshot_inode_ids(entry) {
+            let (block_id, block_offset) = self.image_inode_pos(&entry.image, inode_id as u32);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    v.extend(disk_inode.block_ids(&self.block_device))
+                });
+        }
+        v
+    }
+    /// Get the ids of the inodes allocated in a snapshot
+    fn snapshot_inode_ids(&self, entry: &SnapshotEntry) -> Vec<usize> {
+        let mut inode_ids: Vec<usize> = Vec::new();
+        for block_pos in 0..self.inode_area_start_block - 1 {
+            block_allocated_bits(
+                entry.image.get_block_id(block_pos, &self.block_device) as usize,
+                block_pos as usize * BLOCK_SZ * 8,
+                &self.block_device,
+                &mut inode_ids,
+            );
+        }
+        inode_ids
+    }
+    /// Add one reference to each of the blocks
+    fn share_blocks(&self, blocks: Vec<u32>) {
+        for block_id in blocks {
+            let refcount = self.refcount(block_id);
+            self.set_refcount(block_id, refcount + 1);
+        }
+    }
+    /// Read all snapshot entries
+    fn snapshot_entries(&self) -> Vec<SnapshotEntry> {
+        if self.snapshot_block == 0 {
+            return Vec::new();
+        }
+        self.read_snapshot_table(|table| {
+            let count = table.snapshots.size as usize / SNAPSHOT_ENTRY_SZ;
+            (0..count)
+                .map(|i| {
+                    let mut entry = SnapshotEntry::empty();
+                    table.snapshots.read_at(
+                        i * SNAPSHOT_ENTRY_SZ,
+                        entry.as_bytes_mut(),
+                        &self.block_device,
+                    );
+                    entry
+                })
+                .collect()
+        })
+    }
+    /// Replace all snapshot entries
+    fn write_snapshot_entries(&mut self, entries: &[SnapshotEntry]) {
+        let blocks = self.modify_snapshot_table(|table| table.snapshots.clear_size(&self.block_device));
+        for block_id in blocks {
+            self.dealloc_data(block_id);
+        }
+        let new_size = (entries.len() * SNAPSHOT_ENTRY_SZ) as u32;
+        let blocks_needed = self.read_snapshot_table(|table| table.snapshots.blocks_num_needed(new_size));
+        let new_blocks = self.alloc_data_blocks(blocks_needed as usize);
+        self.modify_snapshot_table(|table| {
+            table.snapshots.increase_size(new_size, new_blocks, &self.block_device);
+            for (i, entry) in entries.iter().enumerate() {
+                table.snapshots.write_at(i * SNAPSHOT_ENTRY_SZ, entry.as_bytes(), &self.block_device);
+            }
+        });
+    }
+    /// Find a snapshot by name, return its index and entry
+    fn find_snapshot(&self, name: &str) -> Option<(usize, SnapshotEntry)> {
+        self.snapshot_entries()
+            .into_iter()
+            .enumerate()
+            .find(|(_, entry)| entry.name() == name)
+    }
+    /// Allocate the snapshot table with a reference count for every data block
+    fn create_snapshot_table(&mut self) {
+        let data_area_blocks = self.read_super_block(|super_block| super_block.data_area_blocks);
+        self.snapshot_block = self.alloc_data();
+        let blocks_needed = self.modify_snapshot_table(|table| {
+            table.initialize();
+            table.refcounts.blocks_num_needed(data_area_blocks)
+        });
+        let new_blocks = self.alloc_data_blocks(blocks_needed as usize);
+        self.modify_snapshot_table(|table| {
+            table.refcounts.increase_size(data_area_blocks, new_blocks, &self.block_device)
+        });
+        let snapshot_block = self.snapshot_block;
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| super_block.snapshot_block = snapshot_block);
+    }
+    /// Freeze the current tree as a read-only snapshot, later writes to the
+    /// live filesystem copy the blocks they modify
+    #[allow(clippy::result_unit_err)]
+    pub fn snapshot(&mut self, name: &str) -> Result<(), ()> {
+        if self.is_read_only() || self.find_snapshot(name).is_some() {
+            return Err(());
+        }
+        let mut entry = SnapshotEntry::new(name).ok_or(())?;
+        let mut entries = self.snapshot_entries();
+        if entries.len() >= MAX_SNAPSHOTS {
+            return Err(());
+        }
+        if self.snapshot_block == 0 {
+            self.create_snapshot_table();
+        }
+        // copy the inode bitmap and the inode area into the image
+        let image_blocks = self.read_super_block(|super_block| {
+            super_block.inode_bitmap_blocks + super_block.inode_area_blocks
+        });
+        let image_size = image_blocks * BLOCK_SZ as u32;
+        let new_blocks = self.alloc_data_blocks(entry.image.blocks_num_needed(image_size) as usize);
+        entry.image.increase_size(image_size, new_blocks, &self.block_device);
+        for i in 0..image_blocks as usize {
+            let data = get_block_cache(1 + i, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |data_block: &DataBlock| *data_block);
+            entry.image.write_at(i * BLOCK_SZ, &data, &self.block_device);
+        }
+        self.share_blocks(self.live_blocks());
+        entries.push(entry);
+        self.write_snapshot_entries(&entries);
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// List the names of all snapshots
+    pub fn snapshots(&self) -> Vec<String> {
+        self.snapshot_entries()
+            .iter()
+            .map(|entry| String::from(entry.name()))
+            .collect()
+    }
+    /// Delete a snapshot, freeing the blocks only it uses
+    #[allow(clippy::result_unit_err)]
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), ()> {
+        if self.is_read_only() {
+            return Err(());
+        }
+        let (index, mut entry) = self.find_snapshot(name).ok_or(())?;
+        for block_id in self.snapshot_blocks(&entry) {
+            self.dealloc_data(block_id);
+        }
+        for block_id in entry.image.clear_size(&self.block_device) {
+            self.dealloc_data(block_id);
+        }
+        let mut entries = self.snapshot_entries();
+        entries.remove(index);
+        self.write_snapshot_entries(&entries);
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Roll the live filesystem back to a snapshot, which is kept.
+    /// Inodes obtained before are invalid afterwards.
+    #[allow(clippy::result_unit_err)]
+    pub fn rollback(&mut self, name: &str) -> Result<(), ()> {
+        if self.is_read_only() {
+            return Err(());
+        }
+        let (_, entry) = self.find_snapshot(name).ok_or(())?;
+        for block_id in self.live_blocks() {
+            self.dealloc_data(block_id);
+        }
+        let image_blocks = entry.image.size as usize / BLOCK_SZ;
+        let mut data = vec![0u8; BLOCK_SZ];
+        for i in 0..image_blocks {
+            entry.image.read_at(i * BLOCK_SZ, &mut data, &self.block_device);
+            get_block_cache(1 + i, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.copy_from_slice(&data));
+        }
+        self.inode_bitmap = Bitmap::new(1, self.inode_area_start_block as usize - 1);
+        self.share_blocks(self.live_blocks());
+        self.recount_quotas();
+        // the keystreams written after the snapshot must not be used again
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.rekey_below = super_block.next_nonce
+            });
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Get the total number of blocks
+    pub fn total_blocks(&self) -> u32 {
+        self.read_super_block(|super_block| super_block.total_blocks)
+    }
+    /// Get the ids of all allocated data blocks
+    fn allocated_data_blocks(&self) -> Vec<u32> {
+        self.data_bitmap
+            .allocated(&self.block_device)
+            .into_iter()
+            .map(|bit| bit as u32 + self.data_area_start_block)
+            .collect()
+    }
+    /// Move data blocks to new places given by `map`: copy their content,
+    /// then replace every reference to them in the live inodes,
+    /// the snapshot images and the snapshot table
+    fn move_blocks(&mut self, map: &BTreeMap<u32, u32>) {
+        for (old_block_id, new_block_id) in map.iter() {
+            let data = get_block_cache(*old_block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |data_block: &DataBlock| *data_block);
+            get_block_cache(*new_block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| *data_block = data);
+        }
+        // the copies reach the disk before anything points to them
+        block_cache_sync_all();
+        for inode_id in self.inode_bitmap.allocated(&self.block_device) {
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id as u32);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.remap_blocks(map, &self.block_device)
+                });
+        }
+        if self.snapshot_block == 0 {
+            return;
+        }
+        if let Some(snapshot_block) = map.get(&self.snapshot_block).copied() {
+            self.snapshot_block = snapshot_block;
+            get_block_cache(0, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |super_block: &mut SuperBlock| super_block.snapshot_block = snapshot_block);
+        }
+        self.modify_snapshot_table(|table| {
+            table.snapshots.remap_blocks(map, &self.block_device);
+            table.refcounts.remap_blocks(map, &self.block_device);
+        });
+        let mut entries = self.snapshot_entries();
+        for entry in entries.iter_mut() {
+            entry.image.remap_blocks(map, &self.block_device);
+            for inode_id in self.snapshot_inode_ids(entry) {
+                let (block_id, block_offset) = self.image_inode_pos(&entry.image, inode_id as u32);
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(block_offset, |disk_inode: &mut DiskInode| {
+                        disk_inode.remap_blocks(map, &self.block_device)
+                    });
+            }
+        }
+        self.modify_snapshot_table(|table| {
+            for (i, entry) in entries.iter().enumerate() {
+                table.snapshots.write_at(i * SNAPSHOT_ENTRY_SZ, entry.as_bytes(), &self.block_device);
+            }
+        });
+    }
+    /// Read the reference counts of all data blocks
+    fn read_refcounts(&self) -> Vec<u8> {
+        if self.snapshot_block == 0 {
+            return Vec::new();
+        }
+        self.read_snapshot_table(|table| {
+            let mut refcounts = vec![0u8; table.refcounts.size as usize];
+            table.refcounts.read_at(0, &mut refcounts, &self.block_device);
+            refcounts
+        })
+    }
+    /// Store reference counts read before the data area changed: from
+    /// `old_start` to the current data area start, with blocks moved by `map`
+    fn write_refcounts(&mut self, old_refcounts: Vec<u8>, old_start: u32, map: &BTreeMap<u32, u32>) {
+        if self.snapshot_block == 0 {
+            return;
+        }
+        let data_area_blocks = self.read_super_block(|super_block| super_block.data_area_blocks);
+        let mut refcounts = vec![0u8; data_area_blocks as usize];
+        for (pos, refcount) in old_refcounts.into_iter().enumerate() {
+            if refcount > 0 {
+                let block_id = old_start + pos as u32;
+                let block_id = map.get(&block_id).copied().unwrap_or(block_id);
+                refcounts[(block_id - self.data_area_start_block) as usize] = refcount;
+            }
+        }
+        let blocks = self.modify_snapshot_table(|table| table.refcounts.clear_size(&self.block_device));
+        for block_id in blocks {
+            self.dealloc_data(block_id);
+        }
+        let blocks_needed =
+            self.read_snapshot_table(|table| table.refcounts.blocks_num_needed(data_area_blocks));
+        let new_blocks = self.alloc_data_blocks(blocks_needed as usize);
+        self.modify_snapshot_table(|table| {
+            table.refcounts.increase_size(data_area_blocks, new_blocks, &self.block_device);
+            table.refcounts.write_at(0, &refcounts, &self.block_device);
+        });
+    }
+    /// Rebuild the data bitmap with the data blocks in `allocated`, moved by `map`
+    fn rebuild_data_bitmap(&mut self, allocated: &[u32], map: &BTreeMap<u32, u32>) {
+        let bits: Vec<usize> = allocated
+            .iter()
+            .map(|block_id| {
+                let block_id = map.get(block_id).copied().unwrap_or(*block_id);
+                (block_id - self.data_area_start_block) as usize
+            })
+            .collect();
+        self.data_bitmap.reset(&self.block_device, &bits);
+    }
+    /// Grow the filesystem to `new_total_blocks`, the block device must have been
+    /// grown already. While the data bitmap has spare bits for the new blocks, only
+    /// the super block and the bitmap size kept in memory change. Otherwise the data
+    /// bitmap takes blocks from the front of the data area, the data there moves to
+    /// the new tail. Inodes in use stay valid, so does `Inode` of open files: this
+    /// can be called on a mounted filesystem, as os6 does when its disk grows.
+    #[allow(clippy::result_unit_err)]
+    pub fn grow(&mut self, new_total_blocks: u32) -> Result<(), ()> {
+        let (total_blocks, data_bitmap_blocks) = self.read_super_block(|super_block| {
+            (super_block.total_blocks, super_block.data_bitmap_blocks)
+        });
+        if self.is_read_only() || new_total_blocks <= total_blocks {
+            return Err(());
+        }
+        let data_bitmap_start = self.data_area_start_block - data_bitmap_blocks;
+        let mut new_bitmap_blocks = data_bitmap_blocks;
+        while new_bitmap_blocks * BLOCK_BITS < new_total_blocks - data_bitmap_start - new_bitmap_blocks {
+            new_bitmap_blocks += 1;
+        }
+        if new_bitmap_blocks == data_bitmap_blocks {
+            self.grow_in_place(new_total_blocks);
+            return Ok(());
+        }
+        let new_data_start = data_bitmap_start + new_bitmap_blocks;
+        // the data moves past the old tail, or past the new bitmap if that ends further
+        let move_start = total_blocks.max(new_data_start);
+        let allocated = self.allocated_data_blocks();
+        let mut map: BTreeMap<u32, u32> = BTreeMap::new();
+        for block_id in allocated.iter().filter(|block_id| **block_id < new_data_start) {
+            map.insert(*block_id, move_start + map.len() as u32);
+        }
+        if move_start + map.len() as u32 > new_total_blocks {
+            return Err(());
+        }
+        let refcounts = self.read_refcounts();
+        self.move_blocks(&map);
+        let old_start = self.data_area_start_block;
+        let data_area_blocks = new_total_blocks - new_data_start;
+        self.data_area_start_block = new_data_start;
+        self.data_bitmap = Bitmap::with_bits(
+            data_bitmap_start as usize,
+            new_bitmap_blocks as usize,
+            data_area_blocks as usize,
+        );
+        self.rebuild_data_bitmap(&allocated, &map);
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.total_blocks = new_total_blocks;
+                super_block.data_bitmap_blocks = new_bitmap_blocks;
+                super_block.data_area_blocks = data_area_blocks;
+            });
+        self.write_refcounts(refcounts, old_start, &map);
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Grow the data area to end at `new_total_blocks` without moving anything,
+    /// the data bitmap must have bits for it. Those bits are clear, as the bitmap
+    /// is cleared on creation and rebuilt when shrinking.
+    fn grow_in_place(&mut self, new_total_blocks: u32) {
+        let data_area_blocks = new_total_blocks - self.data_area_start_block;
+        let refcounts = self.read_refcounts();
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.total_blocks = new_total_blocks;
+                super_block.data_area_blocks = data_area_blocks;
+            });
+        let data_bitmap_blocks = self.read_super_block(|super_block| super_block.data_bitmap_blocks);
+        self.data_bitmap = Bitmap::with_bits(
+            (self.data_area_start_block - data_bitmap_blocks) as usize,
+            data_bitmap_blocks as usize,
+            data_area_blocks as usize,
+        );
+        self.write_refcounts(refcounts, self.data_area_start_block, &BTreeMap::new());
+        block_cache_sync_all();
+    }
+    /// Shrink the filesystem to `new_total_blocks`, moving the data out of the tail
+    /// first. The block device can be truncated afterwards.
+    #[allow(clippy::result_unit_err)]
+    pub fn shrink(&mut self, new_total_blocks: u32) -> Result<(), ()> {
+        let (total_blocks, data_bitmap_blocks) = self.read_super_block(|super_block| {
+            (super_block.total_blocks, super_block.data_bitmap_blocks)
+        });
+        if self.is_read_only()
+            || new_total_blocks >= total_blocks
+            || new_total_blocks <= self.data_area_start_block
+        {
+            return Err(());
+        }
+        let data_area_blocks = new_total_blocks - self.data_area_start_block;
+        let allocated = self.allocated_data_blocks();
+        let tail: Vec<u32> = allocated
+            .iter()
+            .copied()
+            .filter(|block_id| *block_id >= new_total_blocks)
+            .collect();
+        if allocated.len() > data_area_blocks as usize {
+            return Err(());
+        }
+        let refcounts = self.read_refcounts();
+        // only the blocks before the new end can be allocated from now on
+        self.data_bitmap = Bitmap::with_bits(
+            (self.data_area_start_block - data_bitmap_blocks) as usize,
+            data_bitmap_blocks as usize,
+            data_area_blocks as usize,
+        );
+        let mut map: BTreeMap<u32, u32> = BTreeMap::new();
+        for block_id in tail {
+            map.insert(block_id, self.alloc_data());
+        }
+        self.move_blocks(&map);
+        self.rebuild_data_bitmap(&allocated, &map);
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.total_blocks = new_total_blocks;
+                super_block.data_area_blocks = data_area_blocks;
+            });
+        self.write_refcounts(refcounts, self.data_area_start_block, &map);
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Get the ids of the inodes in use, those of the mounted snapshot if there is one
+    fn inode_ids(&self) -> Vec<u32> {
+        let inode_ids = match &self.mounted_snapshot {
+            Some(entry) => self.snapshot_inode_ids(entry),
+            None => self.inode_bitmap.allocated(&self.block_device),
+        };
+        inode_ids.into_iter().map(|inode_id| inode_id as u32).collect()
+    }
+    /// Get the blocks of an inode in file order, index blocks included
+    fn inode_block_ids(&self, inode_id: u32) -> Vec<u32> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| {
+                disk_inode.block_ids(&self.block_device)
+            })
+    }
+    /// Get how scattered the blocks of an inode are
+    pub fn fragmentation(&self, inode_id: u32) -> Fragmentation {
+        Fragmentation::of(&self.inode_block_ids(inode_id))
+    }
+    /// Get how scattered the blocks of all inodes are
+    pub fn volume_fragmentation(&self) -> Fragmentation {
+        self.inode_ids()
+            .into_iter()
+            .map(|inode_id| self.fragmentation(inode_id))
+            .fold(Fragmentation::default(), |sum, fragmentation| sum + fragmentation)
+    }
+    /// Move the data and index blocks of an inode into as few runs of consecutive
+    /// blocks as free space allows. Return whether it was moved, which it is only
+    /// if that gives fewer runs, or with `compact` the same runs further ahead.
+    /// The copies are synced before the inode points to them and the old blocks
+    /// are freed last, so a crash in between leaks blocks but loses no data.
+    fn relocate_inode(&mut self, inode_id: u32, compact: bool) -> Result<bool, ()> {
+        if self.is_read_only() {
+            return Err(());
+        }
+        let old_blocks = self.inode_block_ids(inode_id);
+        let before = Fragmentation::of(&old_blocks);
+        let data_area_blocks = self.read_super_block(|super_block| super_block.data_area_blocks);
+        let free_blocks = data_area_blocks as usize - self.used_data_blocks();
+        if old_blocks.is_empty()
+            || (before.extents == 1 && !compact)
+            || old_blocks.len() > free_blocks
+        {
+            return Ok(false);
+        }
+        let new_blocks = self.alloc_data_blocks(old_blocks.len());
+        let after = Fragmentation::of(&new_blocks);
+        if after.extents > before.extents
+            || (after.extents == before.extents && (!compact || new_blocks[0] > old_blocks[0]))
+        {
+            for block_id in new_blocks {
+                self.dealloc_data(block_id);
+            }
+            return Ok(false);
+        }
+        let map: BTreeMap<u32, u32> = old_blocks.into_iter().zip(new_blocks).collect();
+        self.move_blocks(&map);
+        // blocks shared with snapshots keep their reference counts at the new place
+        for (old_block_id, new_block_id) in map.iter() {
+            let refcount = self.refcount(*old_block_id);
+            if refcount > 0 {
+                self.set_refcount(*new_block_id, refcount);
+                self.set_refcount(*old_block_id, 0);
+            }
+        }
+        block_cache_sync_all();
+        for old_block_id in map.keys() {
+            self.dealloc_data(*old_block_id);
+        }
+        block_cache_sync_all();
+        Ok(true)
+    }
+    /// Move the blocks of an inode into as few runs of consecutive blocks
+    /// as free space allows, return whether they were moved
+    #[allow(clippy::result_unit_err)]
+    pub fn defragment_inode(&mut self, inode_id: u32) -> Result<bool, ()> {
+        self.relocate_inode(inode_id, false)
+    }
+    /// Defragment all inodes, each taking the first free run large enough for it,
+    /// so that files are compacted towards the front of the data area as well.
+    /// Return the number of inodes moved.
+    #[allow(clippy::result_unit_err)]
+    pub fn defragment(&mut self) -> Result<usize, ()> {
+        if self.is_read_only() {
+            return Err(());
+        }
+        let mut moved = 0;
+        for inode_id in self.inode_ids() {
+            self.data_bitmap.rewind();
+            if self.relocate_inode(inode_id, true)? {
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+    /// Set the master key, which must match the one used to encrypt the
+    /// existing files if there are any
+    #[allow(clippy::result_unit_err)]
+    pub fn set_master_key(&mut self, key: [u8; KEY_SZ]) -> Result<(), ()> {
+        let key_check = self.read_super_block(|super_block| super_block.key_check);
+        if key_check != [0; KEY_CHECK_SZ] && key_check != Self::key_check(&key) {
+            return Err(());
+        }
+        self.master_key = Some(key);
+        Ok(())
+    }
+    /// Whether a master key is set
+    pub fn has_master_key(&self) -> bool {
+        self.master_key.is_some()
+    }
+    /// Record the check value of the master key when something gets encrypted
+    pub(crate) fn store_key_check(&self) {
+        if let Some(key) = &self.master_key {
+            let key_check = Self::key_check(key);
+            get_block_cache(0, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |super_block: &mut SuperBlock| super_block.key_check = key_check);
+        }
+    }
+    fn key_check(key: &[u8; KEY_SZ]) -> [u8; KEY_CHECK_SZ] {
+        let mut key_check = [0u8; KEY_CHECK_SZ];
+        key_check.copy_from_slice(&Self::keystream(key, KEY_PURPOSE_CHECK, 0, 0).block(0)[..KEY_CHECK_SZ]);
+        key_check
+    }
+    /// A keystream derived from the master key for a purpose and two ids
+    fn keystream(key: &[u8; KEY_SZ], purpose: u8, id: u32, sub_id: u32) -> ChaCha20 {
+        let mut nonce = [0u8; NONCE_SZ];
+        nonce[0] = purpose;
+        nonce[4..8].copy_from_slice(&id.to_le_bytes());
+        nonce[8..].copy_from_slice(&sub_id.to_le_bytes());
+        ChaCha20::new(key, &nonce)
+    }
+    /// The keystream encrypting the data of a file with nonce `nonce`,
+    /// `None` without master key
+    pub(crate) fn data_cipher(&self, nonce: u32) -> Option<ChaCha20> {
+        Some(Self::keystream(self.master_key.as_ref()?, KEY_PURPOSE_DATA, nonce, 0))
+    }
+    /// The keystream encrypting the entry names of a directory with nonce `nonce`,
+    /// the name of the entry at byte offset `offset` uses the keystream from `offset` on.
+    /// `None` without master key.
+    pub(crate) fn name_cipher(&self, nonce: u32) -> Option<ChaCha20> {
+        Some(Self::keystream(self.master_key.as_ref()?, KEY_PURPOSE_NAME, nonce, 0))
+    }
+    /// Hand out a nonce never used before, `None` once they are exhausted
+    pub(crate) fn alloc_nonce(&self) -> Option<u32> {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                let nonce = super_block.next_nonce;
+                super_block.next_nonce = nonce.checked_add(1)?;
+                Some(nonce)
+            })
+    }
+    /// Whether an inode encrypted with nonce `nonce` has to get a new one
+    /// before anything is written with the current one
+    pub(crate) fn needs_rekey(&self, nonce: u32) -> bool {
+        nonce == 0 || nonce < self.read_super_block(|super_block| super_block.rekey_below)
+    }
+    /// Call a function over the quota entry of a user, which is taken from the
+    /// free entries if the user has none. The function gets `None` if the table is full.
+    fn modify_quota<V>(&self, uid: u32, f: impl FnOnce(Option<&mut QuotaEntry>) -> V) -> V {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                let quotas = &mut super_block.quotas;
+                let index = quotas
+                    .iter()
+                    .position(|quota| quota.uid == uid && !quota.is_free())
+                    .or_else(|| quotas.iter().position(|quota| quota.is_free()));
+                f(index.map(|index| {
+                    let quota = &mut quotas[index];
+                    if quota.is_free() {
+                        *quota = QuotaEntry::empty();
+                        quota.uid = uid;
+                    }
+                    quota
+                }))
+            })
+    }
+    /// Account blocks and inodes to a user, failing if that passes the limits.
+    /// Users left out of a full quota table are not accounted.
+    pub(crate) fn charge(&mut self, uid: u32, blocks: u32, inodes: u32) -> Result<(), ()> {
+        self.modify_quota(uid, |quota| quota.map_or(Ok(()), |quota| quota.charge(blocks, inodes)))
+    }
+    /// Account blocks and inodes to a user without checking the limits
+    pub(crate) fn force_charge(&mut self, uid: u32, blocks: u32, inodes: u32) {
+        self.modify_quota(uid, |quota| {
+            if let Some(quota) = quota {
+                quota.force_charge(blocks, inodes);
+            }
+        })
+    }
+    /// Give back blocks and inodes accounted to a user
+    pub(crate) fn release(&mut self, uid: u32, blocks: u32, inodes: u32) {
+        self.modify_quota(uid, |quota| {
+            if let Some(quota) = quota {
+                quota.release(blocks, inodes);
+            }
+        })
+    }
+    /// Set the limits of a user, 0 for no limit
+    #[allow(clippy::result_unit_err)]
+    pub fn set_quota(
+        &mut self,
+        uid: u32,
+        block_soft: u32,
+        block_hard: u32,
+        inode_soft: u32,
+        inode_hard: u32,
+    ) -> Result<(), ()> {
+        if self.is_read_only() {
+            return Err(());
+        }
+        self.modify_quota(uid, |quota| {
+            let quota = quota.ok_or(())?;
+            quota.block_soft = block_soft;
+            quota.block_hard = block_hard;
+            quota.inode_soft = inode_soft;
+            quota.inode_hard = inode_hard;
+            // the grace starts again under the new limits
+            quota.release(0, 0);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Get the usage and limits of all accounted users
+    pub fn quotas(&self) -> Vec<QuotaEntry> {
+        self.read_super_block(|super_block| {
+            super_block
+                .quotas
+                .iter()
+                .filter(|quota| !quota.is_free())
+                .copied()
+                .collect()
+        })
+    }
+    /// Count the usage of every user again from the live inodes
+    fn recount_quotas(&mut self) {
+        let mut usage: BTreeMap<u32, (u32, u32)> = BTreeMap::new();
+        for inode_id in self.inode_bitmap.allocated(&self.block_device) {
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id as u32);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    let owner_usage = usage.entry(disk_inode.owner).or_insert((0, 0));
+                    owner_usage.0 += disk_inode.block_ids(&self.block_device).len() as u32;
+                    owner_usage.1 += 1;
+                });
+        }
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                for quota in super_block.quotas.iter_mut() {
+                    quota.release(quota.blocks, quota.inodes);
+                }
+            });
+        for (uid, (blocks, inodes)) in usage {
+            self.force_charge(uid, blocks, inodes);
+        }
+    }
+}
+
+/// How scattered the blocks of a file, or of all files of a volume, are
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fragmentation {
+    /// Files using any block
+    pub files: usize,
+    /// Blocks in use, index blocks included
+    pub blocks: usize,
+    /// Runs of consecutive blocks they are stored in
+    pub extents: usize,
+}
+
+impl Fragmentation {
+    /// Count the runs of consecutive blocks of a file, given in file order
+    fn of(block_ids: &[u32]) -> Self {
+        let extents = match block_ids.first() {
+            Some(_) => 1 + block_ids.windows(2).filter(|pair| pair[1] != pair[0] + 1).count(),
+            None => 0,
+        };
+        Self {
+            files: usize::from(!block_ids.is_empty()),
+            blocks: block_ids.len(),
+            extents,
+        }
+    }
+    /// Percentage of the neighbouring blocks of files which are not stored
+    /// one after the other, 0 if every file is a single run
+    pub fn score(&self) -> usize {
+        if self.blocks <= self.files {
+            return 0;
+        }
+        (self.extents - self.files) * 100 / (self.blocks - self.files)
+    }
+}
+
+impl core::ops::Add for Fragmentation {
+    type Output = Self;
+    /// Sum up the fragmentation of two sets of files
+    fn add(self, other: Self) -> Self {
+        Self {
+            files: self.files + other.files,
+            blocks: self.blocks + other.blocks,
+            extents: self.extents + other.extents,
+        }
+    }
 }
@@ -0,0 +1,130 @@
+use super::{BlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+use spin::Mutex;
+
+/// When a [`FaultyBlockDevice`] starts to fault
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultTrigger {
+    /// Writes fault once this many reads and writes have been done
+    AfterOps(usize),
+    /// Writes fault from the first write to this block on
+    AtBlock(usize),
+}
+
+/// What a [`FaultyBlockDevice`] does with the writes once it faults
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultMode {
+    /// Panic, like an I/O error the filesystem has no way to handle
+    Fail,
+    /// Lose the writes silently, like a power cut
+    Drop,
+    /// Hold each write back until the next one is done, so that the
+    /// writes reach the device in swapped pairs
+    Reorder,
+    /// Fail the reads and writes with an error, like a disk gone dead
+    Error,
+}
+
+struct FaultState {
+    trigger: FaultTrigger,
+    mode: FaultMode,
+    ops: usize,
+    triggered: bool,
+    /// The write held back in [`FaultMode::Reorder`]
+    held: Option<(usize, [u8; BLOCK_SZ])>,
+    faulty_writes: usize,
+}
+
+/// A wrapper of a block device faulting on writes, to simulate crashes
+pub struct FaultyBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    state: Mutex<FaultState>,
+}
+
+impl FaultyBlockDevice {
+    /// Wrap `inner`, faulting as `mode` says once `trigger` is met
+    pub fn new(inner: Arc<dyn BlockDevice>, trigger: FaultTrigger, mode: FaultMode) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(FaultState {
+                trigger,
+                mode,
+                ops: 0,
+                triggered: false,
+                held: None,
+                faulty_writes: 0,
+            }),
+        }
+    }
+    /// Whether the device faults already
+    pub fn triggered(&self) -> bool {
+        self.state.lock().triggered
+    }
+    /// Get the number of writes which failed, were lost or were reordered
+    pub fn faulty_writes(&self) -> usize {
+        self.state.lock().faulty_writes
+    }
+}
+
+impl BlockDevice for FaultyBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.try_read_block(block_id, buf)
+            .unwrap_or_else(|_| panic!("injected error reading block {}", block_id));
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.try_write_block(block_id, buf)
+            .unwrap_or_else(|_| panic!("injected error writing block {}", block_id));
+    }
+    fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), ()> {
+        let mut state = self.state.lock();
+        state.ops += 1;
+        if state.triggered && state.mode == FaultMode::Error {
+            return Err(());
+        }
+        // a write held back is visible already, as in the cache of a disk
+        match &state.held {
+            Some((held_id, data)) if *held_id == block_id => buf.copy_from_slice(data),
+            _ => self.inner.read_block(block_id, buf),
+        }
+        Ok(())
+    }
+    fn try_write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), ()> {
+        let mut state = self.state.lock();
+        state.ops += 1;
+        if !state.triggered {
+            state.triggered = match state.trigger {
+                FaultTrigger::AfterOps(n) => state.ops > n,
+                FaultTrigger::AtBlock(id) => block_id == id,
+            };
+        }
+        if !state.triggered {
+            self.inner.write_block(block_id, buf);
+            return Ok(());
+        }
+        state.faulty_writes += 1;
+        match state.mode {
+            FaultMode::Fail => {
+                drop(state);
+                panic!("injected fault writing block {}", block_id);
+            }
+            FaultMode::Drop => {}
+            FaultMode::Reorder => {
+                let mut data = [0u8; BLOCK_SZ];
+                data.copy_from_slice(buf);
+                match state.held.take() {
+                    // a later write of the same block replaces the one held back
+                    Some((held_id, _)) if held_id == block_id => {}
+                    Some((held_id, held_data)) => {
+                        self.inner.write_block(block_id, buf);
+                        self.inner.write_block(held_id, &held_data);
+                        return Ok(());
+                    }
+                    None => {}
+                }
+                state.held = Some((block_id, data));
+            }
+            FaultMode::Error => return Err(()),
+        }
+        Ok(())
+    }
+}
@@ -4,15 +4,23 @@ use super::{
     BlockDevice,
     get_block_cache,
 };
+use super::lz4;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::ops::Range;
 
-/// Magic number for sanity check
-const EFS_MAGIC: u32 = 0x3b800001;
+/// Magic number for sanity check, changed when the super block gained a version
+const EFS_MAGIC: u32 = 0x3b800002;
+/// Magic number of images older than the version field, which cannot be read
+const EFS_MAGIC_UNVERSIONED: u32 = 0x3b800001;
+/// Version of the on-disk layout, bumped whenever [`SuperBlock`] or [`DiskInode`] changes
+pub const EFS_VERSION: u32 = 2;
 /// The max number of direct inodes
 const INODE_DIRECT_COUNT: usize = 28;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 27;
 /// The max number of indirect1 inodes
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 inodes
@@ -24,26 +32,65 @@ const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 /// The upper bound of indirect2 inode index
 #[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// Inode flag: file data is stored as compressed clusters
+pub const INODE_FLAG_COMPRESSED: u32 = 1 << 0;
+/// Inode flag: file data is stored inline in the direct pointer area
+pub const INODE_FLAG_INLINE: u32 = 1 << 1;
+/// Inode flag: names under this directory, or the data of this file, are encrypted
+pub const INODE_FLAG_ENCRYPTED: u32 = 1 << 2;
+/// The max size of data stored inline
+pub const INLINE_DATA_SZ: usize = INODE_DIRECT_COUNT * 4;
+/// Number of blocks in a compression cluster
+const CLUSTER_BLOCKS: usize = 8;
+/// Size of a compression cluster in bytes
+const CLUSTER_SZ: usize = CLUSTER_BLOCKS * BLOCK_SZ;
+/// Set in the first block pointer of a cluster which is stored compressed
+const COMPRESSED_CLUSTER: u32 = 1 << 31;
+/// Size of the compressed length header at the start of a compressed cluster
+const CLUSTER_HEADER_SZ: usize = 4;
 
 /// Super block of a filesystem
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
     magic: u32,
+    /// Layout version the image was created with, see [`EFS_VERSION`]
+    pub version: u32,
     pub total_blocks: u32,
     pub inode_bitmap_blocks: u32,
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Block holding the [`SnapshotTable`], 0 if no snapshot was ever taken
+    pub snapshot_block: u32,
+    /// Derived from the master key to check it, all zero if nothing is encrypted
+    pub key_check: [u8; KEY_CHECK_SZ],
+    /// Next nonce handed out to an encrypted inode, each one is used only once
+    pub next_nonce: u32,
+    /// Encrypted inodes with a smaller nonce get a new one before their next write,
+    /// as a rollback may have brought back a nonce whose keystream was used since
+    pub rekey_below: u32,
+    /// Usage and limits of the users owning something or having a quota
+    pub quotas: [QuotaEntry; MAX_QUOTA_ENTRIES],
 }
 
+/// Size of the master key check value
+pub const KEY_CHECK_SZ: usize = 16;
+
 impl Debug for SuperBlock {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("SuperBlock")
+            .field("version", &self.version)
             .field("total_blocks", &self.total_blocks)
             .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("snapshot_block", &self.snapshot_block)
+            .field("key_check", &self.key_check)
+            .field("next_nonce", &self.next_nonce)
+            .field("rekey_below", &self.rekey_below)
+            .field("quotas", &self.quotas.iter().filter(|quota| !quota.is_free()).collect::<Vec<_>>())
             .finish()
     }
 }
@@ -60,21 +107,131 @@ impl SuperBlock {
     ) {
         *self = Self {
             magic: EFS_MAGIC,
+            version: EFS_VERSION,
             total_blocks,
             inode_bitmap_blocks,
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            snapshot_block: 0,
+            key_check: [0; KEY_CHECK_SZ],
+            next_nonce: 1,
+            rekey_below: 0,
+            quotas: [QuotaEntry::empty(); MAX_QUOTA_ENTRIES],
         }
     }
     /// Check if a super block is valid using efs magic
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// Check if the image has the layout this crate reads and writes
+    pub fn is_supported(&self) -> bool {
+        self.is_valid() && self.version == EFS_VERSION
+    }
+    /// Check if the image is an easy-fs image from before the version field
+    pub fn is_unversioned(&self) -> bool {
+        self.magic == EFS_MAGIC_UNVERSIONED
+    }
+}
+
+/// Max number of users whose usage is accounted, bounded by the room left in the super block
+pub const MAX_QUOTA_ENTRIES: usize = 12;
+/// Number of allocations a user may still make after going over a soft limit
+pub const QUOTA_GRACE: u32 = 16;
+
+/// Disk usage and limits of a user. A limit of 0 means no limit.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuotaEntry {
+    pub uid: u32,
+    /// Data and index blocks used by the inodes the user owns
+    pub blocks: u32,
+    /// Number of inodes the user owns
+    pub inodes: u32,
+    pub block_soft: u32,
+    pub block_hard: u32,
+    pub inode_soft: u32,
+    pub inode_hard: u32,
+    /// Allocations of blocks left while over the soft limit
+    pub block_grace: u32,
+    /// Allocations of inodes left while over the soft limit
+    pub inode_grace: u32,
+}
+
+impl QuotaEntry {
+    /// An entry of no user
+    pub const fn empty() -> Self {
+        Self {
+            uid: 0,
+            blocks: 0,
+            inodes: 0,
+            block_soft: 0,
+            block_hard: 0,
+            inode_soft: 0,
+            inode_hard: 0,
+            block_grace: QUOTA_GRACE,
+            inode_grace: QUOTA_GRACE,
+        }
+    }
+    /// Whether this entry neither accounts nor limits anything and can be reused
+    pub fn is_free(&self) -> bool {
+        self.blocks == 0
+            && self.inodes == 0
+            && self.block_soft == 0
+            && self.block_hard == 0
+            && self.inode_soft == 0
+            && self.inode_hard == 0
+    }
+    /// Whether the user is over one of the soft limits
+    pub fn over_soft_limit(&self) -> bool {
+        (self.block_soft != 0 && self.blocks > self.block_soft)
+            || (self.inode_soft != 0 && self.inodes > self.inode_soft)
+    }
+    /// Account `blocks` and `inodes` more, failing without any change
+    /// if that passes a hard limit, or a soft limit once the grace is used up
+    #[allow(clippy::result_unit_err)]
+    pub fn charge(&mut self, blocks: u32, inodes: u32) -> core::result::Result<(), ()> {
+        let block_grace = Self::check(self.blocks, blocks, self.block_soft, self.block_hard, self.block_grace)?;
+        let inode_grace = Self::check(self.inodes, inodes, self.inode_soft, self.inode_hard, self.inode_grace)?;
+        self.blocks += blocks;
+        self.inodes += inodes;
+        self.block_grace = block_grace;
+        self.inode_grace = inode_grace;
+        Ok(())
+    }
+    /// Account `blocks` and `inodes` more without checking the limits
+    pub fn force_charge(&mut self, blocks: u32, inodes: u32) {
+        self.blocks += blocks;
+        self.inodes += inodes;
+    }
+    /// Account `blocks` and `inodes` less, the grace is restored under the soft limit
+    pub fn release(&mut self, blocks: u32, inodes: u32) {
+        self.blocks = self.blocks.saturating_sub(blocks);
+        self.inodes = self.inodes.saturating_sub(inodes);
+        if self.block_soft == 0 || self.blocks <= self.block_soft {
+            self.block_grace = QUOTA_GRACE;
+        }
+        if self.inode_soft == 0 || self.inodes <= self.inode_soft {
+            self.inode_grace = QUOTA_GRACE;
+        }
+    }
+    /// Check adding `n` to `used` against the limits, return the grace left
+    fn check(used: u32, n: u32, soft: u32, hard: u32, grace: u32) -> core::result::Result<u32, ()> {
+        if n == 0 {
+            return Ok(grace);
+        }
+        if hard != 0 && used + n > hard {
+            return Err(());
+        }
+        if soft != 0 && used + n > soft {
+            return grace.checked_sub(1).ok_or(());
+        }
+        Ok(QUOTA_GRACE)
+    }
 }
 
 /// Type of a disk inode
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum DiskInodeType {
     File,
     Directory,
@@ -93,6 +250,12 @@ pub struct DiskInode {
     pub indirect1: u32,
     pub indirect2: u32,
     pub link_cnt: u32,
+    pub flags: u32,
+    /// Uid of the user whose quota the inode is accounted in
+    pub owner: u32,
+    /// Nonce of the keystream encrypting the data or the entry names,
+    /// 0 until the first write. Renewed whenever stored bytes get rewritten.
+    pub nonce: u32,
     type_: DiskInodeType,
 }
 
@@ -106,6 +269,9 @@ impl DiskInode {
         self.indirect2 = 0;
         self.type_ = type_;
         self.link_cnt = 1;
+        self.flags = INODE_FLAG_INLINE;
+        self.owner = 0;
+        self.nonce = 0;
     }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
@@ -116,6 +282,31 @@ impl DiskInode {
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    /// Whether file data is stored as compressed clusters
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_FLAG_COMPRESSED != 0
+    }
+    /// Whether names under this directory, or the data of this file, are encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & INODE_FLAG_ENCRYPTED != 0
+    }
+    /// Whether file data is stored inline in the inode
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE != 0
+    }
+    /// The inline data area, overlapping the direct pointers
+    fn inline_data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.direct.as_ptr() as *const u8, INLINE_DATA_SZ) }
+    }
+    fn inline_data_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.direct.as_mut_ptr() as *mut u8, INLINE_DATA_SZ)
+        }
+    }
+    /// Get the type of this inode
+    pub fn inode_type(&self) -> DiskInodeType {
+        self.type_
+    }
     /// Get the number of data blocks corresponding to size
     pub fn data_blocks(&self) -> u32 {
         Self::_data_blocks(self.size)
@@ -142,10 +333,29 @@ impl DiskInode {
     /// Get the number of data blocks that have to be allocated given the new size of data
     pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
         assert!(new_size >= self.size);
+        if self.is_inline() {
+            // moving out of the inode needs blocks for the old data as well
+            return if new_size as usize <= INLINE_DATA_SZ {
+                0
+            } else {
+                Self::total_blocks(new_size)
+            };
+        }
         Self::total_blocks(new_size) - Self::total_blocks(self.size)
     }
-    /// Get id of block given inner id
+    /// Get the number of index blocks that have to be allocated given the new size of data.
+    /// Compressed inodes allocate their data blocks cluster by cluster when written.
+    pub fn index_blocks_needed(&self, new_size: u32) -> u32 {
+        assert!(new_size >= self.size);
+        (Self::total_blocks(new_size) - Self::_data_blocks(new_size))
+            - (Self::total_blocks(self.size) - self.data_blocks())
+    }
+    /// Get id of block given inner id, 0 for a hole
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        self.get_block_ptr(inner_id, block_device) & !COMPRESSED_CLUSTER
+    }
+    /// Get the block pointer of given inner id, including the compressed cluster mark
+    fn get_block_ptr(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
         let inner_id = inner_id as usize;
         if inner_id < INODE_DIRECT_COUNT {
             self.direct[inner_id]
@@ -175,20 +385,68 @@ impl DiskInode {
             })
         }
     }
+    /// Set the block pointer of given inner id, the index blocks must exist
+    fn set_block_ptr(&mut self, inner_id: u32, ptr: u32, block_device: &Arc<dyn BlockDevice>) {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = ptr;
+        } else if inner_id < INDIRECT1_BOUND {
+            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect_block: &mut IndirectBlock| {
+                    indirect_block[inner_id - INODE_DIRECT_COUNT] = ptr;
+                });
+        } else {
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = get_block_cache(
+                self.indirect2 as usize,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .read(0, |indirect2: &IndirectBlock| {
+                indirect2[last / INODE_INDIRECT1_COUNT]
+            });
+            get_block_cache(
+                indirect1 as usize,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .modify(0, |indirect1: &mut IndirectBlock| {
+                indirect1[last % INODE_INDIRECT1_COUNT] = ptr;
+            });
+        }
+    }
     /// Inncrease the size of current disk inode
+    /// new data blocks are left as holes for compressed inodes,
+    /// inline data moves to blocks once it outgrows the inode
     pub fn increase_size(
         &mut self,
         new_size: u32,
         new_blocks: Vec<u32>,
         block_device: &Arc<dyn BlockDevice>,
     ) {
+        if self.is_inline() {
+            if new_size as usize <= INLINE_DATA_SZ {
+                self.size = new_size;
+                return;
+            }
+            let data = self.inline_data()[..self.size as usize].to_vec();
+            self.inline_data_mut().fill(0);
+            self.flags &= !INODE_FLAG_INLINE;
+            self.size = 0;
+            self.increase_size(new_size, new_blocks, block_device);
+            self.write_at(0, &data, block_device);
+            return;
+        }
         let mut current_blocks = self.data_blocks();
         self.size = new_size;
         let mut total_blocks = self.data_blocks();
+        let holes = self.is_compressed();
         let mut new_blocks = new_blocks.into_iter();
         // fill direct
         while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
+            self.direct[current_blocks as usize] =
+                if holes { 0 } else { new_blocks.next().unwrap() };
             current_blocks += 1;
         }
         // alloc indirect1
@@ -209,7 +467,8 @@ impl DiskInode {
         .lock()
         .modify(0, |indirect1: &mut IndirectBlock| {
             while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                indirect1[current_blocks as usize] = new_blocks.next().unwrap();
+                indirect1[current_blocks as usize] =
+                    if holes { 0 } else { new_blocks.next().unwrap() };
                 current_blocks += 1;
             }
         });
@@ -246,7 +505,7 @@ impl DiskInode {
                 )
                 .lock()
                 .modify(0, |indirect1: &mut IndirectBlock| {
-                    indirect1[b0] = new_blocks.next().unwrap();
+                    indirect1[b0] = if holes { 0 } else { new_blocks.next().unwrap() };
                 });
                 // move to next
                 b0 += 1;
@@ -259,15 +518,171 @@ impl DiskInode {
     }
     /// Clear size to zero and return blocks that should be deallocated
     /// and clear the block contents to zero later
+    /// An uncompressed inode stores its data inline again afterwards
     pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        if self.is_inline() {
+            self.size = 0;
+            self.inline_data_mut().fill(0);
+            return Vec::new();
+        }
+        let v = self.block_ids(block_device);
+        self.size = 0;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.indirect1 = 0;
+        self.indirect2 = 0;
+        if !self.is_compressed() {
+            self.flags |= INODE_FLAG_INLINE;
+        }
+        v
+    }
+    /// Get ids of all blocks used by this inode, index blocks included
+    pub fn block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        if self.is_inline() {
+            return Vec::new();
+        }
+        self.block_ptrs(block_device)
+            .into_iter()
+            .filter(|ptr| *ptr != 0)
+            .map(|ptr| ptr & !COMPRESSED_CLUSTER)
+            .collect()
+    }
+    /// Make the blocks that writing `[offset, offset + len)` modifies private
+    /// to this inode, including the index blocks growing the file fills.
+    /// `cow` returns a private copy of a shared block, `None` if not shared.
+    pub fn unshare<F: FnMut(u32) -> Option<u32>>(
+        &mut self,
+        offset: usize,
+        len: usize,
+        cow: &mut F,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let data_blocks = self.data_blocks() as usize;
+        if self.is_inline() || len == 0 || data_blocks == 0 {
+            return;
+        }
+        let mut first = offset / BLOCK_SZ;
+        let mut last = (offset + len - 1) / BLOCK_SZ;
+        if self.is_compressed() {
+            // clusters are rewritten as a whole
+            first = first / CLUSTER_BLOCKS * CLUSTER_BLOCKS;
+            last = last / CLUSTER_BLOCKS * CLUSTER_BLOCKS + CLUSTER_BLOCKS - 1;
+        }
+        if last >= data_blocks {
+            self.unshare_index_path(data_blocks, cow, block_device);
+            last = data_blocks - 1;
+        }
+        for inner_id in first..=last {
+            let new_index_block = inner_id == first
+                || inner_id == DIRECT_BOUND
+                || (inner_id >= INDIRECT1_BOUND
+                    && inner_id % INODE_INDIRECT1_COUNT == INDIRECT1_BOUND % INODE_INDIRECT1_COUNT);
+            if new_index_block {
+                self.unshare_index_path(inner_id, cow, block_device);
+            }
+            let ptr = self.get_block_ptr(inner_id as u32, block_device);
+            if ptr & !COMPRESSED_CLUSTER == 0 {
+                continue;
+            }
+            if let Some(block_id) = cow(ptr & !COMPRESSED_CLUSTER) {
+                let mark = ptr & COMPRESSED_CLUSTER;
+                self.set_block_ptr(inner_id as u32, block_id | mark, block_device);
+            }
+        }
+    }
+    /// Make the existing index blocks on the way to `inner_id` private
+    fn unshare_index_path<F: FnMut(u32) -> Option<u32>>(
+        &mut self,
+        inner_id: usize,
+        cow: &mut F,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let data_blocks = self.data_blocks() as usize;
+        if inner_id < DIRECT_BOUND {
+            return;
+        }
+        if inner_id < INDIRECT1_BOUND {
+            if data_blocks > DIRECT_BOUND {
+                if let Some(block_id) = cow(self.indirect1) {
+                    self.indirect1 = block_id;
+                }
+            }
+            return;
+        }
+        if data_blocks <= INDIRECT1_BOUND {
+            return;
+        }
+        if let Some(block_id) = cow(self.indirect2) {
+            self.indirect2 = block_id;
+        }
+        let a = (inner_id - INDIRECT1_BOUND) / INODE_INDIRECT1_COUNT;
+        if data_blocks > INDIRECT1_BOUND + a * INODE_INDIRECT1_COUNT {
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect2: &mut IndirectBlock| {
+                    if let Some(block_id) = cow(indirect2[a]) {
+                        indirect2[a] = block_id;
+                    }
+                });
+        }
+    }
+    /// Replace the ids of moved blocks according to `map`. Index blocks are
+    /// rewritten at their new place, so their content must be copied first.
+    pub fn remap_blocks(&mut self, map: &BTreeMap<u32, u32>, block_device: &Arc<dyn BlockDevice>) {
+        if self.is_inline() {
+            return;
+        }
+        let remap = |ptr: u32| match map.get(&(ptr & !COMPRESSED_CLUSTER)) {
+            Some(block_id) => *block_id | (ptr & COMPRESSED_CLUSTER),
+            None => ptr,
+        };
+        let data_blocks = self.data_blocks() as usize;
+        self.direct
+            .iter_mut()
+            .take(data_blocks)
+            .for_each(|ptr| *ptr = remap(*ptr));
+        if data_blocks > DIRECT_BOUND {
+            self.indirect1 = remap(self.indirect1);
+            let count = (data_blocks - DIRECT_BOUND).min(INODE_INDIRECT1_COUNT);
+            Self::remap_index_block(self.indirect1, count, &remap, block_device);
+        }
+        if data_blocks > INDIRECT1_BOUND {
+            self.indirect2 = remap(self.indirect2);
+            let last = data_blocks - INDIRECT1_BOUND;
+            let indirect1_blocks = (last - 1) / INODE_INDIRECT1_COUNT + 1;
+            Self::remap_index_block(self.indirect2, indirect1_blocks, &remap, block_device);
+            for a in 0..indirect1_blocks {
+                let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |indirect2: &IndirectBlock| indirect2[a]);
+                let count = (last - a * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+                Self::remap_index_block(indirect1, count, &remap, block_device);
+            }
+        }
+    }
+    /// Replace the first `count` pointers of an index block
+    fn remap_index_block(
+        block_id: u32,
+        count: usize,
+        remap: &impl Fn(u32) -> u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |indirect_block: &mut IndirectBlock| {
+                indirect_block
+                    .iter_mut()
+                    .take(count)
+                    .for_each(|ptr| *ptr = remap(*ptr));
+            });
+    }
+    /// Get all block pointers, holes included, index blocks as well
+    fn block_ptrs(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
         let mut v: Vec<u32> = Vec::new();
         let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
         let mut current_blocks = 0usize;
         // direct
         while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
             v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
             current_blocks += 1;
         }
         // indirect1 block
@@ -284,14 +699,12 @@ impl DiskInode {
             Arc::clone(block_device),
         )
         .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
+        .read(0, |indirect1: &IndirectBlock| {
             while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
                 v.push(indirect1[current_blocks]);
-                //indirect1[current_blocks] = 0;
                 current_blocks += 1;
             }
         });
-        self.indirect1 = 0;
         // indirect2 block
         if data_blocks > INODE_INDIRECT1_COUNT {
             v.push(self.indirect2);
@@ -308,7 +721,7 @@ impl DiskInode {
             Arc::clone(block_device),
         )
         .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
+        .read(0, |indirect2: &IndirectBlock| {
             // full indirect1 blocks
             for i in 0..a1 {
                 v.push(indirect2[i]);
@@ -317,13 +730,11 @@ impl DiskInode {
                     Arc::clone(block_device),
                 )
                 .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
+                .read(0, |indirect1: &IndirectBlock| {
                     for j in 0..INODE_INDIRECT1_COUNT {
                         v.push(indirect1[j]);
-                        //indirect1[j] = 0;
                     }
                 });
-                //indirect2[i] = 0;
             }
             // last indirect1 block
             if b1 > 0 {
@@ -333,16 +744,13 @@ impl DiskInode {
                     Arc::clone(block_device),
                 )
                 .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
+                .read(0, |indirect1: &IndirectBlock| {
                     for j in 0..b1 {
                         v.push(indirect1[j]);
-                        //indirect1[j] = 0;
                     }
                 });
-                //indirect2[a1] = 0;
             }
         });
-        self.indirect2 = 0;
         v
     }
     /// Read data from current disk inode
@@ -357,6 +765,13 @@ impl DiskInode {
         if start >= end {
             return 0;
         }
+        if self.is_inline() {
+            buf[..end - start].copy_from_slice(&self.inline_data()[start..end]);
+            return end - start;
+        }
+        if self.is_compressed() {
+            return self.read_at_compressed(offset, &mut buf[..end - offset], block_device);
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut read_size = 0usize;
         loop {
@@ -394,6 +809,13 @@ impl DiskInode {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         assert!(start <= end);
+        if self.is_inline() {
+            self.inline_data_mut()[start..end].copy_from_slice(&buf[..end - start]);
+            return end - start;
+        }
+        if self.is_compressed() {
+            return self.write_at_compressed(offset, &buf[..end - offset], block_device);
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
         loop {
@@ -420,6 +842,208 @@ impl DiskInode {
         }
         write_size
     }
+    /// Range of inner ids covering the clusters touched by `[offset, offset + len)`
+    fn cluster_slots(&self, offset: usize, len: usize) -> Range<u32> {
+        let end = (offset + len).min(self.size as usize);
+        if offset >= end {
+            return 0..0;
+        }
+        let first = offset / CLUSTER_SZ * CLUSTER_BLOCKS;
+        let last = ((end - 1) / CLUSTER_SZ + 1) * CLUSTER_BLOCKS;
+        first as u32..last.min(self.data_blocks() as usize) as u32
+    }
+    /// Number of logical blocks of a cluster given current size
+    fn cluster_blocks(&self, cluster: usize) -> usize {
+        (self.data_blocks() as usize - cluster * CLUSTER_BLOCKS).min(CLUSTER_BLOCKS)
+    }
+    /// Number of holes in the clusters touched by `[offset, offset + len)`
+    pub fn cluster_holes(
+        &self,
+        offset: usize,
+        len: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        self.cluster_slots(offset, len)
+            .filter(|inner_id| self.get_block_id(*inner_id, block_device) == 0)
+            .count()
+    }
+    /// Fill the holes in the clusters touched by `[offset, offset + len)`
+    /// with newly allocated blocks, so that any of them can be stored raw
+    pub fn fill_cluster_holes(
+        &mut self,
+        offset: usize,
+        len: usize,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let mut new_blocks = new_blocks.into_iter();
+        for inner_id in self.cluster_slots(offset, len) {
+            if self.get_block_id(inner_id, block_device) == 0 {
+                self.set_block_ptr(inner_id, new_blocks.next().unwrap(), block_device);
+            }
+        }
+    }
+    /// Punch the blocks a compressed cluster does not use any more
+    /// in the clusters touched by `[offset, offset + len)`,
+    /// and return them to be deallocated
+    pub fn trim_clusters(
+        &mut self,
+        offset: usize,
+        len: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        let slots = self.cluster_slots(offset, len);
+        for first in slots.clone().step_by(CLUSTER_BLOCKS) {
+            let head = self.get_block_ptr(first, block_device);
+            if head & COMPRESSED_CLUSTER == 0 {
+                continue;
+            }
+            let stored_blocks = Self::compressed_cluster_blocks(head, block_device);
+            for inner_id in first + stored_blocks as u32..(first + CLUSTER_BLOCKS as u32).min(slots.end) {
+                let block_id = self.get_block_id(inner_id, block_device);
+                if block_id != 0 {
+                    v.push(block_id);
+                    self.set_block_ptr(inner_id, 0, block_device);
+                }
+            }
+        }
+        v
+    }
+    /// Number of blocks a compressed cluster is stored in, given its head pointer,
+    /// never more than a cluster even if the stored length is corrupted
+    fn compressed_cluster_blocks(head: u32, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let compressed_len = get_block_cache(
+            (head & !COMPRESSED_CLUSTER) as usize,
+            Arc::clone(block_device),
+        )
+        .lock()
+        .read(0, |len: &u32| *len as usize);
+        (compressed_len.min(CLUSTER_SZ) + CLUSTER_HEADER_SZ - 1) / BLOCK_SZ + 1
+    }
+    /// Read the content of a cluster into `buf`, holes and bytes
+    /// past the stored data read as zero.
+    /// Fail if the cluster is compressed and its stored data is corrupted.
+    fn read_cluster(
+        &self,
+        cluster: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> core::result::Result<(), ()> {
+        buf.fill(0);
+        let first = (cluster * CLUSTER_BLOCKS) as u32;
+        let head = self.get_block_ptr(first, block_device);
+        if head & COMPRESSED_CLUSTER != 0 {
+            let stored_blocks = Self::compressed_cluster_blocks(head, block_device)
+                .min(self.cluster_blocks(cluster));
+            let mut stored = vec![0u8; stored_blocks * BLOCK_SZ];
+            for (i, block) in stored.chunks_mut(BLOCK_SZ).enumerate() {
+                get_block_cache(
+                    self.get_block_id(first + i as u32, block_device) as usize,
+                    Arc::clone(block_device),
+                )
+                .lock()
+                .read(0, |data_block: &DataBlock| block.copy_from_slice(data_block));
+            }
+            let compressed_len =
+                u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]) as usize;
+            if CLUSTER_HEADER_SZ + compressed_len > stored.len() {
+                return Err(());
+            }
+            lz4::decompress(
+                &stored[CLUSTER_HEADER_SZ..CLUSTER_HEADER_SZ + compressed_len],
+                buf,
+            )
+            .ok_or(())?;
+        } else {
+            for i in 0..self.cluster_blocks(cluster) {
+                let block_id = self.get_block_id(first + i as u32, block_device);
+                if block_id == 0 {
+                    continue;
+                }
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        buf[i * BLOCK_SZ..(i + 1) * BLOCK_SZ].copy_from_slice(data_block)
+                    });
+            }
+        }
+        Ok(())
+    }
+    /// Store `buf` as the content of a cluster, compressed if it saves at least
+    /// one block. All blocks of the cluster must be allocated beforehand.
+    fn write_cluster(&mut self, cluster: usize, buf: &[u8], block_device: &Arc<dyn BlockDevice>) {
+        let blocks = self.cluster_blocks(cluster);
+        let first = (cluster * CLUSTER_BLOCKS) as u32;
+        let mut stored: Vec<u8> = vec![0u8; CLUSTER_HEADER_SZ];
+        lz4::compress(&buf[..blocks * BLOCK_SZ], &mut stored);
+        let compressed_len = (stored.len() - CLUSTER_HEADER_SZ) as u32;
+        stored[..CLUSTER_HEADER_SZ].copy_from_slice(&compressed_len.to_le_bytes());
+        let (data, mark) = if stored.len() <= (blocks - 1) * BLOCK_SZ {
+            (stored.as_slice(), COMPRESSED_CLUSTER)
+        } else {
+            (&buf[..blocks * BLOCK_SZ], 0)
+        };
+        for (i, src) in data.chunks(BLOCK_SZ).enumerate() {
+            get_block_cache(
+                self.get_block_id(first + i as u32, block_device) as usize,
+                Arc::clone(block_device),
+            )
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| {
+                data_block[..src.len()].copy_from_slice(src);
+                data_block[src.len()..].fill(0);
+            });
+        }
+        let head = self.get_block_id(first, block_device);
+        self.set_block_ptr(first, head | mark, block_device);
+    }
+    /// Read data from a compressed inode, `buf` must lie within the file.
+    /// Stop at the first corrupted cluster and return the bytes read before it.
+    fn read_at_compressed(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        let mut cluster_buf = vec![0u8; CLUSTER_SZ];
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let cluster = pos / CLUSTER_SZ;
+            let cluster_end = ((cluster + 1) * CLUSTER_SZ).min(offset + buf.len());
+            if self.read_cluster(cluster, &mut cluster_buf, block_device).is_err() {
+                return pos - offset;
+            }
+            buf[pos - offset..cluster_end - offset]
+                .copy_from_slice(&cluster_buf[pos % CLUSTER_SZ..cluster_end - cluster * CLUSTER_SZ]);
+            pos = cluster_end;
+        }
+        buf.len()
+    }
+    /// Write data into a compressed inode, `buf` must lie within the file
+    /// and the touched clusters must have no holes.
+    /// Stop at the first corrupted cluster and return the bytes written before it.
+    fn write_at_compressed(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        let mut cluster_buf = vec![0u8; CLUSTER_SZ];
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let cluster = pos / CLUSTER_SZ;
+            let cluster_end = ((cluster + 1) * CLUSTER_SZ).min(offset + buf.len());
+            if self.read_cluster(cluster, &mut cluster_buf, block_device).is_err() {
+                return pos - offset;
+            }
+            cluster_buf[pos % CLUSTER_SZ..cluster_end - cluster * CLUSTER_SZ]
+                .copy_from_slice(&buf[pos - offset..cluster_end - offset]);
+            self.write_cluster(cluster, &cluster_buf, block_device);
+            pos = cluster_end;
+        }
+        buf.len()
+    }
 }
 
 /// A directory entry
@@ -472,8 +1096,97 @@ impl DirEntry {
         let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
         core::str::from_utf8(&self.name[..len]).unwrap()
     }
+    /// The name bytes without the terminator, for encrypting them in place
+    pub fn name_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.name[..NAME_LENGTH_LIMIT]
+    }
     /// Get inode number of the entry
     pub fn inode_number(&self) -> u32 {
         self.inode_number
     }
 }
+
+/// Max number of snapshots, bounded by the width of a reference count
+pub const MAX_SNAPSHOTS: usize = u8::MAX as usize;
+
+/// Bookkeeping of snapshots, stored in [`SuperBlock::snapshot_block`]
+#[repr(C)]
+pub struct SnapshotTable {
+    /// Content is an array of [`SnapshotEntry`]
+    pub snapshots: DiskInode,
+    /// Content is one byte per data block, counting the references
+    /// to the block besides its first owner
+    pub refcounts: DiskInode,
+}
+
+impl SnapshotTable {
+    /// Initialize an empty snapshot table
+    pub fn initialize(&mut self) {
+        self.snapshots.initialize(DiskInodeType::File);
+        self.refcounts.initialize(DiskInodeType::File);
+    }
+}
+
+/// A snapshot: a frozen copy of the inode bitmap and inode area
+#[repr(C)]
+pub struct SnapshotEntry {
+    name: [u8; NAME_LENGTH_LIMIT + 1],
+    /// Content is the inode bitmap followed by the inode area
+    pub image: DiskInode,
+}
+
+/// Size of a snapshot entry
+pub const SNAPSHOT_ENTRY_SZ: usize = core::mem::size_of::<SnapshotEntry>();
+
+impl SnapshotEntry {
+    /// Create an empty snapshot entry
+    pub fn empty() -> Self {
+        Self {
+            name: [0u8; NAME_LENGTH_LIMIT + 1],
+            image: DiskInode {
+                size: 0,
+                direct: [0; INODE_DIRECT_COUNT],
+                indirect1: 0,
+                indirect2: 0,
+                link_cnt: 0,
+                flags: 0,
+                owner: 0,
+                nonce: 0,
+                type_: DiskInodeType::File,
+            },
+        }
+    }
+    /// Create a snapshot entry with an empty image
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT {
+            return None;
+        }
+        let mut entry = Self::empty();
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry.image.initialize(DiskInodeType::File);
+        Some(entry)
+    }
+    /// Serialize into bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as usize as *const u8,
+                SNAPSHOT_ENTRY_SZ,
+            )
+        }
+    }
+    /// Serialize into mutable bytes
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self as *mut _ as usize as *mut u8,
+                SNAPSHOT_ENTRY_SZ,
+            )
+        }
+    }
+    /// Get name of the snapshot
+    pub fn name(&self) -> &str {
+        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
+        core::str::from_utf8(&self.name[..len]).unwrap()
+    }
+}
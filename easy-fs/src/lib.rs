@@ -3,17 +3,29 @@
 extern crate alloc;
 
 mod block_dev;
+mod ram_dev;
+mod fault_dev;
+mod mirror_dev;
+mod concat_dev;
 mod layout;
 mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod lz4;
+mod chacha;
 
 /// Use a block size of 512 bytes
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use ram_dev::RamBlockDevice;
+pub use fault_dev::{FaultMode, FaultTrigger, FaultyBlockDevice};
+pub use mirror_dev::MirrorBlockDevice;
+pub use concat_dev::ConcatBlockDevice;
+pub use chacha::{ChaCha20, KEY_SZ};
+pub use efs::{EasyFileSystem, Fragmentation};
+pub use layout::{DiskInodeType, QuotaEntry, SuperBlock, EFS_VERSION, NAME_LENGTH_LIMIT, QUOTA_GRACE};
+pub use vfs::{Inode, ReadDir, ReadDirEntry};
 use layout::*;
-use bitmap::Bitmap;
-use block_cache::{get_block_cache, block_cache_sync_all};
+use bitmap::{block_allocated_bits, Bitmap};
+use block_cache::{device_key, get_block_cache, block_cache_prefetch, block_cache_sync_all};
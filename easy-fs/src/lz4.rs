@@ -0,0 +1,141 @@
+//! A small LZ4-style block compressor used for compressed clusters.
+//!
+//! The stream is a sequence of LZ4 sequences: a token whose high nibble is
+//! the literal length and low nibble the match length minus [`MIN_MATCH`],
+//! optional extra length bytes, the literals, and a little-endian `u16`
+//! match offset. The last sequence carries literals only.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Shortest match worth encoding
+const MIN_MATCH: usize = 4;
+/// Size of the match finder hash table in bits
+const HASH_BITS: u32 = 12;
+/// The last bytes of the input are always emitted as literals
+const LAST_LITERALS: usize = 5;
+/// No match may start within this many bytes of the end
+const MF_LIMIT: usize = 12;
+/// Largest distance a match offset can encode
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+fn read_u32(src: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([src[pos], src[pos + 1], src[pos + 2], src[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Append the extra bytes of a length that did not fit into a nibble
+fn push_length(dst: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        dst.push(255);
+        len -= 255;
+    }
+    dst.push(len as u8);
+}
+
+/// Append a sequence, `matched` is `None` for the trailing literals
+fn push_sequence(dst: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_extra = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    let token = (literals.len().min(15) << 4) | match_extra.min(15);
+    dst.push(token as u8);
+    if literals.len() >= 15 {
+        push_length(dst, literals.len() - 15);
+    }
+    dst.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        dst.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_extra >= 15 {
+            push_length(dst, match_extra - 15);
+        }
+    }
+}
+
+/// Compress `src`, appending the compressed stream to `dst`
+pub fn compress(src: &[u8], dst: &mut Vec<u8>) {
+    // position + 1 of the last occurrence of each hashed sequence, 0 if none
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0usize;
+    let mut pos = 0usize;
+    if src.len() > MF_LIMIT {
+        let limit = src.len() - MF_LIMIT;
+        while pos < limit {
+            let sequence = read_u32(src, pos);
+            let slot = hash(sequence);
+            let candidate = table[slot];
+            table[slot] = pos + 1;
+            if candidate == 0
+                || pos - (candidate - 1) > MAX_OFFSET
+                || read_u32(src, candidate - 1) != sequence
+            {
+                pos += 1;
+                continue;
+            }
+            let match_pos = candidate - 1;
+            let max_len = src.len() - LAST_LITERALS - pos;
+            let mut len = MIN_MATCH;
+            while len < max_len && src[match_pos + len] == src[pos + len] {
+                len += 1;
+            }
+            push_sequence(dst, &src[anchor..pos], Some((pos - match_pos, len)));
+            pos += len;
+            anchor = pos;
+        }
+    }
+    push_sequence(dst, &src[anchor..], None);
+}
+
+/// Read the extra bytes of a length whose nibble was 15
+fn read_length(src: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut len = 0usize;
+    loop {
+        let byte = *src.get(*pos)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompress `src` into `dst`, return the decompressed length,
+/// or `None` if the stream is corrupted or does not fit into `dst`
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut pos = 0usize;
+    let mut out = 0usize;
+    loop {
+        let token = *src.get(pos)? as usize;
+        pos += 1;
+        let mut literal_len = token >> 4;
+        if literal_len == 15 {
+            literal_len += read_length(src, &mut pos)?;
+        }
+        dst.get_mut(out..out + literal_len)?
+            .copy_from_slice(src.get(pos..pos + literal_len)?);
+        pos += literal_len;
+        out += literal_len;
+        if pos == src.len() {
+            return Some(out);
+        }
+        let offset = u16::from_le_bytes([*src.get(pos)?, *src.get(pos + 1)?]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out {
+            return None;
+        }
+        let mut match_len = token & 15;
+        if match_len == 15 {
+            match_len += read_length(src, &mut pos)?;
+        }
+        match_len += MIN_MATCH;
+        if out + match_len > dst.len() {
+            return None;
+        }
+        // the match may overlap the bytes it produces
+        for i in out..out + match_len {
+            dst[i] = dst[i - offset];
+        }
+        out += match_len;
+    }
+}
@@ -0,0 +1,230 @@
+use super::{BlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Number of checksums in a checksum block
+const CHECKSUMS_PER_BLOCK: usize = BLOCK_SZ / 4;
+
+/// Checksum of a block, never 0 which marks a block not written yet
+fn checksum(data: &[u8]) -> u32 {
+    // FNV-1a
+    let hash = data
+        .iter()
+        .fold(0x811c9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193));
+    if hash == 0 {
+        1
+    } else {
+        hash
+    }
+}
+
+struct MirrorState {
+    /// Checksum of every block, 0 if it has never been written
+    checksums: Vec<u32>,
+    /// Devices which returned an error and are not used any more
+    failed: [bool; 2],
+    /// Device the next read goes to first
+    next_read: usize,
+    /// Number of bad copies rewritten from the good one
+    repaired: usize,
+}
+
+/// RAID-1 over two block devices of the same size. Writes go to both, reads
+/// to either, and a copy not matching the checksum of the block is rewritten
+/// from the other one. A device returning an error is dropped, the mirror
+/// goes on with the other. The checksums are stored at the tail of each device.
+pub struct MirrorBlockDevice {
+    devices: [Arc<dyn BlockDevice>; 2],
+    /// Number of blocks usable through the mirror, the checksums follow them
+    total_blocks: usize,
+    state: Mutex<MirrorState>,
+}
+
+impl MirrorBlockDevice {
+    /// Mirror two devices of `device_blocks` blocks each, loading the checksums
+    /// stored on them
+    pub fn new(
+        primary: Arc<dyn BlockDevice>,
+        secondary: Arc<dyn BlockDevice>,
+        device_blocks: usize,
+    ) -> Self {
+        let total_blocks = Self::usable_blocks(device_blocks);
+        let checksum_blocks = device_blocks - total_blocks;
+        let devices = [primary, secondary];
+        let mut failed = [false; 2];
+        let mut tables: Vec<Vec<u32>> = Vec::new();
+        for (i, device) in devices.iter().enumerate() {
+            let mut table = vec![0u32; checksum_blocks * CHECKSUMS_PER_BLOCK];
+            let mut buf = [0u8; BLOCK_SZ];
+            for (pos, checksums) in table.chunks_mut(CHECKSUMS_PER_BLOCK).enumerate() {
+                if device.try_read_block(total_blocks + pos, &mut buf).is_err() {
+                    failed[i] = true;
+                    break;
+                }
+                for (checksum, bytes) in checksums.iter_mut().zip(buf.chunks(4)) {
+                    *checksum = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                }
+            }
+            tables.push(table);
+        }
+        // a device replaced by a blank one knows no checksums, the other does
+        let checksums = (0..total_blocks)
+            .map(|block_id| match (tables[0][block_id], tables[1][block_id]) {
+                (sum, _) if sum != 0 && !failed[0] => sum,
+                (_, sum) if !failed[1] => sum,
+                _ => 0,
+            })
+            .collect();
+        Self {
+            devices,
+            total_blocks,
+            state: Mutex::new(MirrorState {
+                checksums,
+                failed,
+                next_read: 0,
+                repaired: 0,
+            }),
+        }
+    }
+    /// Get the number of blocks usable through a mirror of devices of
+    /// `device_blocks` blocks each
+    pub fn usable_blocks(device_blocks: usize) -> usize {
+        // every 129 blocks of a device hold 128 blocks and a block of their checksums
+        let checksum_blocks = (device_blocks + CHECKSUMS_PER_BLOCK) / (CHECKSUMS_PER_BLOCK + 1);
+        device_blocks - checksum_blocks
+    }
+    /// Get the number of blocks usable through the mirror
+    pub fn total_blocks(&self) -> usize {
+        self.total_blocks
+    }
+    /// Whether one of the devices has failed
+    pub fn degraded(&self) -> bool {
+        self.state.lock().failed.iter().any(|failed| *failed)
+    }
+    /// Get the number of bad copies rewritten from the good one so far
+    pub fn repaired(&self) -> usize {
+        self.state.lock().repaired
+    }
+    /// Check both copies of every block which has been written, repairing
+    /// the bad ones, e.g. after a blank device took the place of a broken one.
+    /// Return the number of blocks no good copy is left of.
+    pub fn resync(&self) -> usize {
+        let mut lost = 0;
+        let mut state = self.state.lock();
+        for block_id in 0..self.total_blocks {
+            let expected = state.checksums[block_id];
+            if expected == 0 {
+                continue;
+            }
+            let mut copies = [[0u8; BLOCK_SZ]; 2];
+            let mut good = [false; 2];
+            for device in 0..2 {
+                if state.failed[device] {
+                    continue;
+                }
+                if self.devices[device].try_read_block(block_id, &mut copies[device]).is_err() {
+                    state.failed[device] = true;
+                    continue;
+                }
+                good[device] = checksum(&copies[device]) == expected;
+            }
+            let source = match good.iter().position(|good| *good) {
+                Some(source) => source,
+                None => {
+                    lost += 1;
+                    continue;
+                }
+            };
+            for (device, good) in good.iter().enumerate() {
+                if !good && !state.failed[device] {
+                    self.write_device(&mut state, device, block_id, &copies[source]);
+                    state.repaired += 1;
+                }
+            }
+        }
+        lost
+    }
+    /// Store the checksum block covering `block_id` on a device
+    fn write_checksums(&self, state: &MirrorState, device: usize, block_id: usize) -> Result<(), ()> {
+        let pos = block_id / CHECKSUMS_PER_BLOCK;
+        let mut buf = [0u8; BLOCK_SZ];
+        let checksums = &state.checksums[pos * CHECKSUMS_PER_BLOCK..];
+        for (bytes, checksum) in buf.chunks_mut(4).zip(checksums) {
+            bytes.copy_from_slice(&checksum.to_le_bytes());
+        }
+        self.devices[device].try_write_block(self.total_blocks + pos, &buf)
+    }
+    /// Write a block and its checksum to a device, dropping the device on error
+    fn write_device(&self, state: &mut MirrorState, device: usize, block_id: usize, buf: &[u8]) {
+        let written = self.devices[device].try_write_block(block_id, buf).is_ok()
+            && self.write_checksums(state, device, block_id).is_ok();
+        if !written {
+            state.failed[device] = true;
+        }
+    }
+}
+
+impl BlockDevice for MirrorBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.try_read_block(block_id, buf)
+            .expect("No good copy of the block on either mirrored device!");
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.try_write_block(block_id, buf)
+            .expect("Both mirrored devices failed!");
+    }
+    fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), ()> {
+        assert!(block_id < self.total_blocks);
+        let mut state = self.state.lock();
+        let expected = state.checksums[block_id];
+        let first = state.next_read;
+        state.next_read ^= 1;
+        // copies read so far which do not match the checksum
+        let mut bad: Vec<(usize, [u8; BLOCK_SZ])> = Vec::new();
+        for device in [first, first ^ 1] {
+            if state.failed[device] {
+                continue;
+            }
+            if self.devices[device].try_read_block(block_id, buf).is_err() {
+                state.failed[device] = true;
+                continue;
+            }
+            if expected == 0 || checksum(buf) == expected {
+                for (bad_device, _) in bad {
+                    self.write_device(&mut state, bad_device, block_id, buf);
+                    state.repaired += 1;
+                }
+                return Ok(());
+            }
+            let mut data = [0u8; BLOCK_SZ];
+            data.copy_from_slice(buf);
+            bad.push((device, data));
+        }
+        // both copies agree, only the checksum did not make it to the disks
+        if bad.len() == 2 && bad[0].1 == bad[1].1 {
+            buf.copy_from_slice(&bad[0].1);
+            state.checksums[block_id] = checksum(buf);
+            for device in 0..2 {
+                self.write_device(&mut state, device, block_id, buf);
+            }
+            return Ok(());
+        }
+        Err(())
+    }
+    fn try_write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), ()> {
+        assert!(block_id < self.total_blocks);
+        let mut state = self.state.lock();
+        state.checksums[block_id] = checksum(buf);
+        for device in 0..2 {
+            if !state.failed[device] {
+                self.write_device(&mut state, device, block_id, buf);
+            }
+        }
+        if state.failed.iter().all(|failed| *failed) {
+            return Err(());
+        }
+        Ok(())
+    }
+}
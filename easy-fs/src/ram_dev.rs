@@ -0,0 +1,46 @@
+use super::{BlockDevice, BLOCK_SZ};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// A block device in memory, for tests that need no host files
+pub struct RamBlockDevice {
+    blocks: Mutex<Vec<[u8; BLOCK_SZ]>>,
+}
+
+impl RamBlockDevice {
+    /// Create a zeroed device of `total_blocks` blocks
+    pub fn new(total_blocks: usize) -> Self {
+        Self {
+            blocks: Mutex::new(vec![[0u8; BLOCK_SZ]; total_blocks]),
+        }
+    }
+    /// Create a device holding a copy of an image, whose length must be
+    /// a multiple of the block size
+    pub fn from_image(image: &[u8]) -> Self {
+        assert_eq!(image.len() % BLOCK_SZ, 0);
+        let device = Self::new(image.len() / BLOCK_SZ);
+        for (block, data) in device.blocks.lock().iter_mut().zip(image.chunks(BLOCK_SZ)) {
+            block.copy_from_slice(data);
+        }
+        device
+    }
+    /// Copy what has been written to the device so far, e.g. to mount it
+    /// again as if the machine had crashed now
+    pub fn image(&self) -> Vec<u8> {
+        self.blocks.lock().iter().flatten().copied().collect()
+    }
+    /// Get the number of blocks of the device
+    pub fn total_blocks(&self) -> usize {
+        self.blocks.lock().len()
+    }
+}
+
+impl BlockDevice for RamBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.blocks.lock()[block_id]);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.blocks.lock()[block_id].copy_from_slice(buf);
+    }
+}
@@ -1,12 +1,29 @@
 use super::{
-    block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType,
-    EasyFileSystem, DIRENT_SZ,
+    block_cache_prefetch, block_cache_sync_all, device_key, get_block_cache, BlockDevice, DirEntry, DiskInode,
+    DiskInodeType, EasyFileSystem, Fragmentation, BLOCK_SZ, DIRENT_SZ, INODE_FLAG_COMPRESSED,
+    INODE_FLAG_ENCRYPTED, INODE_FLAG_INLINE, NAME_LENGTH_LIMIT,
 };
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use lazy_static::*;
 use spin::{Mutex, MutexGuard};
 
+lazy_static! {
+    /// Number of [`Inode`] handles on each inode, by device key and inode id.
+    /// An unlinked inode is only freed once its last handle is dropped.
+    static ref OPEN_INODES: Mutex<BTreeMap<(usize, u32), usize>> = Mutex::new(BTreeMap::new());
+}
+
+/// Whether any [`Inode`] handle on an inode of a block device exists
+pub(crate) fn is_inode_open(block_device: &Arc<dyn BlockDevice>, inode_id: u32) -> bool {
+    OPEN_INODES
+        .lock()
+        .contains_key(&(device_key(block_device), inode_id))
+}
+
 /// Virtual filesystem layer over easy-fs
 pub struct Inode {
     pub inode_id: u32,
@@ -25,6 +42,10 @@ impl Inode {
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
+        *OPEN_INODES
+            .lock()
+            .entry((device_key(&block_device), inode_id))
+            .or_insert(0) += 1;
         Self {
             inode_id,
             block_id: block_id as usize,
@@ -37,10 +58,95 @@ impl Inode {
     pub fn link_cnt(&self) -> u32 {
         self.read_disk_inode(|disk_inode| disk_inode.link_cnt)
     }
+    /// Get the size of current inode in bytes
+    pub fn size(&self) -> u32 {
+        self.read_disk_inode(|disk_inode| disk_inode.size)
+    }
 
     pub fn is_dir(&self) -> bool {
         self.read_disk_inode(|disk_inode| disk_inode.is_dir())
     }
+
+    pub fn is_compressed(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_compressed())
+    }
+    /// Store the data of current file as compressed clusters from now on,
+    /// only allowed while the file is empty
+    #[allow(clippy::result_unit_err)]
+    pub fn set_compressed(&self, compressed: bool) -> Result<(), ()> {
+        let fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            if fs.is_read_only() || disk_inode.is_dir() || disk_inode.size != 0 {
+                return Err(());
+            }
+            if compressed {
+                disk_inode.flags |= INODE_FLAG_COMPRESSED;
+                disk_inode.flags &= !INODE_FLAG_INLINE;
+            } else {
+                disk_inode.flags &= !INODE_FLAG_COMPRESSED;
+                disk_inode.flags |= INODE_FLAG_INLINE;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_encrypted())
+    }
+    /// Encrypt the names and the files created under current directory from now on,
+    /// only allowed while the directory is empty and a master key is set
+    #[allow(clippy::result_unit_err)]
+    pub fn set_encrypted(&self) -> Result<(), ()> {
+        let fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            if fs.is_read_only() || !fs.has_master_key() || !disk_inode.is_dir() || disk_inode.size != 0 {
+                return Err(());
+            }
+            disk_inode.flags |= INODE_FLAG_ENCRYPTED;
+            Ok(())
+        })?;
+        fs.store_key_check();
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Get the uid of the owner of current inode
+    pub fn owner(&self) -> u32 {
+        self.read_disk_inode(|disk_inode| disk_inode.owner)
+    }
+    /// Give current inode to another user, moving its usage between their quotas.
+    /// Inodes created under a directory later belong to its owner.
+    #[allow(clippy::result_unit_err)]
+    pub fn chown(&self, uid: u32) -> Result<(), ()> {
+        let mut fs = self.fs.lock();
+        if fs.is_read_only() {
+            return Err(());
+        }
+        let (owner, blocks) = self.read_disk_inode(|disk_inode| {
+            (disk_inode.owner, disk_inode.block_ids(&self.block_device).len() as u32)
+        });
+        if owner == uid {
+            return Ok(());
+        }
+        fs.charge(uid, blocks, 1)?;
+        fs.release(owner, blocks, 1);
+        self.modify_disk_inode(|disk_inode| disk_inode.owner = uid);
+        block_cache_sync_all();
+        Ok(())
+    }
+    /// Get the filesystem current inode belongs to
+    pub fn filesystem(&self) -> Arc<Mutex<EasyFileSystem>> {
+        Arc::clone(&self.fs)
+    }
+    /// Get how scattered the blocks of current inode are
+    pub fn fragmentation(&self) -> Fragmentation {
+        self.fs.lock().fragmentation(self.inode_id)
+    }
+    /// Move the blocks of current inode into as few runs of consecutive blocks
+    /// as free space allows, return whether they were moved
+    #[allow(clippy::result_unit_err)]
+    pub fn defragment(&self) -> Result<bool, ()> {
+        self.fs.lock().defragment_inode(self.inode_id)
+    }
     /// Call a function over a disk inode to read it
     fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
         get_block_cache(self.block_id, Arc::clone(&self.block_device))
@@ -53,28 +159,81 @@ impl Inode {
             .lock()
             .modify(self.block_offset, f)
     }
+    /// Read the directory entry at `offset` of a directory disk inode with its name
+    /// decrypted, `None` past the end or if the name can not be decrypted
+    fn read_dirent(
+        &self,
+        disk_inode: &DiskInode,
+        offset: usize,
+        fs: &EasyFileSystem,
+    ) -> Option<DirEntry> {
+        let mut dirent = DirEntry::empty();
+        if disk_inode.read_at(offset, dirent.as_bytes_mut(), &self.block_device) != DIRENT_SZ {
+            return None;
+        }
+        if disk_inode.is_encrypted() {
+            fs.name_cipher(disk_inode.nonce)?
+                .apply_keystream(offset, dirent.name_bytes_mut());
+        }
+        Some(dirent)
+    }
+    /// Make the directory entry to store at `offset` of a directory disk inode,
+    /// `None` if its name has to be encrypted without a master key
+    fn new_dirent(
+        &self,
+        disk_inode: &DiskInode,
+        name: &str,
+        inode_number: u32,
+        offset: usize,
+        fs: &EasyFileSystem,
+    ) -> Option<DirEntry> {
+        let mut dirent = DirEntry::new(name, inode_number);
+        if disk_inode.is_encrypted() {
+            fs.name_cipher(disk_inode.nonce)?
+                .apply_keystream(offset, dirent.name_bytes_mut());
+        }
+        Some(dirent)
+    }
+    /// Give an encrypted directory disk inode a new nonce if it needs one before
+    /// entries are appended, encrypting the names it holds again
+    fn rekey_dirents(
+        &self,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<(), ()> {
+        if !disk_inode.is_encrypted() || !fs.needs_rekey(disk_inode.nonce) {
+            return Ok(());
+        }
+        let size = disk_inode.size as usize;
+        let entries: Vec<DirEntry> = (0..size / DIRENT_SZ)
+            .map(|i| self.read_dirent(disk_inode, i * DIRENT_SZ, fs))
+            .collect::<Option<_>>()
+            .ok_or(())?;
+        disk_inode.nonce = fs.alloc_nonce().ok_or(())?;
+        fs.unshare(disk_inode, 0, size);
+        for (i, entry) in entries.iter().enumerate() {
+            let dirent = self
+                .new_dirent(disk_inode, entry.name(), entry.inode_number(), i * DIRENT_SZ, fs)
+                .ok_or(())?;
+            disk_inode.write_at(i * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+        }
+        Ok(())
+    }
     /// Find inode under a disk inode by name
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode, fs: &EasyFileSystem) -> Option<u32> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
-            }
-        }
-        None
+        (0..file_count)
+            .filter_map(|i| self.read_dirent(disk_inode, DIRENT_SZ * i, fs))
+            .find(|dirent| dirent.name() == name)
+            .map(|dirent| dirent.inode_number())
     }
     /// Find inode under current inode by name
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
         let fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
+            self.find_inode_id(name, disk_inode, &fs).map(|inode_id| {
                 let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
                 Arc::new(Self::new(
                     inode_id,
@@ -86,35 +245,67 @@ impl Inode {
             })
         })
     }
-    /// Increase the size of a disk inode
+    /// Increase the size of a disk inode, failing without any change
+    /// if the new blocks do not fit in the quota of its owner
     fn increase_size(
         &self,
         new_size: u32,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
+    ) -> Result<(), ()> {
         if new_size < disk_inode.size {
-            return;
-        }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+            return Ok(());
         }
+        let blocks_needed = if disk_inode.is_compressed() {
+            disk_inode.index_blocks_needed(new_size)
+        } else {
+            disk_inode.blocks_num_needed(new_size)
+        };
+        fs.charge(disk_inode.owner, blocks_needed, 0)?;
+        let v = fs.alloc_data_blocks(blocks_needed as usize);
         disk_inode.increase_size(new_size, v, &self.block_device);
+        Ok(())
     }
     /// Create inode under current inode by name
     pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+    /// Create a directory under current inode by name
+    pub fn mkdir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
         let mut fs = self.fs.lock();
+        if fs.is_read_only() || name.len() > NAME_LENGTH_LIMIT {
+            return None;
+        }
+        let (exists, encrypted, owner) = self.modify_disk_inode(|root_inode| {
+            // assert it is a directory
+            assert!(root_inode.is_dir());
+            // has the file been created?
+            (
+                self.find_inode_id(name, root_inode, &fs).is_some(),
+                root_inode.is_encrypted(),
+                root_inode.owner,
+            )
+        });
+        if exists || (encrypted && !fs.has_master_key()) {
+            return None;
+        }
+        // the new inode belongs to the owner of the directory
+        fs.charge(owner, 0, 1).ok()?;
+        let file_count = self.read_disk_inode(|root_inode| root_inode.size as usize / DIRENT_SZ);
         if self
             .modify_disk_inode(|root_inode| {
-                // assert it is a directory
-                assert!(root_inode.is_dir());
-                // has the file been created?
-                self.find_inode_id(name, root_inode)
+                self.rekey_dirents(root_inode, &mut fs)?;
+                // make room for the dirent
+                let new_size = (file_count + 1) * DIRENT_SZ;
+                fs.unshare(root_inode, file_count * DIRENT_SZ, DIRENT_SZ);
+                self.increase_size(new_size as u32, root_inode, &mut fs)
             })
-            .is_some()
+            .is_err()
         {
+            fs.release(owner, 0, 1);
             return None;
         }
         // create a new file
@@ -125,16 +316,17 @@ impl Inode {
         get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
+                new_inode.initialize(type_);
+                new_inode.owner = owner;
+                if encrypted {
+                    new_inode.flags |= INODE_FLAG_ENCRYPTED;
+                }
             });
         self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
             // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
+            let dirent = self
+                .new_dirent(root_inode, name, new_inode_id, file_count * DIRENT_SZ, &fs)
+                .unwrap();
             root_inode.write_at(
                 file_count * DIRENT_SZ,
                 dirent.as_bytes(),
@@ -156,37 +348,62 @@ impl Inode {
     }
 
     pub fn link(&self, link_name: &str, file_name: &str) -> Result<(), ()> {
+        // 检查当前 inode 是否为目录, 以及是否存在 file_name 文件.
+        if !self.is_dir() {
+            return Err(());
+        }
+        let inode = self.find(file_name).ok_or(())?;
+        self.link_inode(link_name, &inode)
+    }
+    /// 在当前目录下创建指向同一文件系统中任意 inode 的硬链接 link_name
+    #[allow(clippy::result_unit_err)]
+    pub fn link_inode(&self, link_name: &str, inode: &Inode) -> Result<(), ()> {
+        if self.fs.lock().is_read_only()
+            || !Arc::ptr_eq(&self.fs, &inode.fs)
+            || link_name.len() > NAME_LENGTH_LIMIT
+        {
+            return Err(());
+        }
+        let fs = self.fs.lock();
         self.modify_disk_inode(|root_inode| {
             // 检查当前 inode 是否为目录
             if root_inode.is_file() {
                 return Err(());
             }
+            // 加密目录需要主密钥
+            if root_inode.is_encrypted() && !fs.has_master_key() {
+                return Err(());
+            }
             // 当前目录下没有与 link_name 同名的文件.
-            if self.find_inode_id(link_name, root_inode).is_some() {
+            if self.find_inode_id(link_name, root_inode, &fs).is_some() {
                 return Err(());
             };
             Ok(())
         })?;
+        drop(fs);
 
-        // 检查是否存在 file_name 文件.
-        let inode = self.find(file_name).ok_or(())?;
         let mut fs = self.fs.lock();
         // 创建目录项
         self.modify_disk_inode(|root_inode| {
+            self.rekey_dirents(root_inode, &mut fs)?;
             // append file in the dirent
             let file_count = (root_inode.size as usize) / DIRENT_SZ;
             let new_size = (file_count + 1) * DIRENT_SZ;
+            fs.unshare(root_inode, file_count * DIRENT_SZ, DIRENT_SZ);
             // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
+            self.increase_size(new_size as u32, root_inode, &mut fs)?;
             // let inode_id = self.find_inode_id(file_name, &root_inode).unwrap();
             // write dirent
-            let dirent = DirEntry::new(link_name, inode.inode_id);
+            let dirent = self
+                .new_dirent(root_inode, link_name, inode.inode_id, file_count * DIRENT_SZ, &fs)
+                .unwrap();
             root_inode.write_at(
                 file_count * DIRENT_SZ,
                 dirent.as_bytes(),
                 &self.block_device,
             );
-        });
+            Ok(())
+        })?;
 
         // 增加被链接文件的链接计数
         inode.modify_disk_inode(|disk_inode| disk_inode.link_cnt += 1);
@@ -194,7 +411,12 @@ impl Inode {
         Ok(())
     }
 
+    /// 删除目录项 path, 不能删除非空目录.
+    /// 最后一个链接被删除后, 文件的数据和 inode 在它的最后一个句柄释放时才回收
     pub fn unlink(&self, path: &str) -> Result<(), ()> {
+        if self.fs.lock().is_read_only() {
+            return Err(());
+        }
         self.modify_disk_inode(|root_inode| {
             // 检查当前 inode 是否为目录
             if root_inode.is_file() {
@@ -202,83 +424,289 @@ impl Inode {
             }
             Ok(())
         })?;
-        // 检查是否存在 path 文件.
+        // 检查是否存在 path 文件, 以及它是否为非空目录.
         let inode = self.find(path).ok_or(())?;
-        // 读出非 path 目录项
-        let entries = self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut entries = Vec::with_capacity(file_count);
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                if dirent.name() != path {
-                    entries.push(dirent)
-                }
-            }
-            entries
-        });
-        self.clear();
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir() && disk_inode.size != 0) {
+            return Err(());
+        }
+        // 读出解密后的非 path 目录项
         let mut fs = self.fs.lock();
-        // 将新的内容写回目录
+        let entries: Vec<DirEntry> = self.read_disk_inode(|disk_inode| {
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            (0..file_count)
+                .map(|i| self.read_dirent(disk_inode, i * DIRENT_SZ, &fs))
+                .filter(|dirent| dirent.as_ref().map(|dirent| dirent.name() != path) != Some(false))
+                .collect::<Option<_>>()
+                .ok_or(())
+        })?;
+        // 加密目录的目录项换到新位置, 用新的 nonce 重新加密, 避免重用密钥流
+        let nonce = if self.read_disk_inode(|disk_inode| disk_inode.is_encrypted()) {
+            Some(fs.alloc_nonce().ok_or(())?)
+        } else {
+            None
+        };
+        // 清空目录后将新的内容写回, 目录项只会变少, 新分配的块不超过释放的块, 不受配额限制
         self.modify_disk_inode(|disk_inode| {
-            let mut offset = 0;
-            self.increase_size((DIRENT_SZ * entries.len()) as u32, disk_inode, &mut fs);
-            for entry in entries {
-                disk_inode.write_at(offset, entry.as_bytes(), &self.block_device);
-                offset += DIRENT_SZ;
+            let new_size = (DIRENT_SZ * entries.len()) as u32;
+            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
+            let blocks_freed = data_blocks_dealloc.len() as u32;
+            for data_block in data_blocks_dealloc.into_iter() {
+                fs.dealloc_data(data_block);
+            }
+            let blocks_needed = disk_inode.blocks_num_needed(new_size);
+            let v = fs.alloc_data_blocks(blocks_needed as usize);
+            disk_inode.increase_size(new_size, v, &self.block_device);
+            fs.release(disk_inode.owner, blocks_freed - blocks_needed, 0);
+            if let Some(nonce) = nonce {
+                disk_inode.nonce = nonce;
+            }
+            for (i, entry) in entries.iter().enumerate() {
+                let dirent = self
+                    .new_dirent(disk_inode, entry.name(), entry.inode_number(), i * DIRENT_SZ, &fs)
+                    .unwrap();
+                disk_inode.write_at(i * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
             }
         });
+        drop(fs);
         inode.modify_disk_inode(|disk_inode| disk_inode.link_cnt -= 1);
+        // 没有其他句柄时在这里回收
+        drop(inode);
         block_cache_sync_all();
         Ok(())
     }
 
+    /// Iterate over the entries of current directory inode,
+    /// starting from the entry at byte offset `offset`.
+    /// The `next_offset` of a yielded entry can be used to resume later.
+    pub fn read_dir(&self, offset: usize) -> ReadDir<'_> {
+        ReadDir {
+            inode: self,
+            offset,
+        }
+    }
+    /// Read the directory entry at byte offset `offset`
+    fn dir_entry_at(&self, offset: usize) -> Option<ReadDirEntry> {
+        let fs = self.fs.lock();
+        let dirent = self.read_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir());
+            self.read_dirent(disk_inode, offset, &fs)
+        })?;
+        let (block_id, block_offset) = fs.get_disk_inode_pos(dirent.inode_number());
+        let type_ = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| disk_inode.inode_type());
+        Some(ReadDirEntry {
+            name: String::from(dirent.name()),
+            inode_id: dirent.inode_number(),
+            type_,
+            next_offset: offset + DIRENT_SZ,
+        })
+    }
     /// List inodes under current inode
     pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
+        let fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
             let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
-            }
-            v
+            (0..file_count)
+                .filter_map(|i| self.read_dirent(disk_inode, i * DIRENT_SZ, &fs))
+                .map(|dirent| String::from(dirent.name()))
+                .collect()
         })
     }
-    /// Read data from current inode
+    /// Read data from current inode, nothing from an encrypted file without master key
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if !(disk_inode.is_file() && disk_inode.is_encrypted()) {
+                return disk_inode.read_at(offset, buf, &self.block_device);
+            }
+            let cipher = match fs.data_cipher(disk_inode.nonce) {
+                Some(cipher) => cipher,
+                None => return 0,
+            };
+            let size = disk_inode.read_at(offset, buf, &self.block_device);
+            cipher.apply_keystream(offset, &mut buf[..size]);
+            size
+        })
+    }
+    /// Prefetch the blocks holding `[offset, offset + len)` into the block cache,
+    /// issuing one device read for each physically contiguous run.
+    /// Does nothing if the device cannot batch reads.
+    pub fn readahead(&self, offset: usize, len: usize) {
+        if !self.block_device.batched_reads() {
+            return;
+        }
         let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+        let runs = self.read_disk_inode(|disk_inode| {
+            let end = (offset + len).min(disk_inode.size as usize);
+            let mut runs: Vec<(usize, usize)> = Vec::new();
+            if offset >= end || disk_inode.is_inline() {
+                return runs;
+            }
+            for inner_id in offset / BLOCK_SZ..=(end - 1) / BLOCK_SZ {
+                let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device) as usize;
+                if block_id == 0 {
+                    // a hole of a compressed file
+                    continue;
+                }
+                match runs.last_mut() {
+                    Some((start, count)) if *start + *count == block_id => *count += 1,
+                    _ => runs.push((block_id, 1)),
+                }
+            }
+            runs
+        });
+        for (start, count) in runs {
+            block_cache_prefetch(start, count, Arc::clone(&self.block_device));
+        }
+    }
+    /// Encrypt `buf` to be written at `offset` of current encrypted file.
+    /// Appending uses the keystream past the end of the file, which was never used,
+    /// otherwise the whole file is encrypted again with a new nonce.
+    /// Return where to write the result and the new nonce if there is one,
+    /// `None` without master key.
+    fn encrypt_for_write(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        fs: &EasyFileSystem,
+    ) -> Option<(usize, Vec<u8>, Option<u32>)> {
+        let (size, nonce) =
+            self.read_disk_inode(|disk_inode| (disk_inode.size as usize, disk_inode.nonce));
+        let cipher = fs.data_cipher(nonce)?;
+        if offset >= size && !fs.needs_rekey(nonce) {
+            // the gap before `offset` has to read as zeros too
+            let mut data = vec![0u8; offset - size];
+            data.extend_from_slice(buf);
+            cipher.apply_keystream(size, &mut data);
+            return Some((size, data, None));
+        }
+        let mut data = vec![0u8; size.max(offset + buf.len())];
+        self.read_disk_inode(|disk_inode| {
+            disk_inode.read_at(0, &mut data[..size], &self.block_device)
+        });
+        cipher.apply_keystream(0, &mut data[..size]);
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        let nonce = fs.alloc_nonce()?;
+        fs.data_cipher(nonce)?.apply_keystream(0, &mut data);
+        Some((0, data, Some(nonce)))
     }
     /// Write data to current inode
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
         let mut fs = self.fs.lock();
+        if fs.is_read_only() {
+            return 0;
+        }
+        let len = buf.len();
+        let encrypted_buf: Vec<u8>;
+        let (start, buf, new_nonce) =
+            if self.read_disk_inode(|disk_inode| disk_inode.is_file() && disk_inode.is_encrypted()) {
+                let (start, data, new_nonce) = match self.encrypt_for_write(offset, buf, &fs) {
+                    Some(encrypted) => encrypted,
+                    None => return 0,
+                };
+                encrypted_buf = data;
+                (start, encrypted_buf.as_slice(), new_nonce)
+            } else {
+                (offset, buf, None)
+            };
+        // nothing is written if the new blocks do not fit in the quota
         let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
+            fs.unshare(disk_inode, start, buf.len());
+            if self.increase_size((start + buf.len()) as u32, disk_inode, &mut fs).is_err() {
+                return 0;
+            }
+            if let Some(nonce) = new_nonce {
+                disk_inode.nonce = nonce;
+            }
+            if !disk_inode.is_compressed() {
+                return disk_inode.write_at(start, buf, &self.block_device);
+            }
+            // give every touched cluster all its blocks, then return
+            // those left unused after compression
+            let holes = disk_inode.cluster_holes(start, buf.len(), &self.block_device);
+            if fs.charge(disk_inode.owner, holes as u32, 0).is_err() {
+                return 0;
+            }
+            let v = fs.alloc_data_blocks(holes);
+            disk_inode.fill_cluster_holes(start, buf.len(), v, &self.block_device);
+            let size = disk_inode.write_at(start, buf, &self.block_device);
+            let trimmed = disk_inode.trim_clusters(start, buf.len(), &self.block_device);
+            fs.release(disk_inode.owner, trimmed.len() as u32, 0);
+            for block in trimmed {
+                fs.dealloc_data(block);
+            }
+            size
         });
         block_cache_sync_all();
-        size
+        // an encrypted write may start before `offset`
+        size.saturating_sub(offset - start).min(len)
     }
     /// Clear the data in current inode
     pub fn clear(&self) {
         let mut fs = self.fs.lock();
+        if fs.is_read_only() {
+            return;
+        }
         self.modify_disk_inode(|disk_inode| {
             let size = disk_inode.size;
+            let size = if disk_inode.is_inline() { 0 } else { size };
+            let compressed = disk_inode.is_compressed();
             let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
+            assert!(compressed || data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
+            fs.release(disk_inode.owner, data_blocks_dealloc.len() as u32, 0);
             for data_block in data_blocks_dealloc.into_iter() {
                 fs.dealloc_data(data_block);
             }
+            // data written later must not reuse the keystream
+            disk_inode.nonce = 0;
         });
         block_cache_sync_all();
     }
 }
+
+impl Drop for Inode {
+    /// Free the data and the inode once the last handle on an unlinked inode is dropped
+    fn drop(&mut self) {
+        let key = (device_key(&self.block_device), self.inode_id);
+        let last = {
+            let mut open_inodes = OPEN_INODES.lock();
+            let count = open_inodes.get_mut(&key).unwrap();
+            *count -= 1;
+            *count == 0 && open_inodes.remove(&key).is_some()
+        };
+        if !last || self.fs.lock().is_read_only() || self.link_cnt() != 0 {
+            return;
+        }
+        self.clear();
+        let mut fs = self.fs.lock();
+        let owner = self.read_disk_inode(|disk_inode| disk_inode.owner);
+        fs.dealloc_inode(self.inode_id);
+        fs.release(owner, 0, 1);
+        block_cache_sync_all();
+    }
+}
+
+/// A directory entry yielded by [`Inode::read_dir`]
+pub struct ReadDirEntry {
+    pub name: String,
+    pub inode_id: u32,
+    pub type_: DiskInodeType,
+    /// Offset of the entry following this one
+    pub next_offset: usize,
+}
+
+/// Iterator over the entries of a directory
+pub struct ReadDir<'a> {
+    inode: &'a Inode,
+    offset: usize,
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = ReadDirEntry;
+    fn next(&mut self) -> Option<ReadDirEntry> {
+        let entry = self.inode.dir_entry_at(self.offset)?;
+        self.offset = entry.next_offset;
+        Some(entry)
+    }
+}
@@ -5,12 +5,15 @@ pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const CLOCK_FREQ: usize = 12500000;
 pub const MMIO: &[(usize, usize)] = &[
-    (0x10001000, 0x1000),
+    // virtio-mmio devices 0-7
+    (0x10001000, 0x8000),
 ];
 
 // kernel space config
 pub const KERNEL_STACK_PAGE_NUM: usize = 15;
 pub const KERNEL_STACK_SIZE: usize = PAGE_SIZE * KERNEL_STACK_PAGE_NUM;
+/// 同时存在的进程 (包括僵尸进程) 数的上限, 也是内核栈槽位数
+pub const MAX_TASK_NUM: usize = 128;
 pub const KERNEL_HEAP_SIZE: usize = PAGE_SIZE * 4096;
 pub const MEMORY_END: usize = 0x88000000;
 
@@ -18,11 +21,17 @@ pub const MEMORY_END: usize = 0x88000000;
 pub const MAX_SYSCALL_NUM: usize = 500;
 #[allow(dead_code)]
 pub const BIG_STRIDE: usize = 500000;
+/// 每个进程最多同时打开的文件描述符数
+pub const MAX_FD_NUM: usize = 64;
 
 // user space config
 pub const USER_STACK_PAGE_NUM: usize = 20;
 pub const USER_STACK_SIZE: usize = 4096 * USER_STACK_PAGE_NUM;
+/// exec 和 spawn 的参数与环境变量在用户栈上占用的最大字节数
+pub const ARG_MAX: usize = 4096 * 4;
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
+/// 用户程序的段和栈只能放在 Sv39 地址空间的低半部分
+pub const USER_SPACE_END: usize = 1 << 38;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
 
 
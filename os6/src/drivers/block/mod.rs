@@ -1,14 +1,40 @@
 mod virtio_blk;
 
 use alloc::sync::Arc;
-use easy_fs::BlockDevice;
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, MirrorBlockDevice};
 use lazy_static::*;
 type BlockDeviceImpl = virtio_blk::VirtIOBlock;
 
 lazy_static! {
+    /// 所有 virtio 块设备, 按地址顺序
+    static ref DISKS: Vec<Arc<BlockDeviceImpl>> = {
+        let disks: Vec<_> = BlockDeviceImpl::probe_all().into_iter().map(Arc::new).collect();
+        log::info!("{} virtio block devices found", disks.len());
+        disks
+    };
+    /// 只有一个磁盘时直接使用它, 有两个或更多时前两个组成 RAID-1,
+    /// 一个磁盘坏掉之后文件系统仍然可用
     pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = {
         log::info!("BLOCK_DEVICE init...");
-        Arc::new(BlockDeviceImpl::new())};
+        match DISKS.len() {
+            0 => panic!("No virtio block device!"),
+            1 => DISKS[0].clone(),
+            _ => {
+                let blocks = DISKS[0].total_blocks().min(DISKS[1].total_blocks());
+                Arc::new(MirrorBlockDevice::new(DISKS[0].clone(), DISKS[1].clone(), blocks))
+            }
+        }
+    };
+}
+
+/// `BLOCK_DEVICE` 现在的块数, 重新从磁盘读取. RAID-1 的校验和放在磁盘末尾,
+/// 不能在挂载时扩容, 这时返回 None
+pub fn block_device_blocks() -> Option<usize> {
+    match DISKS.len() {
+        1 => Some(DISKS[0].total_blocks()),
+        _ => None,
+    }
 }
 
 #[allow(unused)]
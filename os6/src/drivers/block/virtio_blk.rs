@@ -5,13 +5,26 @@ use crate::mm::{
 };
 use crate::sync::UPSafeCell;
 use alloc::vec::Vec;
+use core::ptr::read_volatile;
 use lazy_static::*;
 use virtio_drivers::{VirtIOBlk, VirtIOHeader};
 
-#[allow(unused)]
+/// QEMU virt 机器上的 8 个 virtio-mmio 设备, 第一个在 VIRTIO0, 依次相隔 VIRTIO_STRIDE
 const VIRTIO0: usize = 0x10001000;
+const VIRTIO_SLOTS: usize = 8;
+const VIRTIO_STRIDE: usize = 0x1000;
+/// virtio-mmio 寄存器的偏移, 以及块设备配置空间里以 512 字节扇区计的容量的偏移
+const MAGIC_VALUE_OFFSET: usize = 0x000;
+const DEVICE_ID_OFFSET: usize = 0x008;
+const CAPACITY_OFFSET: usize = 0x100;
+const MAGIC_VALUE: u32 = 0x74726976;
+const DEVICE_ID_BLOCK: u32 = 2;
 
-pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static>>);
+pub struct VirtIOBlock {
+    blk: UPSafeCell<VirtIOBlk<'static>>,
+    /// virtio-mmio 寄存器的基址
+    base: usize,
+}
 
 lazy_static! {
     static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameTracker>> = unsafe { UPSafeCell::new(Vec::new()) };
@@ -19,28 +32,62 @@ lazy_static! {
 
 impl BlockDevice for VirtIOBlock {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        self.0
+        self.blk
             .exclusive_access()
             .read_block(block_id, buf)
             .expect("Error when reading VirtIOBlk");
     }
     fn write_block(&self, block_id: usize, buf: &[u8]) {
-        self.0
+        self.blk
             .exclusive_access()
             .write_block(block_id, buf)
             .expect("Error when writing VirtIOBlk");
     }
+    /// 磁盘出错 (比如在 QEMU monitor 里被 drive_del) 时返回错误而不是 panic
+    fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), ()> {
+        self.blk
+            .exclusive_access()
+            .read_block(block_id, buf)
+            .map_err(|_| ())
+    }
+    fn try_write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), ()> {
+        self.blk
+            .exclusive_access()
+            .write_block(block_id, buf)
+            .map_err(|_| ())
+    }
 }
 
 impl VirtIOBlock {
     #[allow(unused)]
     pub fn new() -> Self {
+        Self::probe(VIRTIO0).expect("No virtio block device at VIRTIO0")
+    }
+    /// 初始化 virtio-mmio 地址 `base` 处的块设备, 那里没有块设备时返回 None
+    pub fn probe(base: usize) -> Option<Self> {
+        let read_reg = |offset: usize| unsafe { read_volatile((base + offset) as *const u32) };
+        if read_reg(MAGIC_VALUE_OFFSET) != MAGIC_VALUE || read_reg(DEVICE_ID_OFFSET) != DEVICE_ID_BLOCK {
+            return None;
+        }
         unsafe {
-            Self(UPSafeCell::new(
-                VirtIOBlk::new(&mut *(VIRTIO0 as *mut VirtIOHeader)).unwrap(),
-            ))
+            let blk = VirtIOBlk::new(&mut *(base as *mut VirtIOHeader)).ok()?;
+            Some(Self {
+                blk: UPSafeCell::new(blk),
+                base,
+            })
         }
     }
+    /// 按地址顺序初始化所有 virtio-mmio 块设备, 即 QEMU 的 virtio-mmio-bus.0, 1, ...
+    pub fn probe_all() -> Vec<Self> {
+        (0..VIRTIO_SLOTS)
+            .filter_map(|i| Self::probe(VIRTIO0 + i * VIRTIO_STRIDE))
+            .collect()
+    }
+    /// 磁盘的块数, 每次都从配置空间重新读取, 磁盘在 QEMU monitor 里
+    /// 被 block_resize 扩容之后就能看到新的容量
+    pub fn total_blocks(&self) -> usize {
+        unsafe { read_volatile((self.base + CAPACITY_OFFSET) as *const u64) as usize }
+    }
 }
 
 #[no_mangle]
@@ -1,3 +1,3 @@
 mod block;
 
-pub use block::BLOCK_DEVICE;
+pub use block::{block_device_blocks, BLOCK_DEVICE};
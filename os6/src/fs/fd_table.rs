@@ -0,0 +1,97 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{File, Stdin, Stdout};
+use crate::config::MAX_FD_NUM;
+
+#[derive(Clone)]
+struct FdEntry {
+    file: Arc<dyn File>,
+    /// exec 时是否关闭
+    cloexec: bool,
+}
+
+/// 进程的文件描述符表.
+///
+/// 新描述符总是取最小的空闲编号, 关闭描述符只留下空位, 不会改变其他描述符的编号.
+#[derive(Clone)]
+pub struct FdTable {
+    entries: Vec<Option<FdEntry>>,
+}
+
+impl FdTable {
+    /// 只打开了标准输入 (0), 标准输出 (1) 和标准错误 (2) 的描述符表
+    pub fn new() -> Self {
+        let mut table = Self {
+            entries: Vec::new(),
+        };
+        table.alloc(Arc::new(Stdin), false).unwrap();
+        table.alloc(Arc::new(Stdout), false).unwrap();
+        table.alloc(Arc::new(Stdout), false).unwrap();
+        table
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<dyn File>> {
+        match self.entries.get(fd) {
+            Some(Some(entry)) => Some(Arc::clone(&entry.file)),
+            _ => None,
+        }
+    }
+
+    /// 把文件放到最小的空闲描述符上并返回它, 描述符用完时失败
+    pub fn alloc(&mut self, file: Arc<dyn File>, cloexec: bool) -> Result<usize, ()> {
+        let fd = match self.entries.iter().position(|entry| entry.is_none()) {
+            Some(fd) => fd,
+            None if self.entries.len() < MAX_FD_NUM => {
+                self.entries.push(None);
+                self.entries.len() - 1
+            }
+            None => return Err(()),
+        };
+        self.entries[fd] = Some(FdEntry { file, cloexec });
+        Ok(fd)
+    }
+
+    /// 把文件放到指定的描述符上, 原来打开的文件被关闭, 描述符超过上限时失败
+    pub fn install(&mut self, fd: usize, file: Arc<dyn File>, cloexec: bool) -> Result<(), ()> {
+        if fd >= MAX_FD_NUM {
+            return Err(());
+        }
+        if fd >= self.entries.len() {
+            self.entries.resize(fd + 1, None);
+        }
+        self.entries[fd] = Some(FdEntry { file, cloexec });
+        Ok(())
+    }
+
+    /// 关闭描述符, 它没有打开文件时失败
+    pub fn close(&mut self, fd: usize) -> Result<(), ()> {
+        match self.entries.get_mut(fd) {
+            Some(entry @ Some(_)) => *entry = None,
+            _ => return Err(()),
+        }
+        self.shrink();
+        Ok(())
+    }
+
+    /// 关闭所有描述符, 进程退出时调用
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// 关闭所有设置了 close-on-exec 的描述符
+    pub fn close_on_exec(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if let Some(FdEntry { cloexec: true, .. }) = entry {
+                *entry = None;
+            }
+        }
+        self.shrink();
+    }
+
+    /// 去掉末尾的空位
+    fn shrink(&mut self) {
+        while let Some(None) = self.entries.last() {
+            self.entries.pop();
+        }
+    }
+}
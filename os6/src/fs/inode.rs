@@ -1,8 +1,8 @@
-use alloc::{sync::Arc, vec::Vec};
-use easy_fs::{EasyFileSystem, Inode};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use easy_fs::{DiskInodeType, EasyFileSystem, Inode};
 use lazy_static::lazy_static;
 
-use super::File;
+use super::{File, SeekFrom};
 use crate::{drivers::BLOCK_DEVICE, mm::UserBuffer, UPSafeCell};
 
 lazy_static! {
@@ -18,6 +18,10 @@ lazy_static! {
 pub struct OSInode {
     readable: bool,
     writable: bool,
+    /// 每次写入前把偏移移到文件末尾
+    append: bool,
+    /// 打开时的规范绝对路径
+    path: String,
     inner: UPSafeCell<OSInodeInner>,
 }
 
@@ -31,6 +35,8 @@ impl OSInode {
         Self {
             readable,
             writable,
+            append: false,
+            path: String::new(),
             inner: unsafe { UPSafeCell::new(OSInodeInner { offset: 0, inode }) },
         }
     }
@@ -73,12 +79,18 @@ impl File for OSInode {
 
     fn write(&self, buf: UserBuffer) -> usize {
         let mut inner = self.inner.exclusive_access();
+        if self.append {
+            inner.offset = inner.inode.size() as usize;
+        }
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
+            // 超出配额, 只读或者没有密钥时只写入一部分, 甚至一个字节也写不进去
             let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
             inner.offset += write_size;
             total_write_size += write_size;
+            if write_size < slice.len() {
+                break;
+            }
         }
         total_write_size
     }
@@ -86,8 +98,90 @@ impl File for OSInode {
     fn inode(&self) -> Option<Arc<Inode>> {
         Some(self.inner.exclusive_access().inode.clone())
     }
+
+    fn path(&self) -> Option<String> {
+        Some(self.path.clone())
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Option<usize> {
+        let mut inner = self.inner.exclusive_access();
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as isize),
+            SeekFrom::Current(offset) => (inner.offset, offset),
+            SeekFrom::End(offset) => (inner.inode.size() as usize, offset),
+        };
+        let offset = base as isize + offset;
+        if offset < 0 {
+            return None;
+        }
+        inner.offset = offset as usize;
+        Some(inner.offset)
+    }
+
+    fn pread(&self, mut buf: UserBuffer, offset: usize) -> Option<usize> {
+        let inode = Arc::clone(&self.inner.exclusive_access().inode);
+        let mut total_read_size = 0usize;
+        for slice in buf.buffers.iter_mut() {
+            let read_size = inode.read_at(offset + total_read_size, *slice);
+            total_read_size += read_size;
+            if read_size < slice.len() {
+                break;
+            }
+        }
+        Some(total_read_size)
+    }
+
+    fn pwrite(&self, buf: UserBuffer, offset: usize) -> Option<usize> {
+        let inode = Arc::clone(&self.inner.exclusive_access().inode);
+        let mut total_write_size = 0usize;
+        for slice in buf.buffers.iter() {
+            let write_size = inode.write_at(offset + total_write_size, *slice);
+            total_write_size += write_size;
+            if write_size < slice.len() {
+                break;
+            }
+        }
+        Some(total_write_size)
+    }
+
+    fn getdents(&self, limit: usize) -> Option<Vec<u8>> {
+        let mut inner = self.inner.exclusive_access();
+        if !inner.inode.is_dir() {
+            return None;
+        }
+        let inode = Arc::clone(&inner.inode);
+        let mut v: Vec<u8> = Vec::new();
+        for entry in inode.read_dir(inner.offset) {
+            // d_ino + d_off + d_reclen + d_type + d_name + '\0', 按 8 字节对齐
+            let reclen = (8 + 8 + 2 + 1 + entry.name.len() + 1 + 7) & !7;
+            if v.len() + reclen > limit {
+                break;
+            }
+            let d_type = match entry.type_ {
+                DiskInodeType::Directory => DT_DIR,
+                DiskInodeType::File => DT_REG,
+            };
+            let start = v.len();
+            v.extend_from_slice(&(entry.inode_id as u64).to_ne_bytes());
+            v.extend_from_slice(&(entry.next_offset as i64).to_ne_bytes());
+            v.extend_from_slice(&(reclen as u16).to_ne_bytes());
+            v.push(d_type);
+            v.extend_from_slice(entry.name.as_bytes());
+            v.resize(start + reclen, 0);
+            inner.offset = entry.next_offset;
+        }
+        if v.is_empty() && inode.read_dir(inner.offset).next().is_some() {
+            return None;
+        }
+        Some(v)
+    }
 }
 
+/// linux_dirent64 中的目录类型
+const DT_DIR: u8 = 4;
+/// linux_dirent64 中的普通文件类型
+const DT_REG: u8 = 8;
+
 bitflags! {
     pub struct OpenFlags: u32 {
         const RDONLY = 0;
@@ -95,14 +189,17 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10; // 截断
+        const APPEND = 1 << 11; // 每次写入都追加到文件末尾
+        const CLOEXEC = 1 << 19; // exec 时关闭
     }
 }
 
 impl OpenFlags {
     pub fn read_write(&self) -> (bool, bool) {
-        if self.is_empty() {
+        let flags = *self - Self::APPEND - Self::CLOEXEC;
+        if flags.is_empty() {
             (true, false)
-        } else if self.contains(Self::WRONLY) {
+        } else if flags.contains(Self::WRONLY) {
             (false, true)
         } else {
             (true, true)
@@ -110,33 +207,88 @@ impl OpenFlags {
     }
 }
 
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+/// 把 path 解析为规范的绝对路径: 绝对路径从根目录开始, 相对路径从绝对路径 `base`
+/// 开始, 去掉 "." 和多余的 '/', ".." 回到上一级 (根目录的上一级仍是根目录).
+/// 目录中没有 "." 和 ".." 目录项, 所以只能这样按字面处理.
+pub fn absolute_path(base: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { base };
+    let mut components: Vec<&str> = Vec::new();
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    let mut abs_path = String::from("/");
+    abs_path.push_str(&components.join("/"));
+    abs_path
+}
+
+/// 从根目录沿规范的绝对路径查找 inode
+pub fn find_path(abs_path: &str) -> Option<Arc<Inode>> {
+    let mut inode = Arc::clone(&ROOT_INODE);
+    for name in abs_path.split('/').filter(|name| !name.is_empty()) {
+        if !inode.is_dir() {
+            return None;
+        }
+        inode = inode.find(name)?;
+    }
+    Some(inode)
+}
+
+/// 查找规范的绝对路径的父目录, 返回它和路径的最后一个分量. 根目录没有父目录.
+fn find_parent(abs_path: &str) -> Option<(Arc<Inode>, &str)> {
+    let (parent, name) = abs_path.rsplit_once('/')?;
+    if name.is_empty() {
+        return None;
+    }
+    let dir = find_path(parent)?;
+    if !dir.is_dir() {
+        return None;
+    }
+    Some((dir, name))
+}
+
+/// 打开规范的绝对路径 `path` 上的文件
+pub fn open_file(path: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
     let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(readable, writable, inode)))
-        } else {
-            // create file
-            ROOT_INODE
-                .create(name)
-                .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
-        }
-    } else {
-        ROOT_INODE.find(name).map(|inode| {
-            if flags.contains(OpenFlags::TRUNC) {
+    let inode = match find_path(path) {
+        Some(inode) => {
+            // CREATE 时清空已有的文件, 追加写时保留原有内容
+            let clear = flags.contains(OpenFlags::TRUNC)
+                || flags.contains(OpenFlags::CREATE) && !flags.contains(OpenFlags::APPEND);
+            if clear && !inode.is_dir() {
                 inode.clear();
             }
-            Arc::new(OSInode::new(readable, writable, inode))
-        })
-    }
+            inode
+        }
+        None if flags.contains(OpenFlags::CREATE) => {
+            let (dir, name) = find_parent(path)?;
+            dir.create(name)?
+        }
+        None => return None,
+    };
+    let mut file = OSInode::new(readable, writable, inode);
+    file.append = flags.contains(OpenFlags::APPEND);
+    file.path = String::from(path);
+    Some(Arc::new(file))
 }
 
+/// 为 oldpath 上的文件创建硬链接 newpath, 都是规范的绝对路径
 pub fn link_at(newpath: &str, oldpath: &str) -> Result<(), ()> {
-    ROOT_INODE.link(&newpath, &oldpath)
+    let inode = find_path(oldpath).ok_or(())?;
+    if inode.is_dir() {
+        return Err(());
+    }
+    let (dir, name) = find_parent(newpath).ok_or(())?;
+    dir.link_inode(name, &inode)
 }
 
+/// 删除规范的绝对路径 path 对应的目录项
 pub fn unlink_at(path: &str) -> Result<(), ()> {
-    ROOT_INODE.unlink(path)
+    let (dir, name) = find_parent(path).ok_or(())?;
+    dir.unlink(name)
 }
@@ -1,11 +1,15 @@
+mod fd_table;
 mod inode;
+mod pipe;
 mod stdio;
 
 use crate::mm::UserBuffer;
-use alloc::sync::Arc;
+use alloc::{string::String, sync::Arc, vec::Vec};
 use easy_fs::Inode;
-pub use inode::{link_at, open_file, unlink_at, OSInode, OSInodeInner, OpenFlags, ROOT_INODE};
-pub use stdio::{Stdin, Stdout};
+pub use fd_table::FdTable;
+pub use pipe::make_pipe;
+pub use inode::{absolute_path, find_path, link_at, open_file, unlink_at, OSInode, OSInodeInner, OpenFlags, ROOT_INODE};
+pub use stdio::{has_stdin_readers, poll_stdin, Stdin, Stdout};
 
 pub trait File: Send + Sync {
     fn readable(&self) -> bool;
@@ -15,4 +19,33 @@ pub trait File: Send + Sync {
     fn inode(&self) -> Option<Arc<Inode>> {
         None
     }
+    /// 打开文件时的规范绝对路径, 不在文件系统中的文件返回 None
+    fn path(&self) -> Option<String> {
+        None
+    }
+    /// 从当前偏移读取目录项, 编码为总长不超过 `limit` 的 linux_dirent64 记录.
+    /// 不是目录, 或者缓冲区连一条记录都放不下时返回 None.
+    fn getdents(&self, _limit: usize) -> Option<Vec<u8>> {
+        None
+    }
+    /// 移动读写偏移, 返回新的偏移. 不支持定位, 或者新偏移为负数时返回 None.
+    fn seek(&self, _pos: SeekFrom) -> Option<usize> {
+        None
+    }
+    /// 从 `offset` 处读取, 不改变读写偏移. 不支持定位时返回 None.
+    fn pread(&self, _buf: UserBuffer, _offset: usize) -> Option<usize> {
+        None
+    }
+    /// 写到 `offset` 处, 不改变读写偏移. 不支持定位时返回 None.
+    fn pwrite(&self, _buf: UserBuffer, _offset: usize) -> Option<usize> {
+        None
+    }
+}
+
+/// 定位的基准和偏移, 对应 lseek 的 SEEK_SET, SEEK_CUR 和 SEEK_END
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
 }
@@ -0,0 +1,142 @@
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+};
+
+use super::File;
+use crate::{mm::UserBuffer, sync::UPSafeCell, task::WaitQueue};
+
+/// 管道缓冲区的容量, 不超过它的写入是原子的
+const PIPE_BUF_SIZE: usize = 4096;
+
+/// 读端和写端共享的环形缓冲区
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    /// 所有读端都关闭后无法升级
+    read_end: Weak<Pipe>,
+    /// 所有写端都关闭后无法升级
+    write_end: Weak<Pipe>,
+    /// 等待数据或者写端关闭的任务
+    readers: WaitQueue,
+    /// 等待空间或者读端关闭的任务
+    writers: WaitQueue,
+}
+
+/// 管道的一端. dup 和 fork 复制的是同一个 `Arc<Pipe>`,
+/// 它的所有引用都释放后这一端才算关闭.
+pub struct Pipe {
+    readable: bool,
+    writable: bool,
+    buffer: Arc<UPSafeCell<PipeBuffer>>,
+}
+
+/// 创建管道, 返回 (读端, 写端)
+pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
+    let buffer = Arc::new(unsafe {
+        UPSafeCell::new(PipeBuffer {
+            data: VecDeque::with_capacity(PIPE_BUF_SIZE),
+            read_end: Weak::new(),
+            write_end: Weak::new(),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+        })
+    });
+    let read_end = Arc::new(Pipe {
+        readable: true,
+        writable: false,
+        buffer: Arc::clone(&buffer),
+    });
+    let write_end = Arc::new(Pipe {
+        readable: false,
+        writable: true,
+        buffer: Arc::clone(&buffer),
+    });
+    {
+        let mut buffer = buffer.exclusive_access();
+        buffer.read_end = Arc::downgrade(&read_end);
+        buffer.write_end = Arc::downgrade(&write_end);
+    }
+    (read_end, write_end)
+}
+
+impl File for Pipe {
+    fn readable(&self) -> bool {
+        self.readable
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// 读出缓冲区中已有的数据. 缓冲区为空时阻塞, 直到有数据写入
+    /// 或者所有写端都关闭 (此时返回 0 表示文件结束).
+    fn read(&self, mut buf: UserBuffer) -> usize {
+        if !self.readable {
+            return 0;
+        }
+        let mut buffer = self.buffer.exclusive_access();
+        if buffer.data.is_empty() {
+            if buffer.write_end.upgrade().is_some() {
+                buffer.readers.wait();
+            }
+            return 0;
+        }
+        let mut read_size = 0usize;
+        'copy: for slice in buf.buffers.iter_mut() {
+            for byte in slice.iter_mut() {
+                match buffer.data.pop_front() {
+                    Some(data) => *byte = data,
+                    None => break 'copy,
+                }
+                read_size += 1;
+            }
+        }
+        buffer.writers.wake_all();
+        read_size
+    }
+
+    /// 写入缓冲区放得下的部分, 没有全部写完时阻塞, 由 sys_write 在重新执行时写剩下的.
+    /// 不超过缓冲区容量的写入要么一次写完, 要么一个字节都不写.
+    /// 所有读端都关闭后返回 0.
+    fn write(&self, buf: UserBuffer) -> usize {
+        if !self.writable {
+            return 0;
+        }
+        let mut buffer = self.buffer.exclusive_access();
+        if buffer.read_end.upgrade().is_none() {
+            return 0;
+        }
+        let len = buf.len();
+        let space = PIPE_BUF_SIZE - buffer.data.len();
+        if len <= PIPE_BUF_SIZE && len > space {
+            buffer.writers.wait();
+            return 0;
+        }
+        let write_size = len.min(space);
+        let mut remaining = write_size;
+        for slice in buf.buffers.iter() {
+            let n = slice.len().min(remaining);
+            buffer.data.extend(slice[..n].iter());
+            remaining -= n;
+        }
+        if write_size > 0 {
+            buffer.readers.wake_all();
+        }
+        if write_size < len {
+            buffer.writers.wait();
+        }
+        write_size
+    }
+}
+
+impl Drop for Pipe {
+    /// 一端关闭后唤醒另一端等待的任务, 它们会看到文件结束或者写入失败
+    fn drop(&mut self) {
+        let mut buffer = self.buffer.exclusive_access();
+        if self.readable {
+            buffer.writers.wake_all();
+        }
+        if self.writable {
+            buffer.readers.wake_all();
+        }
+    }
+}
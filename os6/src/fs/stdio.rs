@@ -1,26 +1,66 @@
-use crate::{mm::UserBuffer, sbi::console_getchar, task::{pop_cur_task, switch_task}};
+use alloc::collections::VecDeque;
+use lazy_static::lazy_static;
+
+use crate::{mm::UserBuffer, sbi::console_getchar, sync::UPSafeCell, task::WaitQueue};
 
 use super::File;
 
 pub struct Stdin;
 pub struct Stdout;
 
+/// 已经从控制台读到, 还没有被读走的输入
+struct StdinBuffer {
+    data: VecDeque<u8>,
+    /// 等待输入的任务
+    readers: WaitQueue,
+}
+
+lazy_static! {
+    static ref STDIN_BUFFER: UPSafeCell<StdinBuffer> = unsafe {
+        UPSafeCell::new(StdinBuffer {
+            data: VecDeque::new(),
+            readers: WaitQueue::new(),
+        })
+    };
+}
+
+/// 是否有任务在等待控制台输入
+pub fn has_stdin_readers() -> bool {
+    STDIN_BUFFER.exclusive_access().readers.has_waiters()
+}
+
+/// 把控制台上的输入读进缓冲区, 读到输入时唤醒等待的任务.
+/// 控制台没有中断, 所以在时钟中断和空闲时轮询.
+pub fn poll_stdin() {
+    let mut buffer = STDIN_BUFFER.exclusive_access();
+    let len = buffer.data.len();
+    loop {
+        let c = console_getchar();
+        if c == 0 || c == usize::MAX {
+            break;
+        }
+        buffer.data.push_back(c as u8);
+    }
+    if buffer.data.len() > len {
+        buffer.readers.wake_all();
+    }
+}
+
 impl File for Stdin {
     fn readable(&self) -> bool { true }
     fn writable(&self) -> bool { false }
     fn read(&self, mut user_buf: UserBuffer) -> usize {
         assert_eq!(user_buf.len(), 1);
-        // busy loop
-        let c: usize;
-        loop {
-            c = console_getchar();
-            if c == 0 {
-                switch_task(pop_cur_task().unwrap());
-            } else {
-                break;
+        poll_stdin();
+        let mut buffer = STDIN_BUFFER.exclusive_access();
+        // 没有输入时阻塞, 有输入后重新读
+        let ch = match buffer.data.pop_front() {
+            Some(ch) => ch,
+            None => {
+                buffer.readers.wait();
+                return 0;
             }
-        }
-        let ch = c as u8;
+        };
         unsafe { user_buf.buffers[0].as_mut_ptr().write_volatile(ch); }
         1
     }
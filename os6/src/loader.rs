@@ -1,7 +1,7 @@
-use alloc::vec::Vec;
+use alloc::{borrow::ToOwned, string::String, vec, vec::Vec};
 use lazy_static::lazy_static;
 
-use crate::fs::{open_file, File, OpenFlags, ROOT_INODE};
+use crate::fs::{absolute_path, open_file, File, OpenFlags, ROOT_INODE};
 
 pub fn get_num_app() -> usize {
     extern "C" {
@@ -77,9 +77,69 @@ pub fn list_efs_apps() {
 }
 
 pub fn efs_get_app_elf(name: &str) -> Result<Vec<u8>, ()> {
-    let all_data = match open_file(name, OpenFlags::RDONLY) {
-        Some(app_inode) => app_inode.read_all(),
-        None => panic!("wrong app name? {}", name),
+    // 相对路径从根目录开始
+    let app_inode = match open_file(&absolute_path("/", name), OpenFlags::RDONLY) {
+        Some(app_inode) => app_inode,
+        None => {
+            log::warn!("wrong app name? {}", name);
+            return Err(());
+        }
+    };
+    if app_inode.inode().map_or(true, |inode| inode.is_dir()) {
+        log::warn!("{} is a directory", name);
+        return Err(());
+    }
+    Ok(app_inode.read_all())
+}
+
+/// 解释器脚本最多嵌套的层数
+const MAX_INTERP_DEPTH: usize = 4;
+/// "#!" 行的最大长度
+const MAX_INTERP_LINE: usize = 256;
+
+/// 读出要执行的程序和它的参数. 以 "#!interpreter arg" 开头的脚本换成解释器,
+/// 参数变为解释器, 可选的 arg, 脚本路径和原来除 argv[0] 以外的参数.
+/// 解释器的相对路径从根目录开始, 它本身也可以是脚本, 最多嵌套 `MAX_INTERP_DEPTH` 层.
+pub fn efs_get_exec_elf(name: &str, args: &[String]) -> Result<(Vec<u8>, Vec<String>), ()> {
+    let mut path = name.to_owned();
+    let mut args = args.to_vec();
+    for _ in 0..=MAX_INTERP_DEPTH {
+        let data = efs_get_app_elf(&path)?;
+        let (interp, interp_arg) = match parse_interp_line(&data)? {
+            Some(line) => line,
+            None => return Ok((data, args)),
+        };
+        let mut new_args = vec![interp.clone()];
+        new_args.extend(interp_arg);
+        new_args.push(path);
+        new_args.extend(args.into_iter().skip(1));
+        path = interp;
+        args = new_args;
+    }
+    log::warn!("{}, too many levels of interpreters", name);
+    Err(())
+}
+
+/// 解析脚本的 "#!" 行, 返回解释器和可选的一个参数, 不是脚本时返回 None.
+/// 行太长, 不是 UTF-8 或者没有解释器时失败.
+fn parse_interp_line(data: &[u8]) -> Result<Option<(String, Option<String>)>, ()> {
+    let rest = match data.strip_prefix(b"#!") {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    let len = rest.iter().position(|&c| c == b'\n').unwrap_or(rest.len());
+    if len > MAX_INTERP_LINE {
+        log::warn!("interpreter line is too long");
+        return Err(());
+    }
+    let line = core::str::from_utf8(&rest[..len]).map_err(|_| ())?.trim();
+    if line.is_empty() {
+        return Err(());
+    }
+    // 和 Linux 一样, 解释器之后的部分整体作为一个参数
+    let line = match line.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((interp, arg)) => (String::from(interp), Some(String::from(arg.trim()))),
+        None => (String::from(line), None),
     };
-    Ok(all_data)
+    Ok(Some(line))
 }
@@ -5,7 +5,9 @@ use super::{
     StepByOne, VPNRange, VirtAddr, VirtPageNum,
 };
 use crate::{
-    config::{MEMORY_END, MMIO, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE},
+    config::{
+        MEMORY_END, MMIO, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_SPACE_END, USER_STACK_SIZE,
+    },
     sync::UPSafeCell,
     task::PidHandle,
 };
@@ -106,7 +108,7 @@ impl MemorySet {
     }
     pub fn unmap_area(
         &mut self,
-        task_pid: PidHandle,
+        task_pid: &PidHandle,
         start_va: VirtAddr,
         end_va: VirtAddr,
     ) -> Result<(), ()> {
@@ -220,15 +222,15 @@ impl MemorySet {
     }
     /// Include sections in elf and trampoline and TrapContext and user stack,
     /// also returns user_sp and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
+    /// 为 ELF 程序建立用户地址空间, 返回它, 用户栈栈顶和入口地址.
+    /// 程序不能载入时失败, 此时没有分配任何资源.
+    pub fn from_elf(elf_data: &[u8]) -> Result<(Self, usize, usize), ()> {
+        let elf = check_elf(elf_data).map_err(|reason| log::warn!("invalid elf, {}", reason))?;
         let mut memory_set = Self::new_bare();
         // map trampoline
         memory_set.map_trampoline();
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
         let elf_header = elf.header;
-        let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
         let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
         // parse elf
@@ -249,7 +251,7 @@ impl MemorySet {
                     map_perm |= MapPermission::X;
                 }
                 let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
-                max_end_vpn = map_area.vpn_range.get_end();
+                max_end_vpn = max_end_vpn.max(map_area.vpn_range.get_end());
                 memory_set.push(
                     map_area,
                     Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
@@ -284,7 +286,7 @@ impl MemorySet {
 
         let entrypoint = elf.header.pt2.entry_point();
         log::debug!("parse elf, entrypoint={}", entrypoint);
-        (memory_set, user_stack_top, entrypoint as usize)
+        Ok((memory_set, user_stack_top, entrypoint as usize))
     }
 
     pub fn activate(&self) {
@@ -299,6 +301,67 @@ impl MemorySet {
     }
 }
 
+/// ELF 文件头中的 64 位文件类别
+const ELFCLASS64: u8 = 2;
+/// ELF 文件头中的小端数据编码
+const ELFDATA2LSB: u8 = 1;
+/// ELF 文件头中的可执行文件类型
+const ET_EXEC: u16 = 2;
+/// ELF 文件头中的 RISC-V 机器类型
+const EM_RISCV: u16 = 0xf3;
+
+/// 检查 ELF 文件能否载入用户地址空间: 它是 64 位小端的 RISC-V 可执行文件,
+/// 可载入的段互不重叠, 内容在文件之内, 连同其后的保护页和用户栈都在用户地址范围之内,
+/// 入口位于可执行的段中. 失败时返回原因.
+fn check_elf(elf_data: &[u8]) -> Result<xmas_elf::ElfFile<'_>, &'static str> {
+    if elf_data.len() < 64 || elf_data[..4] != [0x7f, 0x45, 0x4c, 0x46] {
+        return Err("bad magic");
+    }
+    if elf_data[4] != ELFCLASS64 || elf_data[5] != ELFDATA2LSB {
+        return Err("not a 64-bit little-endian file");
+    }
+    let e_type = u16::from_le_bytes([elf_data[16], elf_data[17]]);
+    let e_machine = u16::from_le_bytes([elf_data[18], elf_data[19]]);
+    if e_type != ET_EXEC || e_machine != EM_RISCV {
+        return Err("not a RISC-V executable");
+    }
+    let elf = xmas_elf::ElfFile::new(elf_data)?;
+    let entry = elf.header.pt2.entry_point() as usize;
+    let mut entry_executable = false;
+    // 各个段占用的页 [start, end)
+    let mut pages: Vec<(usize, usize)> = Vec::new();
+    for i in 0..elf.header.pt2.ph_count() {
+        let ph = elf.program_header(i)?;
+        if ph.get_type()? != xmas_elf::program::Type::Load {
+            continue;
+        }
+        let (start, mem_size) = (ph.virtual_addr() as usize, ph.mem_size() as usize);
+        let (offset, file_size) = (ph.offset() as usize, ph.file_size() as usize);
+        let end = start.checked_add(mem_size).ok_or("segment out of range")?;
+        if end > USER_SPACE_END - PAGE_SIZE - USER_STACK_SIZE {
+            return Err("segment out of range");
+        }
+        let in_file = offset
+            .checked_add(file_size)
+            .map_or(false, |end| end <= elf_data.len());
+        if file_size > mem_size || !in_file {
+            return Err("segment out of file");
+        }
+        if ph.flags().is_execute() && (start..end).contains(&entry) {
+            entry_executable = true;
+        }
+        pages.push((start / PAGE_SIZE, (end + PAGE_SIZE - 1) / PAGE_SIZE));
+    }
+    pages.sort();
+    if pages.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+        return Err("overlapping segments");
+    }
+    if !entry_executable {
+        return Err("entry point not in an executable segment");
+    }
+    Ok(elf)
+}
+
 /// map area structure, controls a contiguous piece of virtual memory
 pub struct MapArea {
     vpn_range: VPNRange,
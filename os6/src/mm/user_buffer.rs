@@ -16,4 +16,17 @@ impl UserBuffer {
         }
         total
     }
+    /// 把 `data` 拷贝到用户缓冲区开头, 返回实际拷贝的字节数
+    pub fn write_bytes(&mut self, data: &[u8]) -> usize {
+        let mut copied = 0usize;
+        for buffer in self.buffers.iter_mut() {
+            if copied == data.len() {
+                break;
+            }
+            let len = buffer.len().min(data.len() - copied);
+            buffer[..len].copy_from_slice(&data[copied..copied + len]);
+            copied += len;
+        }
+        copied
+    }
 }
\ No newline at end of file
@@ -1,7 +1,15 @@
-use alloc::sync::{Arc, Weak};
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+};
+use easy_fs::{Inode, QuotaEntry, KEY_SZ};
 
 use crate::{
-    fs::{link_at, open_file, unlink_at, OpenFlags, ROOT_INODE},
+    drivers::block_device_blocks,
+    fs::{
+        absolute_path, find_path, link_at, make_pipe, open_file, unlink_at, File, OpenFlags,
+        SeekFrom, ROOT_INODE,
+    },
     mm::UserBuffer,
     task::Task,
 };
@@ -15,21 +23,36 @@ pub fn sys_write(task: &Weak<Task>, fd: usize, buf: usize, len: usize) -> Syscal
     let task = Task::from_weak(&task);
     let buffer = UserBuffer::new(translated_byte_buffer(&task, buf, len));
     let inner = task.inner_exclusive_access();
-    let file = if let Some(Some(file)) = inner.fd_table.get(fd) {
-        Arc::clone(file)
+    let file = if let Some(file) = inner.fd_table.get(fd) {
+        file
     } else {
         log::error!("{}, sys_write, user pass a bad fd? fd={}", task, fd);
         return Err(());
     };
     drop(inner);
-    Ok(file.write(buffer) as isize)
+    let written = file.write(buffer);
+    let mut inner = task.inner_exclusive_access();
+    if inner.restart_syscall {
+        // 阻塞前写入的部分不再重写, 重新执行时只写剩下的
+        inner.written_before_block += written;
+        let trap_ctx = inner.trap_context();
+        trap_ctx.set_reg_a(1, buf + written);
+        trap_ctx.set_reg_a(2, len - written);
+        return Ok(0);
+    }
+    let written = written + core::mem::take(&mut inner.written_before_block);
+    if written == 0 && len > 0 {
+        // 一个字节都写不进去, 例如管道已经没有读端
+        return Err(());
+    }
+    Ok(written as isize)
 }
 
 pub fn sys_read(task: Arc<Task>, fd: usize, buf: usize, len: usize) -> SyscallResult {
     let buffer = UserBuffer::new(translated_byte_buffer(&task, buf, len));
     let inner = task.inner_exclusive_access();
-    let file = if let Some(Some(file)) = inner.fd_table.get(fd) {
-        Arc::clone(file)
+    let file = if let Some(file) = inner.fd_table.get(fd) {
+        file
     } else {
         log::error!("{}, sys_read, user pass a bad fd? fd={}", task, fd);
         return Err(());
@@ -38,6 +61,148 @@ pub fn sys_read(task: Arc<Task>, fd: usize, buf: usize, len: usize) -> SyscallRe
     Ok(file.read(buffer) as isize)
 }
 
+/// 取 fd 对应的文件, fd 没有打开文件时失败
+fn fd_file(task: &Arc<Task>, fd: usize) -> Result<Arc<dyn File>, ()> {
+    let file = task.inner_exclusive_access().fd_table.get(fd);
+    file.ok_or_else(|| log::error!("{}, fd_file, user pass a bad fd? fd={}", task, fd))
+}
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+/// 按 whence 移动 fd 的读写偏移, 返回新的偏移
+pub fn sys_lseek(task: &Weak<Task>, fd: usize, offset: isize, whence: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as usize),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(()),
+    };
+    let offset = fd_file(&task, fd)?.seek(pos).ok_or(())?;
+    Ok(offset as isize)
+}
+
+/// 从 fd 的 `offset` 处读取, 不改变读写偏移
+pub fn sys_pread64(task: &Weak<Task>, fd: usize, buf: usize, len: usize, offset: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let file = fd_file(&task, fd)?;
+    if !file.readable() {
+        return Err(());
+    }
+    let buffer = UserBuffer::new(translated_byte_buffer(&task, buf, len));
+    let read = file.pread(buffer, offset).ok_or(())?;
+    Ok(read as isize)
+}
+
+/// 写到 fd 的 `offset` 处, 不改变读写偏移, 追加写打开的文件也一样
+pub fn sys_pwrite64(task: &Weak<Task>, fd: usize, buf: usize, len: usize, offset: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let file = fd_file(&task, fd)?;
+    if !file.writable() {
+        return Err(());
+    }
+    let buffer = UserBuffer::new(translated_byte_buffer(&task, buf, len));
+    let written = file.pwrite(buffer, offset).ok_or(())?;
+    Ok(written as isize)
+}
+
+pub fn sys_getdents64(task: &Weak<Task>, fd: usize, buf: usize, len: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let inner = task.inner_exclusive_access();
+    let file = if let Some(file) = inner.fd_table.get(fd) {
+        file
+    } else {
+        log::error!("{}, sys_getdents64, user pass a bad fd? fd={}", task, fd);
+        return Err(());
+    };
+    drop(inner);
+    let dirents = file.getdents(len).ok_or(())?;
+    let mut buffer = UserBuffer::new(translated_byte_buffer(&task, buf, len));
+    Ok(buffer.write_bytes(&dirents) as isize)
+}
+
+/// 设置 easy-fs 的主密钥, 调用者没有特权或者密钥与已加密的文件不符时失败
+pub fn sys_fs_set_key(task: &Weak<Task>, key: usize, len: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    if !task.inner_exclusive_access().privileged || len != KEY_SZ {
+        return Err(());
+    }
+    let mut master_key = [0u8; KEY_SZ];
+    let mut copied = 0;
+    for buffer in translated_byte_buffer(&task, key, len) {
+        master_key[copied..copied + buffer.len()].copy_from_slice(buffer);
+        copied += buffer.len();
+    }
+    ROOT_INODE.filesystem().lock().set_master_key(master_key)?;
+    Ok(0)
+}
+
+/// 将各用户的磁盘用量写入用户的 `QuotaEntry` 数组, 最多 `count` 项, 返回用户总数
+pub fn sys_quota_report(task: &Weak<Task>, buf: usize, count: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let quotas = ROOT_INODE.filesystem().lock().quotas();
+    let entry_size = core::mem::size_of::<QuotaEntry>();
+    let len = quotas.len().min(count) * entry_size;
+    let bytes = unsafe { core::slice::from_raw_parts(quotas.as_ptr() as *const u8, len) };
+    let mut buffer = UserBuffer::new(translated_byte_buffer(&task, buf, len));
+    buffer.write_bytes(bytes);
+    Ok(quotas.len() as isize)
+}
+
+/// 取 fd 对应的 easy-fs inode, fd 不对应文件时失败
+fn fd_inode(task: &Arc<Task>, fd: usize) -> Result<Arc<Inode>, ()> {
+    fd_file(task, fd)?.inode().ok_or(())
+}
+
+/// 返回 fd 对应文件的碎片化程度 (0~100), fd 为 -1 时返回整个文件系统的
+pub fn sys_frag_score(task: &Weak<Task>, fd: isize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let fragmentation = if fd == -1 {
+        ROOT_INODE.filesystem().lock().volume_fragmentation()
+    } else {
+        fd_inode(&task, fd as usize)?.fragmentation()
+    };
+    Ok(fragmentation.score() as isize)
+}
+
+/// 整理 fd 对应文件的碎片, fd 为 -1 时整理并紧缩整个文件系统, 这要重写所有文件,
+/// 只有特权进程可以做. 返回整理之后的碎片化程度
+pub fn sys_defrag(task: &Weak<Task>, fd: isize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    if fd == -1 {
+        if !task.inner_exclusive_access().privileged {
+            return Err(());
+        }
+        let mut fs = ROOT_INODE.filesystem().lock();
+        let moved = fs.defragment()?;
+        log::info!("{}, sys_defrag, {} files moved", task, moved);
+        return Ok(fs.volume_fragmentation().score() as isize);
+    }
+    let inode = fd_inode(&task, fd as usize)?;
+    inode.defragment()?;
+    Ok(inode.fragmentation().score() as isize)
+}
+
+/// 磁盘在挂载时扩容之后, 让文件系统用上新增的块. 重新读取磁盘容量,
+/// 比文件系统大时在文件系统锁内扩容; 调用者没有特权或者磁盘不能扩容时失败.
+/// 返回文件系统现在的块数
+pub fn sys_fs_grow(task: &Weak<Task>) -> SyscallResult {
+    let task = Task::from_weak(task);
+    if !task.inner_exclusive_access().privileged {
+        return Err(());
+    }
+    let device_blocks = block_device_blocks().ok_or(())?.min(u32::MAX as usize) as u32;
+    let mut fs = ROOT_INODE.filesystem().lock();
+    let total_blocks = fs.total_blocks();
+    if device_blocks > total_blocks {
+        fs.grow(device_blocks)?;
+        log::info!("{}, sys_fs_grow, {} -> {} blocks", task, total_blocks, device_blocks);
+    }
+    Ok(fs.total_blocks() as isize)
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Stat {
@@ -67,31 +232,12 @@ pub fn sys_fstat(task: &Weak<Task>, fd: i32, st_user: usize) -> SyscallResult {
     let task = Task::from_weak(&task);
     let stat = from_user_ptr(&task, st_user);
     let (ino, mode, nlink) = {
-        /*
-         * 这里需要考虑两种情况:
-         * 1. fd 超过目前 fd_table 的长度, 可能是因为还没有打开目标文件
-         * 2. fd 在 fd_table 里, 但是为 None, 可能是因为目标文件已经被关闭了.
-         */
-        let target_file = {
-            let fd_table = &task.inner_exclusive_access().fd_table;
-            let len = fd_table.len() as i32;
-            if len <= fd {
-                log::warn!(
-                    "{}, sys_fstat, fd={} fd_table len={}, didn't have target file?",
-                    task,
-                    fd,
-                    len
-                );
-                return Err(());
-            }
-            if let Some(file) = fd_table.get(fd as usize).unwrap() {
-                Arc::clone(file)
-            } else {
-                log::warn!(
-                    "{}, sys_fstat, fd={}, has file slot but is None, file has been close?",
-                    task,
-                    fd,
-                );
+        // fd 为负数, 超过描述符表的长度, 或者对应的文件已经被关闭
+        let target_file = task.inner_exclusive_access().fd_table.get(fd as usize);
+        let target_file = match target_file {
+            Some(file) if fd >= 0 => file,
+            _ => {
+                log::warn!("{}, sys_fstat, fd={}, didn't have target file?", task, fd);
                 return Err(());
             }
         };
@@ -128,6 +274,33 @@ pub fn sys_fstat(task: &Weak<Task>, fd: i32, st_user: usize) -> SyscallResult {
     Ok(0)
 }
 
+/// *at 系统调用中表示当前工作目录的 dirfd
+const AT_FDCWD: i32 = -100;
+
+/// 把 *at 系统调用的 dirfd 和 path 解析为规范的绝对路径.
+/// 绝对路径忽略 dirfd, 相对路径从 dirfd 为 AT_FDCWD 时的当前工作目录,
+/// 或者 dirfd 打开的目录开始.
+fn resolve_at(task: &Arc<Task>, dirfd: i32, path: &str) -> Result<String, ()> {
+    if path.starts_with('/') {
+        return Ok(absolute_path("/", path));
+    }
+    if dirfd == AT_FDCWD {
+        let cwd_path = task.inner_exclusive_access().cwd_path.clone();
+        return Ok(absolute_path(&cwd_path, path));
+    }
+    if dirfd < 0 {
+        return Err(());
+    }
+    let dir = fd_file(task, dirfd as usize)?;
+    match (dir.inode(), dir.path()) {
+        (Some(inode), Some(dir_path)) if inode.is_dir() => Ok(absolute_path(&dir_path, path)),
+        _ => {
+            log::warn!("{}, resolve_at, dirfd={} is not a directory", task, dirfd);
+            Err(())
+        }
+    }
+}
+
 pub fn sys_link_at(
     task: &Weak<Task>,
     olddirfd: i32,
@@ -136,38 +309,45 @@ pub fn sys_link_at(
     newpath: usize,
     flags: u32,
 ) -> SyscallResult {
-    (drop(olddirfd), drop(newdirfd), drop(flags));
+    drop(flags);
     let task = Task::from_weak(task);
     let (oldpath, newpath) = (
         from_user_cstring(&task, oldpath),
         from_user_cstring(&task, newpath),
     );
+    let oldpath = resolve_at(&task, olddirfd, &oldpath)?;
+    let newpath = resolve_at(&task, newdirfd, &newpath)?;
 
     link_at(&newpath, &oldpath).map(|_| 0)
 }
 
 pub fn sys_unlink_at(task: &Weak<Task>, dirfd: i32, path: usize, flags: u32) -> SyscallResult {
-    (drop(dirfd), drop(flags));
+    drop(flags);
     let task = Task::from_weak(task);
     let path = from_user_cstring(&task, path);
+    let path = resolve_at(&task, dirfd, &path)?;
     unlink_at(&path).map(|_| 0)
 }
 
 pub fn sys_open_at(
     task: &Weak<Task>,
-    _at_fdcwd: usize,
+    dirfd: i32,
     path: usize,
     flags: u32,
     _mode: u32,
 ) -> SyscallResult {
     let task = Task::from_weak(task);
     let path = from_user_cstring(&task, path);
-    let file = open_file(&path, OpenFlags::from_bits(flags).ok_or(())?);
+    let path = resolve_at(&task, dirfd, &path)?;
+    let flags = OpenFlags::from_bits(flags).ok_or(())?;
+    let file = open_file(&path, flags);
     match file {
         Some(file) => {
             let mut inner = task.inner_exclusive_access();
-            inner.fd_table.push(Some(file));
-            Ok(inner.fd_table.len() as isize - 1)
+            let fd = inner
+                .fd_table
+                .alloc(file, flags.contains(OpenFlags::CLOEXEC))?;
+            Ok(fd as isize)
         }
         None => {
             log::warn!("{}, sys_open_at, wrong path? path={}", task, path);
@@ -176,11 +356,100 @@ pub fn sys_open_at(
     }
 }
 
+/// 切换到规范的绝对路径 path 上的目录
+fn change_dir(task: &Arc<Task>, path: String) -> SyscallResult {
+    let inode = find_path(&path).ok_or(())?;
+    if !inode.is_dir() {
+        return Err(());
+    }
+    let mut inner = task.inner_exclusive_access();
+    inner.cwd = inode;
+    inner.cwd_path = path;
+    Ok(0)
+}
+
+/// 把当前工作目录切换到 path
+pub fn sys_chdir(task: &Weak<Task>, path: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let path = from_user_cstring(&task, path);
+    let path = resolve_at(&task, AT_FDCWD, &path)?;
+    change_dir(&task, path)
+}
+
+/// 把当前工作目录切换到 fd 打开的目录
+pub fn sys_fchdir(task: &Weak<Task>, fd: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let path = fd_file(&task, fd)?.path().ok_or(())?;
+    change_dir(&task, path)
+}
+
+/// 把当前工作目录的绝对路径以 '\0' 结尾写入用户缓冲区, 返回写入的长度,
+/// 缓冲区放不下时失败
+pub fn sys_getcwd(task: &Weak<Task>, buf: usize, len: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let mut cwd_path = task.inner_exclusive_access().cwd_path.clone();
+    cwd_path.push('\0');
+    if cwd_path.len() > len {
+        return Err(());
+    }
+    let mut buffer = UserBuffer::new(translated_byte_buffer(&task, buf, cwd_path.len()));
+    Ok(buffer.write_bytes(cwd_path.as_bytes()) as isize)
+}
+
 pub fn sys_close(task: &Weak<Task>, fd: usize) -> SyscallResult {
     let task = Task::from_weak(&task);
     let mut inner = task.inner_exclusive_access();
-    match inner.fd_table.remove(fd) {
-        Some(_) => Ok(0),
-        None => Err(()),
+    inner.fd_table.close(fd).map(|_| 0)
+}
+
+/// 复制 fd 到最小的空闲描述符上, 新描述符不带 close-on-exec
+pub fn sys_dup(task: &Weak<Task>, fd: usize) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let mut inner = task.inner_exclusive_access();
+    let file = inner.fd_table.get(fd).ok_or(())?;
+    let new_fd = inner.fd_table.alloc(file, false)?;
+    Ok(new_fd as isize)
+}
+
+/// 复制 oldfd 到 newfd 上, newfd 原来打开的文件被关闭.
+/// flags 只能为 0 或 O_CLOEXEC, oldfd 与 newfd 相同时失败.
+pub fn sys_dup3(task: &Weak<Task>, oldfd: usize, newfd: usize, flags: u32) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let flags = OpenFlags::from_bits(flags).ok_or(())?;
+    if oldfd == newfd || !(flags - OpenFlags::CLOEXEC).is_empty() {
+        return Err(());
     }
+    let mut inner = task.inner_exclusive_access();
+    let file = inner.fd_table.get(oldfd).ok_or(())?;
+    inner
+        .fd_table
+        .install(newfd, file, flags.contains(OpenFlags::CLOEXEC))?;
+    Ok(newfd as isize)
+}
+
+/// 创建管道, 读端和写端的描述符依次写入用户的 `[usize; 2]` 数组.
+/// flags 只能为 0 或 O_CLOEXEC.
+pub fn sys_pipe(task: &Weak<Task>, pipe: usize, flags: u32) -> SyscallResult {
+    let task = Task::from_weak(task);
+    let flags = OpenFlags::from_bits(flags).ok_or(())?;
+    if !(flags - OpenFlags::CLOEXEC).is_empty() {
+        return Err(());
+    }
+    let cloexec = flags.contains(OpenFlags::CLOEXEC);
+    let (read_end, write_end) = make_pipe();
+    let fds = {
+        let mut inner = task.inner_exclusive_access();
+        let read_fd = inner.fd_table.alloc(read_end, cloexec)?;
+        match inner.fd_table.alloc(write_end, cloexec) {
+            Ok(write_fd) => [read_fd, write_fd],
+            Err(_) => {
+                inner.fd_table.close(read_fd)?;
+                return Err(());
+            }
+        }
+    };
+    let len = core::mem::size_of_val(&fds);
+    let bytes = unsafe { core::slice::from_raw_parts(fds.as_ptr() as *const u8, len) };
+    UserBuffer::new(translated_byte_buffer(&task, pipe, len)).write_bytes(bytes);
+    Ok(0)
 }
@@ -70,6 +70,6 @@ pub fn sys_unmmap(task: &Weak<Task>, start: usize, len: usize) -> SyscallResult
     let mut inner = task.inner_exclusive_access();
     inner
         .addr_space
-        .unmap_area(task.pid.clone(), start, end)
+        .unmap_area(&task.pid, start, end)
         .map(|_| 0)
 }
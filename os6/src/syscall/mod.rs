@@ -8,26 +8,39 @@ use alloc::{string::ToString, sync::Weak};
 pub use crate::syscall::proc::sys_exit;
 use crate::{
     syscall::{
-        fs::{sys_close, sys_fstat, sys_link_at, sys_open_at, sys_read, sys_unlink_at, sys_write},
+        fs::{
+            sys_chdir, sys_close, sys_defrag, sys_dup, sys_dup3, sys_fchdir, sys_frag_score, sys_fs_grow, sys_fs_set_key, sys_fstat, sys_getcwd, sys_getdents64, sys_quota_report, sys_link_at, sys_lseek, sys_open_at, sys_pipe, sys_pread64, sys_pwrite64, sys_read,
+            sys_unlink_at, sys_write,
+        },
         mm::{sys_mmap, sys_unmmap},
         proc::{
-            sys_exec, sys_fork, sys_getpid, sys_gettimeofday, sys_set_priority, sys_spawn,
-            sys_taskinfo, sys_waitpid, sys_yield,
+            sys_exec, sys_fork, sys_getpid, sys_gettimeofday, sys_nanosleep, sys_set_priority,
+            sys_sleep, sys_spawn, sys_taskinfo, sys_waitpid, sys_yield,
         },
     },
-    task::{pop_cur_task, Task},
+    task::{pop_cur_task, suspend_task, Task, TaskState},
 };
 
 #[derive(Debug)]
 enum Syscall {
+    GetCwd,       //17
+    Dup,          //24
     UnLinkAt,     //35
     LinkAt,       //37
+    ChDir,        //49
+    FChDir,       //50
     OpenAt,       //56
     Close,        //57
+    Pipe,         //59
+    GetDents64,   //61
+    LSeek,        //62
     Read,         //63
     Write,        //64
+    PRead64,      //67
+    PWrite64,     //68
     FStat,        //80
     Exit,         //93
+    Sleep,        //101
     Yield,        //124
     SetPriority,  //140
     GetTimeOfDay, //169
@@ -39,19 +52,36 @@ enum Syscall {
     WaitPid,      //260
     Spawn,        //400
     TaskInfo,     //410
+    FsSetKey,     //420
+    QuotaReport,  //421
+    FragScore,    //422
+    Defrag,       //423
+    Dup3,         //424
+    FsGrow,       //425
+    NanoSleep,    //426
 }
 
 impl Syscall {
     fn from(n: usize) -> Result<Syscall, ()> {
         Ok(match n {
+            17 => Self::GetCwd,        // 0x11
+            24 => Self::Dup,           // 0x18
             35 => Self::UnLinkAt,      // 0x23
             37 => Self::LinkAt,        // 0x25
+            49 => Self::ChDir,         // 0x31
+            50 => Self::FChDir,        // 0x32
             56 => Self::OpenAt,        // 0x38
             57 => Self::Close,         // 0x39
+            59 => Self::Pipe,          // 0x3b
+            61 => Self::GetDents64,    // 0x3d
+            62 => Self::LSeek,         // 0x3e
             63 => Self::Read,          // 0x3f
             64 => Self::Write,         // 0x40
+            67 => Self::PRead64,       // 0x43
+            68 => Self::PWrite64,      // 0x44
             80 => Self::FStat,         // 0x50
             93 => Self::Exit,          // 0x5d
+            101 => Self::Sleep,        // 0x65
             124 => Self::Yield,        // 0x7c
             140 => Self::SetPriority,  // 0x8c
             169 => Self::GetTimeOfDay, // 0xa9
@@ -63,6 +93,13 @@ impl Syscall {
             260 => Self::WaitPid,      // 0x104
             400 => Self::Spawn,        // 0x190
             410 => Self::TaskInfo,     // 0x19a
+            420 => Self::FsSetKey,     // 0x1a4
+            421 => Self::QuotaReport,  // 0x1a5
+            422 => Self::FragScore,    // 0x1a6
+            423 => Self::Defrag,       // 0x1a7
+            424 => Self::Dup3,         // 0x1a8
+            425 => Self::FsGrow,       // 0x1a9
+            426 => Self::NanoSleep,    // 0x1aa
             _ => {
                 log::warn!("unsupported syscall: {}", n.to_string());
                 panic!("unsupported syscall");
@@ -75,31 +112,34 @@ impl Syscall {
 type SyscallResult = Result<isize, ()>;
 
 impl Syscall {
-    fn handle(&self, task: &Weak<Task>, arg1: usize, arg2: usize, arg3: usize) {
+    /// 执行系统调用, 返回它是否需要阻塞
+    fn handle(&self, task: &Weak<Task>, arg1: usize, arg2: usize, arg3: usize) -> bool {
         let ret: SyscallResult = match self {
             Syscall::Write => sys_write(task, arg1, arg2, arg3),
             Syscall::Exit => sys_exit(Task::from_weak(&task), arg1 as i32),
             Syscall::GetTimeOfDay => sys_gettimeofday(task, arg1, arg2),
+            Syscall::Sleep => sys_sleep(task, arg1),
+            Syscall::NanoSleep => sys_nanosleep(task, arg1, arg2),
             Syscall::Yield => sys_yield(Task::from_weak(&task)),
             Syscall::TaskInfo => sys_taskinfo(task, arg1),
             Syscall::Mmap => sys_mmap(task, arg1, arg2, arg3),
             Syscall::Munmap => sys_unmmap(task, arg1, arg2),
             Syscall::Fork => sys_fork(task),
-            Syscall::WaitPid => sys_waitpid(task, arg1 as isize, arg2),
+            Syscall::WaitPid => sys_waitpid(task, arg1 as isize, arg2, arg3),
             Syscall::GetPid => sys_getpid(task),
             Syscall::Read => sys_read(task.upgrade().unwrap(), arg1, arg2, arg3),
             Syscall::SetPriority => sys_set_priority(task, arg1 as isize),
-            Syscall::Exec => sys_exec(task, arg1),
-            Syscall::Spawn => sys_spawn(task, arg1),
-            Syscall::UnLinkAt => sys_unlink_at(task, -100, arg2, 0),
+            Syscall::Exec => sys_exec(task, arg1, arg2, arg3),
+            Syscall::Spawn => sys_spawn(task, arg1, arg2, arg3),
+            Syscall::UnLinkAt => sys_unlink_at(task, arg1 as i32, arg2, arg3 as u32),
             Syscall::LinkAt => {
-                let (arg4, _) = {
+                let (arg4, arg5) = {
                     let task = Task::from_weak(task);
                     let inner = task.inner_exclusive_access();
                     let trapctx = inner.trap_context();
                     (trapctx.reg_a(3), trapctx.reg_a(4))
                 };
-                sys_link_at(task, -100, arg2, -100 as i32, arg4, 0)
+                sys_link_at(task, arg1 as i32, arg2, arg3 as i32, arg4, arg5 as u32)
             }
             Syscall::FStat => sys_fstat(task, arg1 as i32, arg2),
             Syscall::OpenAt => {
@@ -108,19 +148,49 @@ impl Syscall {
                     .trap_context()
                     .reg_a(3);
 
-                sys_open_at(task, arg1, arg2, arg3 as u32, arg4 as u32)
+                sys_open_at(task, arg1 as i32, arg2, arg3 as u32, arg4 as u32)
             }
             Syscall::Close => sys_close(task, arg1),
+            Syscall::GetCwd => sys_getcwd(task, arg1, arg2),
+            Syscall::ChDir => sys_chdir(task, arg1),
+            Syscall::FChDir => sys_fchdir(task, arg1),
+            Syscall::Dup => sys_dup(task, arg1),
+            Syscall::Dup3 => sys_dup3(task, arg1, arg2, arg3 as u32),
+            Syscall::Pipe => sys_pipe(task, arg1, arg2 as u32),
+            Syscall::GetDents64 => sys_getdents64(task, arg1, arg2, arg3),
+            Syscall::LSeek => sys_lseek(task, arg1, arg2 as isize, arg3),
+            Syscall::PRead64 | Syscall::PWrite64 => {
+                let arg4 = Task::from_weak(task)
+                    .inner_exclusive_access()
+                    .trap_context()
+                    .reg_a(3);
+                if let Syscall::PRead64 = self {
+                    sys_pread64(task, arg1, arg2, arg3, arg4)
+                } else {
+                    sys_pwrite64(task, arg1, arg2, arg3, arg4)
+                }
+            }
+            Syscall::FsSetKey => sys_fs_set_key(task, arg1, arg2),
+            Syscall::QuotaReport => sys_quota_report(task, arg1, arg2),
+            Syscall::FragScore => sys_frag_score(task, arg1 as isize),
+            Syscall::Defrag => sys_defrag(task, arg1 as isize),
+            Syscall::FsGrow => sys_fs_grow(task),
             // _ => todo!("unsupported syscall handle function, syscall={:?}", self),
         };
 
         let ret = ret.unwrap_or(-1);
         let task = Task::from_weak(&task);
-        let a0 = {
-            let inner = task.inner_exclusive_access();
+        let (blocked, a0) = {
+            let mut inner = task.inner_exclusive_access();
+            let blocked = core::mem::take(&mut inner.restart_syscall);
             let trap_ctx = inner.trap_context();
-            trap_ctx.set_reg_a(0, ret as usize);
-            trap_ctx.reg_a(0)
+            if blocked {
+                // 回到 ecall 重新执行, a0 仍然是第一个参数
+                trap_ctx.sepc -= 4;
+            } else {
+                trap_ctx.set_reg_a(0, ret as usize);
+            }
+            (blocked, trap_ctx.reg_a(0))
         };
 
         log::info!(
@@ -129,6 +199,7 @@ impl Syscall {
             ret,
             a0
         );
+        blocked
     }
 }
 
@@ -161,5 +232,14 @@ pub fn syscall_handler(weak_task: &Weak<Task>) {
         );
     }
     // log::info!("syscall_times={:?}", ctx.syscall_times);
-    syscall.handle(weak_task, a0, a1, a2)
+    if syscall.handle(weak_task, a0, a1, a2) {
+        // 系统调用的局部变量都已经释放, 可以让出处理器了
+        let task = pop_cur_task().unwrap();
+        let blocked = task.inner_exclusive_access().state == TaskState::Blocked;
+        if blocked {
+            suspend_task(task)
+        } else {
+            sys_yield(task)
+        }
+    }
 }
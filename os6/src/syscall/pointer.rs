@@ -49,6 +49,53 @@ pub fn from_user_cstring(task: &Arc<Task>, user_addr: usize) -> String {
     s
 }
 
+/// 读取用户以 '\0' 结尾的字符串, 每个字节都经过页表翻译.
+/// 地址无效, 或者字符串连同 '\0' 超过 `budget` 字节时失败, 成功时从 `budget` 中扣除读取的字节数
+pub fn from_user_cstring_within(task: &Arc<Task>, user_addr: usize, budget: &mut usize) -> Result<String, ()> {
+    let mut s = String::new();
+    loop {
+        *budget = budget.checked_sub(1).ok_or(())?;
+        let phy_addr = match task.inner_exclusive_access().translate(user_addr + s.len()) {
+            Some(addr) => addr,
+            None => {
+                log::warn!("{}, receive bad user addr? user_addr=0x{:x}", task, user_addr + s.len());
+                return Err(());
+            }
+        };
+        let val = unsafe { *(phy_addr as *const u8) };
+        if val == 0 {
+            return Ok(s);
+        }
+        s.push(val as char);
+    }
+}
+
+/// 读取用户以空指针结尾的字符串指针数组, 例如 argv 和 envp. 数组地址为 0 时视为空数组.
+/// 指针和字符串边读边从 `budget` 中扣除, 地址无效或者超过 `budget` 字节时失败
+pub fn from_user_cstring_array(task: &Arc<Task>, user_addr: usize, budget: &mut usize) -> Result<Vec<String>, ()> {
+    let mut strings = Vec::new();
+    if user_addr == 0 {
+        return Ok(strings);
+    }
+    let word = core::mem::size_of::<usize>();
+    loop {
+        *budget = budget.checked_sub(word).ok_or(())?;
+        let ptr_addr = user_addr + strings.len() * word;
+        let phy_addr = match task.inner_exclusive_access().translate(ptr_addr) {
+            Some(addr) if ptr_addr % word == 0 => addr,
+            _ => {
+                log::warn!("{}, receive bad user array? user_addr=0x{:x}", task, ptr_addr);
+                return Err(());
+            }
+        };
+        let ptr = unsafe { *(phy_addr as *const usize) };
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        strings.push(from_user_cstring_within(task, ptr, budget)?);
+    }
+}
+
 /// translate a pointer to a mutable u8 Vec through page table
 pub fn translated_byte_buffer(task: &Arc<Task>, ptr: usize, len: usize) -> Vec<&'static mut [u8]> {
     let mut start = ptr as usize;
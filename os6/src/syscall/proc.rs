@@ -1,13 +1,19 @@
 use alloc::{
+    string::String,
     sync::{Arc, Weak},
     vec::Vec,
 };
 
 use crate::{
-    config::MAX_SYSCALL_NUM,
-    syscall::pointer::{from_user_cstring, from_user_ptr},
-    task::{add_task, fork_task, run_next_task, switch_task, Task, TaskState},
-    timer::{self, get_time_ms},
+    config::{ARG_MAX, MAX_SYSCALL_NUM},
+    fs::absolute_path,
+    mm::{MemorySet, PhysPageNum},
+    syscall::pointer::{from_user_cstring_array, from_user_cstring_within, from_user_ptr},
+    task::{
+        add_task, fork_task, reparent_to_initproc, run_next_task, switch_task, wait_cur_task,
+        wake_task, Task, TaskState,
+    },
+    timer::{self, add_timer, get_time_ms, TimeSpec, NANO_PER_SEC},
 };
 
 use super::SyscallResult;
@@ -20,10 +26,22 @@ pub fn sys_yield(task: Arc<Task>) -> ! {
 }
 
 pub fn sys_exit(task: Arc<Task>, exit_code: i32) -> ! {
-    {
+    let (parent, children) = {
         let mut inner = task.inner_exclusive_access();
         inner.set_state(TaskState::Exited);
         inner.exit_code = exit_code;
+        // 等不到父进程回收就关闭文件, 管道的另一端才能及时看到文件结束
+        inner.fd_table.clear();
+        // 僵尸进程只保留退出码, 地址空间现在就释放,
+        // trap 上下文所在的物理页随之释放, 不能再留着它的页号
+        inner.addr_space = MemorySet::default();
+        inner.trap_ctx_ppn = PhysPageNum::default();
+        let parent = inner.parent.as_ref().and_then(Weak::upgrade);
+        (parent, core::mem::take(&mut inner.children))
+    };
+    reparent_to_initproc(children);
+    if let Some(parent) = parent {
+        wake_task(&parent);
     }
     log::info!(
         "{}, ready to exit, exit_code={}, Arc count={}",
@@ -65,51 +83,81 @@ pub fn sys_gettimeofday(task: &Weak<Task>, timeval_ptr: usize, _tz: usize) -> Sy
     Ok(0)
 }
 
+/// 阻塞到 `duration_us` 算出的微秒数之后
+fn sleep_for(task: &Arc<Task>, duration_us: impl FnOnce() -> Result<usize, ()>) -> SyscallResult {
+    let now = timer::get_time_us();
+    // 被唤醒后重新执行时沿用第一次算出的时间
+    let deadline = task.inner_exclusive_access().sleep_deadline.take();
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => now.saturating_add(duration_us()?),
+    };
+    if now >= deadline {
+        return Ok(0);
+    }
+    task.inner_exclusive_access().sleep_deadline = Some(deadline);
+    add_timer(deadline, task);
+    wait_cur_task();
+    Ok(0)
+}
+
+/// 睡眠 `req` 指定的时间. 不会被信号打断, 所以不写 `rem`.
+/// 其他章节的 101 号系统调用以毫秒为参数, 所以 nanosleep 用 426 号
+pub fn sys_nanosleep(task: &Weak<Task>, req: usize, _rem: usize) -> SyscallResult {
+    let task = Task::from_weak(&task);
+    sleep_for(&task, || {
+        let req: &mut TimeSpec = from_user_ptr(&task, req);
+        if req.nsec >= NANO_PER_SEC {
+            return Err(());
+        }
+        Ok(req.as_us())
+    })
+}
+
+/// 睡眠 `ms` 毫秒, 和其他章节的 101 号系统调用一样
+pub fn sys_sleep(task: &Weak<Task>, ms: usize) -> SyscallResult {
+    let task = Task::from_weak(&task);
+    sleep_for(&task, || Ok(ms.saturating_mul(1000)))
+}
+
 pub fn sys_fork(task: &Weak<Task>) -> SyscallResult {
     let task = Task::from_weak(&task);
-    let child = fork_task(&task);
+    let child = fork_task(&task)?;
     let child_pid = child.pid.0;
     task.inner_exclusive_access().children.push(child);
     Ok(child_pid as isize)
 }
 
-pub fn sys_waitpid(task: &Weak<Task>, target_pid: isize, exit_code: usize) -> SyscallResult {
+/// waitpid 的选项: 没有退出的子进程时立即返回 -2, 而不是阻塞
+const WNOHANG: usize = 1;
+
+/// 回收一个已经退出的子进程, 返回它的 pid. `target_pid` 为 -1 时可以是任意子进程.
+/// 没有这样的子进程时返回 -1, 子进程都还没有退出时阻塞直到有子进程退出.
+pub fn sys_waitpid(
+    task: &Weak<Task>,
+    target_pid: isize,
+    exit_code: usize,
+    options: usize,
+) -> SyscallResult {
     let task = Task::from_weak(&task);
-    let target_children_pid = {
-        let children = { &task.inner_exclusive_access().children };
-
-        let target_children: Vec<&Arc<Task>> = if target_pid == -1 {
-            children.iter().collect()
-        } else {
-            children
-                .iter()
-                .filter(|t| t.pid.0 == target_pid as usize)
-                .collect()
-        };
-        if target_children.is_empty() {
+    let is_target = |child: &Arc<Task>| target_pid == -1 || child.pid.0 == target_pid as usize;
+    let target_child = {
+        let mut inner = task.inner_exclusive_access();
+        if !inner.children.iter().any(is_target) {
             return Ok(-1);
         }
-        let exited_children: Vec<&Arc<Task>> = target_children
-            .iter()
-            .map(|t| *t)
-            .filter(|t| t.inner_exclusive_access().state == TaskState::Exited)
-            .collect();
-
-        if exited_children.is_empty() {
-            return Ok(-2);
+        let idx = inner.children.iter().position(|child| {
+            is_target(child) && child.inner_exclusive_access().state == TaskState::Exited
+        });
+        match idx {
+            Some(idx) => inner.children.remove(idx),
+            None if options & WNOHANG != 0 => return Ok(-2),
+            None => {
+                drop(inner);
+                wait_cur_task();
+                return Ok(0);
+            }
         }
-        (*exited_children.get(0).unwrap()).pid.clone()
-    };
-
-    let target_child = {
-        let mut inner = task.inner_exclusive_access();
-        let (idx, _) = inner
-            .children
-            .iter()
-            .enumerate()
-            .find(|(_, t)| t.pid == target_children_pid)
-            .expect("should have this pid child");
-        inner.children.remove(idx)
     };
     log::info!(
         "{}, have been wait, arc count={}",
@@ -118,14 +166,8 @@ pub fn sys_waitpid(task: &Weak<Task>, target_pid: isize, exit_code: usize) -> Sy
     );
 
     let exit_code: &mut i32 = from_user_ptr(&task, exit_code);
-    *exit_code = {
-        let child_inner = target_child.inner_exclusive_access();
-        assert!(target_child.pid == target_children_pid);
-        assert!(child_inner.state == TaskState::Exited);
-        child_inner.exit_code
-    };
-
-    Ok(target_children_pid.0 as isize)
+    *exit_code = target_child.inner_exclusive_access().exit_code;
+    Ok(target_child.pid.0 as isize)
 }
 
 pub fn sys_getpid(task: &Weak<Task>) -> SyscallResult {
@@ -143,20 +185,47 @@ pub fn sys_set_priority(task: &Weak<Task>, priority: isize) -> SyscallResult {
     }
 }
 
-pub fn sys_exec(task: &Weak<Task>, path: usize) -> SyscallResult {
+/// 复制用户的 argv 和 envp 数组, 它们在新的用户栈上放不下或者地址无效时失败.
+/// 边复制边检查大小, 过大的数组不会先耗尽内核堆
+fn user_args(task: &Arc<Task>, argv: usize, envp: usize) -> Result<(Vec<String>, Vec<String>), ()> {
+    // 留出 argc 的位置, 两个数组末尾的空指针在复制时计入
+    let mut budget = ARG_MAX - core::mem::size_of::<usize>();
+    let mut copy = || -> Result<_, ()> {
+        let args = from_user_cstring_array(task, argv, &mut budget)?;
+        let envs = from_user_cstring_array(task, envp, &mut budget)?;
+        Ok((args, envs))
+    };
+    copy().map_err(|_| log::warn!("{}, user_args, bad or more than {} bytes of arguments", task, ARG_MAX))
+}
+
+pub fn sys_exec(task: &Weak<Task>, path: usize, argv: usize, envp: usize) -> SyscallResult {
     let task = Task::from_weak(&task);
-    let path = from_user_cstring(&task, path);
-    log::info!("sys_exec, {}, target app={}", task, path);
-    task.exec(&path)?;
-    Ok(0)
+    let mut budget = ARG_MAX;
+    let path = from_user_cstring_within(&task, path, &mut budget)?;
+    let path = absolute_path(&task.inner_exclusive_access().cwd_path, &path);
+    let (args, envs) = user_args(&task, argv, envp)?;
+    log::info!("sys_exec, {}, target app={}, args={:?}", task, path, args);
+    let argc = task.exec(&path, &args, &envs)?;
+    Ok(argc as isize)
 }
 
-pub fn sys_spawn(task: &Weak<Task>, path: usize) -> SyscallResult {
+pub fn sys_spawn(task: &Weak<Task>, path: usize, argv: usize, envp: usize) -> SyscallResult {
     let task = Task::from_weak(&task);
-    let path = from_user_cstring(&task, path);
-    log::info!("sys_spawn, {}, target app={}", task, path);
-    let child = Task::spawn(&path)?;
+    let mut budget = ARG_MAX;
+    let path = from_user_cstring_within(&task, path, &mut budget)?;
+    let path = absolute_path(&task.inner_exclusive_access().cwd_path, &path);
+    let (args, envs) = user_args(&task, argv, envp)?;
+    log::info!("sys_spawn, {}, target app={}, args={:?}", task, path, args);
+    let child = Task::spawn(&path, &args, &envs)?;
     let child_pid = child.pid.0;
+    {
+        // 子进程继承当前工作目录
+        let inner = task.inner_exclusive_access();
+        let mut child_inner = child.inner_exclusive_access();
+        child_inner.cwd = Arc::clone(&inner.cwd);
+        child_inner.cwd_path = inner.cwd_path.clone();
+    }
+    child.inner_exclusive_access().parent = Some(Arc::downgrade(&task));
     task.inner_exclusive_access()
         .children
         .push(Arc::clone(&child));
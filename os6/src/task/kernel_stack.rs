@@ -1,40 +1,51 @@
+use lazy_static::lazy_static;
+
 use crate::{
     config::*,
     mm::{MapPermission, VirtAddr, KERNEL_SPACE},
-    task::PidHandle,
+    sync::UPSafeCell,
+    task::pid::RecycleAllocator,
 };
 
+lazy_static! {
+    static ref KSTACK_ALLOCATOR: UPSafeCell<RecycleAllocator> =
+        unsafe { UPSafeCell::new(RecycleAllocator::new(0, MAX_TASK_NUM)) };
+}
+
+/// 内核栈, 占用一个槽位, 释放时解除映射并归还槽位
 pub struct KernelStack {
-    pid: usize,
+    slot: usize,
 }
 
 impl KernelStack {
     pub fn position(&self) -> (usize, usize) {
-        kernel_stack_position(self.pid)
+        kernel_stack_position(self.slot)
     }
 }
 
 impl Drop for KernelStack {
     fn drop(&mut self) {
-        log::info!("drop kernel_stack, pid={}", self.pid);
+        log::info!("drop kernel_stack, slot={}", self.slot);
         let (kernel_stack_bottom, _) = self.position();
         let kernel_stack_bottom_va: VirtAddr = kernel_stack_bottom.into();
         KERNEL_SPACE
             .exclusive_access()
             .remove_area_with_start_vpn(kernel_stack_bottom_va.into());
+        KSTACK_ALLOCATOR.exclusive_access().dealloc(self.slot);
     }
 }
 
-fn kernel_stack_position(app_id: usize) -> (usize, usize) {
-    let top = TRAMPOLINE - app_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
+/// 槽位对应的内核栈位置, 各个内核栈之间有一个保护页
+fn kernel_stack_position(slot: usize) -> (usize, usize) {
+    let top = TRAMPOLINE - slot * (KERNEL_STACK_SIZE + PAGE_SIZE);
     let bottom = top - KERNEL_STACK_SIZE;
     (bottom, top)
 }
 
-pub fn alloc_kernel_stack(pid: PidHandle) -> KernelStack {
-    log::info!("alloc_kernel_stack, pid={}", pid);
-    let pid = pid.0;
-    let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(pid);
+/// 分配并映射内核栈, 槽位用完时返回 None
+pub fn alloc_kernel_stack() -> Option<KernelStack> {
+    let slot = KSTACK_ALLOCATOR.exclusive_access().alloc()?;
+    let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(slot);
     KERNEL_SPACE
         .exclusive_access()
         .insert_framed_area(
@@ -44,10 +55,10 @@ pub fn alloc_kernel_stack(pid: PidHandle) -> KernelStack {
         )
         .expect("map kernel stack failed!");
     log::info!(
-        "alloc_kernel_stack finish, pid={}, stack_bottom=0x{:x}, stack_top=0x{:x}",
-        pid,
+        "alloc_kernel_stack finish, slot={}, stack_bottom=0x{:x}, stack_top=0x{:x}",
+        slot,
         kernel_stack_bottom,
         kernel_stack_top
     );
-    KernelStack { pid }
+    Some(KernelStack { slot })
 }
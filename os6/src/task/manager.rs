@@ -1,5 +1,8 @@
 use crate::{task::Task, sync::UPSafeCell};
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -9,12 +12,15 @@ lazy_static! {
 pub struct TaskManager {
     pub next_task: usize,
     task_list: VecDeque<Arc<Task>>,
+    /// 阻塞的任务, 以 pid 为键
+    blocked_tasks: BTreeMap<usize, Arc<Task>>,
 }
 
 impl TaskManager {
     fn new() -> Self {
         Self {
             task_list: VecDeque::new(),
+            blocked_tasks: BTreeMap::new(),
             next_task: 0,
         }
     }
@@ -43,4 +49,23 @@ impl TaskManager {
     pub fn add_task(&mut self, task: Arc<Task>) {
         self.task_list.push_back(task);
     }
+
+    pub fn add_blocked_task(&mut self, task: Arc<Task>) {
+        self.blocked_tasks.insert(task.pid.0, task);
+    }
+
+    pub fn has_blocked_task(&self) -> bool {
+        !self.blocked_tasks.is_empty()
+    }
+
+    /// 把阻塞的任务移回就绪队列, 它不在阻塞队列中时返回 false
+    pub fn wake_task(&mut self, pid: usize) -> bool {
+        match self.blocked_tasks.remove(&pid) {
+            Some(task) => {
+                self.task_list.push_back(task);
+                true
+            }
+            None => false,
+        }
+    }
 }
@@ -3,24 +3,41 @@ mod manager;
 mod pid;
 mod processor;
 mod task;
+mod wait_queue;
 
-use alloc::sync::{Arc, Weak};
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use lazy_static::lazy_static;
 
 use crate::{
     config::BIG_STRIDE,
+    fs::{has_stdin_readers, poll_stdin},
     task::{manager::TM, processor::processor_inner},
+    timer::{check_timers, has_timers},
     trap::restore,
     BATCH_PROCESSING_TASK,
 };
 pub use {
     pid::{alloc_pid, PidHandle},
     task::{fork_task, Task, TaskInner, TaskState},
+    wait_queue::WaitQueue,
 };
 
+lazy_static! {
+    /// 初始进程, 孤儿进程都交给它回收
+    pub static ref INITPROC: Arc<Task> = {
+        let task = Task::new("ch6b_initproc");
+        task.inner_exclusive_access().privileged = true;
+        task
+    };
+}
+
 // 将初始进程加入任务管理器.
 #[allow(dead_code)]
 pub fn add_initproc() {
-    TM.exclusive_access().add_task(Task::new("ch6b_initproc"))
+    TM.exclusive_access().add_task(Arc::clone(&INITPROC))
 }
 
 pub fn add_task(task: Arc<Task>) {
@@ -28,19 +45,27 @@ pub fn add_task(task: Arc<Task>) {
 }
 
 pub fn fetch_ready_task() -> Arc<Task> {
-    let mut task_manager = TM.exclusive_access();
-    let task = match task_manager.find_next_ready_task() {
-        None => {
+    loop {
+        let mut task_manager = TM.exclusive_access();
+        if let Some(task) = task_manager.find_next_ready_task() {
+            return task;
+        }
+        if !task_manager.has_blocked_task() {
             let mut batch_tasks = BATCH_PROCESSING_TASK.exclusive_access();
             while batch_tasks.len() > 0 {
                 batch_tasks.pop();
             }
             panic!("all task complete!");
         }
-        Some(task) => task,
-    };
-
-    task
+        drop(task_manager);
+        // 没有任务在睡眠也没有任务在等输入, 阻塞的任务再也不会被唤醒
+        if !has_timers() && !has_stdin_readers() {
+            panic!("deadlock: all tasks are blocked and nothing can wake them up");
+        }
+        // 所有任务都在阻塞, 等到有定时器到期或者有输入
+        check_timers();
+        poll_stdin();
+    }
 }
 
 pub fn run_task(task: Arc<Task>) -> ! {
@@ -70,3 +95,45 @@ pub fn pop_cur_task() -> Option<Arc<Task>> {
 pub fn weak_cur_task() -> Option<Weak<Task>> {
     processor_inner().weak_task()
 }
+
+/// 阻塞的任务离开处理器, 放进阻塞队列直到被 `wake_task` 唤醒
+pub fn suspend_task(task: Arc<Task>) -> ! {
+    TM.exclusive_access().add_blocked_task(task);
+    run_next_task()
+}
+
+/// 唤醒阻塞的任务, 任务不在阻塞队列中时什么也不做.
+/// 正在运行的任务不在阻塞队列中, 所以这里不会借用它的 inner.
+pub fn wake_task(task: &Arc<Task>) {
+    let woken = TM.exclusive_access().wake_task(task.pid.0);
+    if woken {
+        task.inner_exclusive_access().set_state(TaskState::Ready);
+    }
+}
+
+/// 把退出进程的子进程交给 initproc, 其中已经退出的僵尸进程由它回收
+pub fn reparent_to_initproc(children: Vec<Arc<Task>>) {
+    let mut has_zombie = false;
+    for child in children {
+        {
+            let mut child_inner = child.inner_exclusive_access();
+            child_inner.parent = Some(Arc::downgrade(&INITPROC));
+            has_zombie |= child_inner.state == TaskState::Exited;
+        }
+        INITPROC.inner_exclusive_access().children.push(child);
+    }
+    if has_zombie {
+        wake_task(&INITPROC);
+    }
+}
+
+/// 当前任务正在执行的系统调用需要阻塞. 系统调用返回之后任务离开处理器,
+/// 直到被 `wake_task` 唤醒才再次调度, 然后从头重新执行这个系统调用,
+/// 所以调用者阻塞前不能产生其他副作用.
+pub fn wait_cur_task() {
+    let task = weak_cur_task().expect("no current task to block");
+    let task = Task::from_weak(&task);
+    let mut inner = task.inner_exclusive_access();
+    inner.restart_syscall = true;
+    inner.set_state(TaskState::Blocked);
+}
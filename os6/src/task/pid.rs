@@ -1,31 +1,56 @@
+use alloc::vec::Vec;
 use core::fmt::Display;
 use lazy_static::lazy_static;
 
-use crate::sync::UPSafeCell;
+use crate::{config::MAX_TASK_NUM, sync::UPSafeCell};
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Clone)]
+/// 进程号, 释放时归还给分配器
+#[derive(PartialEq, PartialOrd, Eq, Ord)]
 pub struct PidHandle(pub usize);
 
 lazy_static! {
-    static ref PID_ALLOCATOR: UPSafeCell<PidAllocator> = unsafe { UPSafeCell::new(PidAllocator::new())};
+    static ref PID_ALLOCATOR: UPSafeCell<RecycleAllocator> =
+        unsafe { UPSafeCell::new(RecycleAllocator::new(1, MAX_TASK_NUM)) };
 }
 
-struct PidAllocator {
+/// 分配 `[start, start + limit)` 中的编号, 释放的编号优先重新分配
+pub struct RecycleAllocator {
     current: usize,
+    end: usize,
+    recycled: Vec<usize>,
 }
 
-impl PidAllocator {
-    fn new() -> Self {
-        PidAllocator { current: 0 }
+impl RecycleAllocator {
+    pub fn new(start: usize, limit: usize) -> Self {
+        RecycleAllocator {
+            current: start,
+            end: start + limit,
+            recycled: Vec::new(),
+        }
     }
 
-    fn alloc(&mut self) -> PidHandle {
-        self.current += 1;
-        PidHandle(self.current)
+    /// 编号用完时返回 None
+    pub fn alloc(&mut self) -> Option<usize> {
+        if let Some(id) = self.recycled.pop() {
+            Some(id)
+        } else if self.current < self.end {
+            self.current += 1;
+            Some(self.current - 1)
+        } else {
+            None
+        }
     }
-    #[allow(dead_code)]
-    fn dealloc(&mut self) -> PidHandle {
-        PidHandle(0)
+
+    pub fn dealloc(&mut self, id: usize) {
+        assert!(id < self.current, "id {} has not been allocated", id);
+        assert!(!self.recycled.contains(&id), "id {} is freed twice", id);
+        self.recycled.push(id);
+    }
+}
+
+impl Drop for PidHandle {
+    fn drop(&mut self) {
+        PID_ALLOCATOR.exclusive_access().dealloc(self.0);
     }
 }
 
@@ -35,6 +60,7 @@ impl Display for PidHandle {
     }
 }
 
-pub fn alloc_pid() -> PidHandle {
-    PID_ALLOCATOR.exclusive_access().alloc()
+/// 分配进程号, 进程数达到 `MAX_TASK_NUM` 时返回 None
+pub fn alloc_pid() -> Option<PidHandle> {
+    PID_ALLOCATOR.exclusive_access().alloc().map(PidHandle)
 }
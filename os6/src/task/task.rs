@@ -7,11 +7,12 @@ use alloc::{
     vec::Vec,
 };
 
-use crate::fs::Stdin;
+use easy_fs::Inode;
+
 use crate::{
     config::*,
-    fs::{File, Stdout},
-    loader::efs_get_app_elf,
+    fs::{FdTable, ROOT_INODE},
+    loader::efs_get_exec_elf,
     mm::{MemorySet, PhysAddr, PhysPageNum, VirtAddr, VirtPageNum},
     sync::UPSafeCell,
     timer::get_time_ms,
@@ -21,7 +22,7 @@ use crate::{
 use super::{
     add_task, alloc_pid,
     kernel_stack::{alloc_kernel_stack, KernelStack},
-    PidHandle,
+    PidHandle, INITPROC,
 };
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -29,6 +30,9 @@ pub enum TaskState {
     UnInit,
     Ready,
     Running,
+    /// 不在就绪队列中, 等待被 `wake_task` 唤醒
+    Blocked,
+    /// 僵尸进程, 只保留退出码等待父进程回收
     Exited,
 }
 
@@ -44,12 +48,26 @@ pub struct TaskInner {
     pub state: TaskState,
     pub syscall_times: [u32; MAX_SYSCALL_NUM],
     pub addr_space: MemorySet,
+    pub parent: Option<Weak<Task>>,
     pub children: Vec<Arc<Task>>,
     pub exit_code: i32,
     pub name: String,
     pub priority: u32,
     pub pass: usize,
-    pub fd_table: Vec<Option<Arc<dyn File>>>,
+    pub fd_table: FdTable,
+    /// 系统调用需要阻塞, 返回用户态之前让出处理器, 再次运行时重新执行它
+    pub restart_syscall: bool,
+    /// 阻塞的 write 在阻塞之前已经写入的字节数
+    pub written_before_block: usize,
+    /// 阻塞的 sleep 和 nanosleep 醒来的时间, 单位为微秒
+    pub sleep_deadline: Option<usize>,
+    /// 当前工作目录
+    pub cwd: Arc<Inode>,
+    /// 当前工作目录的规范绝对路径, 相对路径中的 ".." 靠它解析
+    pub cwd_path: String,
+    /// 可以设置文件系统主密钥. 只有初始进程和它 fork 出的进程 (shell) 有,
+    /// 不会再往下继承, 被收养的孤儿进程也不会因此获得
+    pub privileged: bool,
 }
 
 impl Default for TaskInner {
@@ -60,16 +78,18 @@ impl Default for TaskInner {
             state: Default::default(),
             syscall_times: [0; MAX_SYSCALL_NUM],
             addr_space: Default::default(),
+            parent: None,
             children: Default::default(),
             exit_code: Default::default(),
             priority: 16,
             pass: Default::default(),
-            fd_table: {
-                let mut v: Vec<Option<Arc<dyn File>>> = Vec::new();
-                v.push(Some(Arc::new(Stdin)));
-                v.push(Some(Arc::new(Stdout)));
-                v
-            },
+            fd_table: FdTable::new(),
+            restart_syscall: false,
+            written_before_block: 0,
+            sleep_deadline: None,
+            cwd: Arc::clone(&ROOT_INODE),
+            cwd_path: String::from("/"),
+            privileged: false,
         }
     }
 }
@@ -89,6 +109,52 @@ impl TaskInner {
     pub fn trap_context(&self) -> &mut TrapContext {
         self.trap_ctx_ppn.get_mut()
     }
+
+    /// 把数据写入用户地址空间的 va 处, 目标必须已经映射
+    fn write_user(&self, va: usize, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let pa = self
+                .translate(va + i)
+                .expect("write to unmapped user address");
+            unsafe { (pa as *mut u8).write_volatile(*byte) };
+        }
+    }
+
+    /// 把参数和环境变量压入栈顶为 `user_sp` 的用户栈: 字符串在上方,
+    /// 下方从新的栈顶开始依次是 argc, argv[], NULL, envp[], NULL.
+    /// 返回新的栈顶以及 argv 和 envp 数组的地址.
+    fn push_args(&self, user_sp: usize, args: &[String], envs: &[String]) -> (usize, usize, usize) {
+        let mut sp = user_sp;
+        let mut push_strings = |strings: &[String]| -> Vec<usize> {
+            strings
+                .iter()
+                .map(|string| {
+                    sp -= string.len() + 1;
+                    self.write_user(sp, string.as_bytes());
+                    self.write_user(sp + string.len(), &[0]);
+                    sp
+                })
+                .collect()
+        };
+        let arg_ptrs = push_strings(args);
+        let env_ptrs = push_strings(envs);
+        let word = core::mem::size_of::<usize>();
+        let words = 1 + arg_ptrs.len() + 1 + env_ptrs.len() + 1;
+        // RISC-V 要求栈顶 16 字节对齐
+        sp = (sp - words * word) & !0xf;
+        let mut table: Vec<usize> = Vec::with_capacity(words);
+        table.push(arg_ptrs.len());
+        table.extend(arg_ptrs.iter());
+        table.push(0);
+        table.extend(env_ptrs.iter());
+        table.push(0);
+        for (i, value) in table.iter().enumerate() {
+            self.write_user(sp + i * word, &value.to_ne_bytes());
+        }
+        let argv = sp + word;
+        let envp = argv + (arg_ptrs.len() + 1) * word;
+        (sp, argv, envp)
+    }
 }
 
 #[repr(C, align(4096))]
@@ -100,27 +166,37 @@ pub struct Task {
 }
 
 impl Task {
-    pub fn new(name: &str) -> Arc<Task> {
-        let new_pid = alloc_pid();
-        let task = Task {
-            pid: new_pid.clone(),
+    /// 分配 pid 和内核栈, 进程数达到 `MAX_TASK_NUM` 时失败
+    fn new_bare() -> Result<Self, ()> {
+        let (pid, kernel_stack) = match (alloc_pid(), alloc_kernel_stack()) {
+            (Some(pid), Some(kernel_stack)) => (pid, kernel_stack),
+            _ => {
+                log::warn!("too many tasks, limit={}", MAX_TASK_NUM);
+                return Err(());
+            }
+        };
+        Ok(Task {
+            pid,
             start_time_ms: get_time_ms(),
-            kernel_stack: alloc_kernel_stack(new_pid),
+            kernel_stack,
             inner: unsafe { UPSafeCell::new(TaskInner::default()) },
-        };
+        })
+    }
 
-        task.init(name);
+    pub fn new(name: &str) -> Arc<Task> {
+        let task = Self::new_bare().expect("too many tasks");
+        task.init(name, &[String::from(name)], &[])
+            .unwrap_or_else(|_| panic!("failed to load {}", name));
         Arc::new(task)
     }
 
-    fn init(&self, name: &str) {
+    /// 载入程序, 参数和环境变量放在用户栈上, argc, argv 和 envp 同时通过 a0, a1, a2 传递.
+    /// 脚本换成它的解释器执行. 程序不存在或者不是合法的可执行文件时失败,
+    /// 此时原来的地址空间保持不变. 成功时返回 argc.
+    fn init(&self, name: &str, args: &[String], envs: &[String]) -> Result<usize, ()> {
         let kernel_stack_top = self.kernel_stack.position().1;
-        let (ms, user_stack, entrypoint) = {
-            // todo: get_app error check?
-            let app_elf_vec = efs_get_app_elf(name).unwrap();
-            let elf = app_elf_vec.as_slice();
-            MemorySet::from_elf(elf)
-        };
+        let (app_elf_vec, args) = efs_get_exec_elf(name, args)?;
+        let (ms, user_stack, entrypoint) = MemorySet::from_elf(app_elf_vec.as_slice())?;
 
         let trap_ctx_ppn = ms
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
@@ -132,24 +208,25 @@ impl Task {
         inner.trap_ctx_ppn = trap_ctx_ppn;
         inner.addr_space = ms;
         inner.state = TaskState::Ready;
-        inner
-            .trap_context()
-            .init(user_stack, entrypoint, kernel_stack_top)
+        let (user_sp, argv, envp) = inner.push_args(user_stack, &args, envs);
+        let trap_ctx = inner.trap_context();
+        trap_ctx.init(user_sp, entrypoint, kernel_stack_top);
+        trap_ctx.set_reg_a(0, args.len());
+        trap_ctx.set_reg_a(1, argv);
+        trap_ctx.set_reg_a(2, envp);
+        Ok(args.len())
     }
 
-    pub fn exec(&self, name: &str) -> Result<(), ()> {
-        Ok(self.init(name))
+    /// 把当前进程替换为新程序, 成功时返回 argc, 它会作为系统调用的返回值放入 a0
+    pub fn exec(&self, name: &str, args: &[String], envs: &[String]) -> Result<usize, ()> {
+        let argc = self.init(name, args, envs)?;
+        self.inner.exclusive_access().fd_table.close_on_exec();
+        Ok(argc)
     }
 
-    pub fn spawn(name: &str) -> Result<Arc<Task>, ()> {
-        let new_pid = alloc_pid();
-        let task = Task {
-            pid: new_pid.clone(),
-            start_time_ms: get_time_ms(),
-            kernel_stack: alloc_kernel_stack(new_pid),
-            inner: unsafe { UPSafeCell::new(TaskInner::default()) },
-        };
-        task.init(name);
+    pub fn spawn(name: &str, args: &[String], envs: &[String]) -> Result<Arc<Task>, ()> {
+        let task = Self::new_bare()?;
+        task.init(name, args, envs)?;
         Ok(Arc::new(task))
     }
 
@@ -172,16 +249,11 @@ impl Display for Task {
     }
 }
 
-pub fn fork_task(parent: &Arc<Task>) -> Arc<Task> {
-    let new_pid = alloc_pid();
-    let p_inner = parent.inner_exclusive_access();
+/// 复制进程, 进程数达到 `MAX_TASK_NUM` 时失败
+pub fn fork_task(parent: &Arc<Task>) -> Result<Arc<Task>, ()> {
     // init child task
-    let child_task = Arc::from(Task {
-        pid: new_pid.clone(),
-        start_time_ms: get_time_ms(),
-        kernel_stack: alloc_kernel_stack(new_pid.clone()),
-        inner: unsafe { UPSafeCell::new(TaskInner::default()) },
-    });
+    let child_task = Arc::new(Task::new_bare()?);
+    let p_inner = parent.inner_exclusive_access();
 
     let mut child_inner = child_task.inner_exclusive_access();
     // init basic
@@ -212,10 +284,14 @@ pub fn fork_task(parent: &Arc<Task>) -> Arc<Task> {
         child_trapctx.set_reg_a(10, 0); // fork return 0 to child.
         child_trapctx.kernel_sp = kernel_stack_top;
     }
-    // init fd_table
+    child_inner.parent = Some(Arc::downgrade(parent));
+    child_inner.privileged = Arc::ptr_eq(parent, &INITPROC);
+    // init fd_table and cwd
     child_inner.fd_table = p_inner.fd_table.clone();
+    child_inner.cwd = Arc::clone(&p_inner.cwd);
+    child_inner.cwd_path = p_inner.cwd_path.clone();
 
     drop(child_inner);
     add_task(Arc::clone(&child_task));
-    child_task
+    Ok(child_task)
 }
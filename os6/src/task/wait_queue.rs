@@ -0,0 +1,39 @@
+use alloc::{sync::Weak, vec::Vec};
+
+use super::{wait_cur_task, wake_task, weak_cur_task, Task};
+
+/// 等待同一个条件的任务. 被唤醒的任务会重新执行阻塞时的系统调用并再次检查条件,
+/// 所以多余的唤醒是无害的.
+#[derive(Default)]
+pub struct WaitQueue {
+    tasks: Vec<Weak<Task>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 当前任务在这个队列上阻塞, 见 `wait_cur_task`
+    pub fn wait(&mut self) {
+        let task = weak_cur_task().expect("no current task to block");
+        if !self.tasks.iter().any(|waiting| waiting.ptr_eq(&task)) {
+            self.tasks.push(task);
+        }
+        wait_cur_task();
+    }
+
+    /// 队列中是否有还没退出的任务
+    pub fn has_waiters(&self) -> bool {
+        self.tasks.iter().any(|task| task.strong_count() > 0)
+    }
+
+    /// 唤醒队列中所有的任务
+    pub fn wake_all(&mut self) {
+        for task in self.tasks.drain(..) {
+            if let Some(task) = task.upgrade() {
+                wake_task(&task);
+            }
+        }
+    }
+}
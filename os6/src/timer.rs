@@ -1,10 +1,17 @@
 use crate::config::CLOCK_FREQ;
 use crate::sbi::set_timer;
+use crate::sync::UPSafeCell;
+use crate::task::{wake_task, Task};
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use lazy_static::lazy_static;
 use riscv::register::time;
 
 const TICKS_PER_SEC: usize = 100;
 const MICRO_PER_MILLISEC: usize = 1_000;
 const MICRO_PER_SEC: usize = 1_000_000;
+const NANO_PER_MICROSEC: usize = 1_000;
+pub const NANO_PER_SEC: usize = 1_000_000_000;
 
 pub fn get_time() -> usize {
     time::read()
@@ -30,6 +37,61 @@ pub fn set_time_val(time: &mut TimeVal) {
     time.usec = (cpu_time - time.sec * CLOCK_FREQ) / (CLOCK_FREQ / MICRO_PER_SEC)
 }
 
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+impl TimeSpec {
+    /// 向上取整到微秒, 过长时取最大值
+    pub fn as_us(&self) -> usize {
+        self.sec
+            .saturating_mul(MICRO_PER_SEC)
+            .saturating_add((self.nsec + NANO_PER_MICROSEC - 1) / NANO_PER_MICROSEC)
+    }
+}
+
+lazy_static! {
+    /// 睡眠中的任务, 以 (醒来的时间, pid) 为键, 时间单位为微秒
+    static ref TIMERS: UPSafeCell<BTreeMap<(usize, usize), Weak<Task>>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+}
+
+/// 在 `deadline_us` 时唤醒任务
+pub fn add_timer(deadline_us: usize, task: &Arc<Task>) {
+    TIMERS
+        .exclusive_access()
+        .insert((deadline_us, task.pid.0), Arc::downgrade(task));
+}
+
+/// 是否还有任务在睡眠, 它们迟早会被唤醒
+pub fn has_timers() -> bool {
+    TIMERS
+        .exclusive_access()
+        .values()
+        .any(|task| task.strong_count() > 0)
+}
+
+/// 唤醒所有已经到期的任务
+pub fn check_timers() {
+    let now = get_time_us();
+    loop {
+        let task = {
+            let mut timers = TIMERS.exclusive_access();
+            let key = match timers.keys().next() {
+                Some(&key) if key.0 <= now => key,
+                _ => break,
+            };
+            timers.remove(&key).unwrap()
+        };
+        if let Some(task) = task.upgrade() {
+            wake_task(&task);
+        }
+    }
+}
+
 pub fn set_next_trigger() {
     set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
 }
@@ -1,7 +1,8 @@
 use crate::{
+    fs::poll_stdin,
     syscall::{self, sys_exit},
     task::{pop_cur_task, run_task, switch_task, weak_cur_task, Task, TaskState},
-    timer::set_next_trigger,
+    timer::{check_timers, set_next_trigger},
 };
 use riscv::register::{
     scause::{self, Exception, Interrupt, Trap},
@@ -58,6 +59,8 @@ pub fn trap_handler() -> ! {
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             log::info!("Timer interrupt.");
             set_next_trigger();
+            check_timers();
+            poll_stdin();
             {
                 let task = Task::from_weak(&weak_task);
                 let mut inner = task.inner_exclusive_access();
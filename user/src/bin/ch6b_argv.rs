@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::null;
+use user_lib::{env, exec, execve, fork, getenv, spawnve, waitpid};
+
+/// 测试 exec 和 spawn 传递的参数与环境变量, 输出 argv test passed! 就算正确。
+
+const ARGS: [&str; 3] = ["ch6b_argv", "one", "two words"];
+
+/// 检查 execve 或 spawnve 传来的参数和环境变量, 再用 exec 把环境变量传下去
+fn check_args(argv: &[&str]) -> i32 {
+    assert_eq!(argv, &ARGS[..]);
+    assert_eq!(env().len(), 2);
+    assert_eq!(getenv("CH6B"), Some("argv"));
+    assert_eq!(getenv("EMPTY"), Some(""));
+    assert_eq!(getenv("NOPE"), None);
+    exec(
+        "ch6b_argv\0",
+        &["ch6b_argv\0".as_ptr(), "inherit\0".as_ptr(), null()],
+    );
+    panic!("exec ch6b_argv failed");
+}
+
+/// 运行子进程并等它成功退出
+fn wait_child(pid: isize) {
+    assert!(pid > 0);
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+}
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    match argc {
+        1 => {}
+        2 => {
+            assert_eq!(argv, &["ch6b_argv", "inherit"][..]);
+            assert_eq!(getenv("CH6B"), Some("argv"));
+            return 0;
+        }
+        3 => return check_args(argv),
+        _ => panic!("unexpected argc={}", argc),
+    }
+    assert_eq!(argv, &["ch6b_argv"][..]);
+
+    let args = [
+        "ch6b_argv\0".as_ptr(),
+        "one\0".as_ptr(),
+        "two words\0".as_ptr(),
+        null(),
+    ];
+    let envs = ["CH6B=argv\0".as_ptr(), "EMPTY=\0".as_ptr(), null()];
+    let pid = fork();
+    if pid == 0 {
+        execve("ch6b_argv\0", &args, &envs);
+        panic!("execve ch6b_argv failed");
+    }
+    wait_child(pid);
+    wait_child(spawnve("ch6b_argv\0", &args, &envs));
+    // 无效的字符串指针让 exec 和 spawn 失败, 而不是让内核崩溃
+    let bad = [0x10 as *const u8, null()];
+    assert_eq!(execve("ch6b_argv\0", &bad, &envs), -1);
+    assert_eq!(spawnve("ch6b_argv\0", &args, &bad), -1);
+    println!("argv test passed!");
+    0
+}
@@ -13,9 +13,9 @@ use user_lib::{
 };
 use alloc::string::String;
 
-#[no_mangle]
-pub fn main() -> i32 {
-    let fd = open("filea\0", OpenFlags::RDONLY);
+/// Print a file, `path` must be nul-terminated
+fn cat(path: &str) {
+    let fd = open(path, OpenFlags::RDONLY);
     if fd == -1 {
         panic!("Error occured when opening file");
     }
@@ -29,5 +29,18 @@ pub fn main() -> i32 {
     }
     println!("{}", s);
     close(fd);
+}
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 {
+        cat("filea\0");
+    }
+    for path in argv.iter().skip(1) {
+        // the arguments come without their nul terminators
+        let mut path = String::from(*path);
+        path.push('\0');
+        cat(path.as_str());
+    }
     0
 }
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{chdir, close, fchdir, fork, getcwd, open, openat, read, waitpid, write, OpenFlags};
+
+/// 测试 chdir, fchdir, getcwd 和以目录描述符为起点的 openat,
+/// 输出 cwd test passed! 就算正确。
+
+const FNAME: &str = "ch6b_cwd_file\0";
+
+/// 断言当前工作目录是 `expected`
+fn assert_cwd(expected: &str) {
+    let mut buf = [0u8; 64];
+    let len = getcwd(&mut buf);
+    assert_eq!(len, expected.len() as isize + 1);
+    assert_eq!(&buf[..expected.len()], expected.as_bytes());
+    assert_eq!(buf[expected.len()], 0);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(chdir("/\0"), 0);
+    assert_cwd("/");
+    // 放不下路径和 '\0' 时失败
+    assert_eq!(getcwd(&mut [0u8; 1]), -1);
+    // ".." 停在根目录
+    assert_eq!(chdir("/..\0"), 0);
+    assert_cwd("/");
+
+    let dirfd = open(".\0", OpenFlags::RDONLY);
+    assert!(dirfd > 0);
+    let dirfd = dirfd as usize;
+    let fd = openat(dirfd, FNAME, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"cwd"), 3);
+    close(fd);
+
+    let fd = open("/ch6b_cwd_file\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let mut buffer = [0u8; 8];
+    assert_eq!(read(fd, &mut buffer), 3);
+    assert_eq!(&buffer[..3], b"cwd");
+    // 普通文件不能作为目录使用
+    assert_eq!(openat(fd, FNAME, OpenFlags::RDONLY), -1);
+    assert_eq!(fchdir(fd), -1);
+    assert_eq!(chdir(FNAME), -1);
+    assert_eq!(chdir("ch6b_no_such_dir\0"), -1);
+    close(fd);
+    // 绝对路径不受 dirfd 影响
+    let fd = openat(dirfd, "/ch6b_cwd_file\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    close(fd as usize);
+
+    assert_eq!(fchdir(dirfd), 0);
+    assert_cwd("/");
+    close(dirfd);
+
+    // 子进程继承工作目录
+    let pid = fork();
+    if pid == 0 {
+        assert_cwd("/");
+        let fd = open(FNAME, OpenFlags::RDONLY);
+        assert!(fd > 0);
+        close(fd as usize);
+        return 0;
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    println!("cwd test passed!");
+    0
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::null;
+use user_lib::{
+    close, dup, dup2, dup3, exec, fork, lseek, open, read, waitpid, write, OpenFlags, SEEK_SET,
+};
+
+/// 测试 dup, dup2, dup3 和 close-on-exec, 输出 dup test passed! 就算正确。
+
+const FNAME: &str = "ch6b_dup_file\0";
+/// 没有 close-on-exec 的描述符
+const KEPT_FD: usize = 10;
+/// 带 close-on-exec 的描述符
+const CLOEXEC_FD: usize = 11;
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc == 2 && argv[1] == "check" {
+        // exec 之后 KEPT_FD 还在, CLOEXEC_FD 已经关闭
+        let mut buffer = [0u8; 8];
+        assert!(read(KEPT_FD, &mut buffer) >= 0);
+        assert_eq!(read(CLOEXEC_FD, &mut buffer), -1);
+        return 0;
+    }
+
+    let fd = open(FNAME, OpenFlags::CREATE | OpenFlags::RDWR);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"abc"), 3);
+
+    // 新描述符是最小的空闲描述符, 和原来的共享读写位置
+    let new_fd = dup(fd);
+    assert_eq!(new_fd, fd as isize + 1);
+    let new_fd = new_fd as usize;
+    assert_eq!(write(new_fd, b"def"), 3);
+    assert_eq!(lseek(fd, 0, SEEK_SET), 0);
+    let mut buffer = [0u8; 16];
+    let len = read(new_fd, &mut buffer) as usize;
+    assert_eq!(&buffer[..len], b"abcdef");
+    assert_eq!(close(new_fd), 0);
+    assert_eq!(dup(fd), new_fd as isize);
+    assert_eq!(close(new_fd), 0);
+    assert_eq!(dup(new_fd), -1);
+
+    assert_eq!(dup2(fd, KEPT_FD), KEPT_FD as isize);
+    assert_eq!(dup2(fd, fd), fd as isize);
+    assert_eq!(dup2(new_fd, KEPT_FD), -1);
+    assert_eq!(dup3(fd, fd, OpenFlags::empty()), -1);
+    assert_eq!(dup3(fd, CLOEXEC_FD, OpenFlags::APPEND), -1);
+    assert_eq!(
+        dup3(fd, CLOEXEC_FD, OpenFlags::CLOEXEC),
+        CLOEXEC_FD as isize
+    );
+    // dup2 到已经打开的描述符上会先关闭它
+    assert_eq!(dup2(KEPT_FD, CLOEXEC_FD), CLOEXEC_FD as isize);
+    assert_eq!(
+        dup3(fd, CLOEXEC_FD, OpenFlags::CLOEXEC),
+        CLOEXEC_FD as isize
+    );
+
+    let pid = fork();
+    if pid == 0 {
+        exec(
+            "ch6b_dup\0",
+            &["ch6b_dup\0".as_ptr(), "check\0".as_ptr(), null()],
+        );
+        panic!("exec ch6b_dup failed");
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+
+    close(CLOEXEC_FD);
+    close(KEPT_FD);
+    close(fd);
+    println!("dup test passed!");
+    0
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::{close, closedir, link, open, opendir, readdir, write, OpenFlags, DT_REG};
+
+/// 测试没有主密钥时写加密文件失败, 内核不会崩溃。输出 encrypt nokey test passed! 就算正确。
+///
+/// 没有主密钥时加密目录里的文件名都找不到, 所以先用硬链接把一个加密文件放到根目录:
+/// 用 make run SECRETS=<主机目录> FS_KEY=<密钥> 打包 /secrets, 在 shell 里 setkey 之后
+/// 运行 `ch6b_encrypt_nokey link`, 再用 make qemu 不重新打包地重启, 在 setkey 之前运行
+/// `ch6b_encrypt_nokey`。
+/// 没有这个硬链接时跳过。
+
+const LINK: &str = "/ch6b_secret_link\0";
+
+/// 把 /secrets 里的第一个文件硬链接到 `LINK`
+fn make_link() -> i32 {
+    let mut dir = opendir("/secrets\0").expect("no encrypted directory /secrets");
+    let mut secret: Option<String> = None;
+    while let Some(entry) = readdir(&mut dir) {
+        if entry.d_type == DT_REG {
+            secret = Some(entry.name);
+            break;
+        }
+    }
+    closedir(dir);
+    // 没有主密钥时读不出 /secrets 里的文件名
+    let mut path = String::from("/secrets/");
+    path.push_str(&secret.expect("no file in /secrets, or no master key"));
+    path.push('\0');
+    assert_eq!(link(path.as_str(), LINK), 0);
+    println!(
+        "linked {} to {}, reboot and run ch6b_encrypt_nokey before setkey",
+        path.trim_end_matches('\0'),
+        LINK.trim_end_matches('\0')
+    );
+    0
+}
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc == 2 && argv[1] == "link" {
+        return make_link();
+    }
+    let fd = open(LINK, OpenFlags::WRONLY);
+    if fd < 0 {
+        println!(
+            "no {}, encrypt nokey test skipped",
+            LINK.trim_end_matches('\0')
+        );
+        return 0;
+    }
+    let fd = fd as usize;
+    // 没有主密钥时一个字节也写不进去
+    assert_eq!(write(fd, b"plain text"), -1);
+    close(fd);
+    println!("encrypt nokey test passed!");
+    0
+}
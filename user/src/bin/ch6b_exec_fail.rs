@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::null;
+use user_lib::{close, exec, open, read, spawn, write, OpenFlags};
+
+/// 测试 exec 和 spawn 的失败情形: 调用者不受影响, 继续运行。
+/// 输出 exec fail test passed! 就算正确。
+
+const FNAME: &str = "ch6b_exec_fail_file\0";
+
+/// 超过 ARG_MAX 的参数
+static LONG_ARG: [u8; 1024] = {
+    let mut arg = [b'a'; 1024];
+    arg[1023] = 0;
+    arg
+};
+
+/// 创建内容为 `data` 的文件 `path`
+fn create(path: &str, data: &[u8]) {
+    let fd = open(path, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, data), data.len() as isize);
+    close(fd as usize);
+}
+
+/// exec 和 spawn `path` 都失败
+fn assert_fails(path: &str) {
+    assert_eq!(exec(path, &[path.as_ptr(), null()]), -1);
+    assert_eq!(spawn(path), -1);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // 失败的 exec 不能关掉调用者打开的文件
+    create(FNAME, b"still here");
+    let fd = open(FNAME, OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+
+    assert_fails("ch6b_no_such_app\0");
+    assert_fails("/\0");
+    create("ch6b_not_elf\0", b"hello, not an elf\n");
+    assert_fails("ch6b_not_elf\0");
+    create("ch6b_no_interp_sh\0", b"#!\n");
+    assert_fails("ch6b_no_interp_sh\0");
+    create("ch6b_missing_interp_sh\0", b"#!ch6b_no_such_app\n");
+    assert_fails("ch6b_missing_interp_sh\0");
+    let mut long_line = [b'a'; 300];
+    long_line[..2].copy_from_slice(b"#!");
+    long_line[299] = b'\n';
+    create("ch6b_long_interp_sh\0", &long_line);
+    assert_fails("ch6b_long_interp_sh\0");
+
+    let mut args = [LONG_ARG.as_ptr(); 21];
+    args[20] = null();
+    assert_eq!(exec("ch6b_exec_fail\0", &args), -1);
+
+    let mut buffer = [0u8; 16];
+    let len = read(fd, &mut buffer) as usize;
+    assert_eq!(&buffer[..len], b"still here");
+    close(fd);
+    println!("exec fail test passed!");
+    0
+}
@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{closedir, opendir, readdir, DT_DIR};
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut dir = match opendir("/\0") {
+        Some(dir) => dir,
+        None => {
+            println!("Error occured when opening directory");
+            return -1;
+        }
+    };
+    while let Some(entry) = readdir(&mut dir) {
+        let suffix = if entry.d_type == DT_DIR { "/" } else { "" };
+        println!("{:>6} {}{}", entry.ino, entry.name, suffix);
+    }
+    closedir(dir);
+    0
+}
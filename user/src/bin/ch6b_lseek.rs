@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, lseek, open, pread, pwrite, read, write, OpenFlags, SEEK_CUR, SEEK_END, SEEK_SET,
+};
+
+/// 测试 lseek, pread, pwrite 和 O_APPEND, 输出 lseek test passed! 就算正确。
+
+const FNAME: &str = "ch6b_lseek_file\0";
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = open(
+        FNAME,
+        OpenFlags::CREATE | OpenFlags::TRUNC | OpenFlags::RDWR,
+    );
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"0123456789"), 10);
+
+    let mut buffer = [0u8; 16];
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 10);
+    assert_eq!(lseek(fd, 2, SEEK_SET), 2);
+    assert_eq!(read(fd, &mut buffer[..3]), 3);
+    assert_eq!(&buffer[..3], b"234");
+    assert_eq!(lseek(fd, 1, SEEK_CUR), 6);
+    assert_eq!(lseek(fd, -1, SEEK_END), 9);
+    assert_eq!(read(fd, &mut buffer), 1);
+    assert_eq!(buffer[0], b'9');
+    assert_eq!(read(fd, &mut buffer), 0);
+    assert_eq!(lseek(fd, -1, SEEK_SET), -1);
+    assert_eq!(lseek(fd, 0, 3), -1);
+
+    // pread 和 pwrite 不移动读写位置
+    assert_eq!(lseek(fd, 3, SEEK_SET), 3);
+    assert_eq!(pread(fd, &mut buffer[..4], 5), 4);
+    assert_eq!(&buffer[..4], b"5678");
+    assert_eq!(pwrite(fd, b"ab", 0), 2);
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 3);
+    close(fd);
+
+    // 追加写总是写到文件末尾, pwrite 不受影响
+    let fd = open(FNAME, OpenFlags::WRONLY | OpenFlags::APPEND);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"xy"), 2);
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 12);
+    assert_eq!(lseek(fd, 0, SEEK_SET), 0);
+    assert_eq!(write(fd, b"z"), 1);
+    assert_eq!(pwrite(fd, b"Z", 1), 1);
+    // 只写打开的文件不能读
+    assert_eq!(pread(fd, &mut buffer, 0), -1);
+    close(fd);
+
+    let fd = open(FNAME, OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let len = read(fd, &mut buffer) as usize;
+    assert_eq!(&buffer[..len], b"aZ23456789xyz");
+    close(fd);
+    println!("lseek test passed!");
+    0
+}
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, exit, fork, pipe, read, wait, waitpid};
+
+/// 测试进程号的回收和进程数上限, 输出 pid recycle test passed! 就算正确。
+
+/// 内核的进程数上限
+const MAX_TASK_NUM: usize = 128;
+
+/// 创建立即以 `exit_code` 退出的子进程, 回收它并返回它的进程号
+fn run_child(exit_code: i32) -> isize {
+    let pid = fork();
+    if pid == 0 {
+        exit(exit_code);
+    }
+    assert!(pid > 0);
+    let mut code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut code), pid);
+    assert_eq!(code, exit_code);
+    pid
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // 回收的进程号会被下一个进程复用
+    let pid = run_child(1);
+    assert_eq!(run_child(2), pid);
+
+    // 一直创建阻塞在管道上的子进程, 直到达到上限
+    let mut pipe_fd = [0usize; 2];
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    let mut children = 0;
+    loop {
+        let pid = fork();
+        if pid == 0 {
+            close(pipe_fd[1]);
+            let mut buffer = [0u8; 1];
+            // 父进程关闭写端后读到文件结束
+            assert_eq!(read(pipe_fd[0], &mut buffer), 0);
+            exit(0);
+        }
+        if pid < 0 {
+            break;
+        }
+        children += 1;
+        assert!(children < MAX_TASK_NUM);
+    }
+    assert!(children > 0);
+    close(pipe_fd[1]);
+    close(pipe_fd[0]);
+    let mut exit_code: i32 = 0;
+    for _ in 0..children {
+        assert!(wait(&mut exit_code) > 0);
+        assert_eq!(exit_code, 0);
+    }
+    assert_eq!(wait(&mut exit_code), -1);
+
+    // 全部回收后又能创建进程
+    run_child(3);
+    println!(
+        "pid recycle test passed! {} children at the limit",
+        children
+    );
+    0
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::null_mut;
+use user_lib::{close, fork, nanosleep, pipe, read, waitpid, write, TimeSpec};
+
+/// 测试管道的读写, 阻塞, 文件结束和断开, 输出 pipe test passed! 就算正确。
+
+/// 比管道缓冲区大, 写端要阻塞几次
+const LARGE_LEN: usize = 3 * 4096 + 100;
+
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // 同一个进程里写入再读出
+    let mut pipe_fd = [0usize; 2];
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    assert_eq!(write(pipe_fd[1], b"hello"), 5);
+    let mut buffer = [0u8; 16];
+    assert_eq!(read(pipe_fd[0], &mut buffer), 5);
+    assert_eq!(&buffer[..5], b"hello");
+    // 写端都关闭后读到文件结束
+    close(pipe_fd[1]);
+    assert_eq!(read(pipe_fd[0], &mut buffer), 0);
+    close(pipe_fd[0]);
+
+    // 读端都关闭后写入失败
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    close(pipe_fd[0]);
+    assert_eq!(write(pipe_fd[1], b"lost"), -1);
+    close(pipe_fd[1]);
+
+    // 管道为空时读端阻塞, 直到子进程写入
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    let pid = fork();
+    if pid == 0 {
+        close(pipe_fd[0]);
+        nanosleep(&TimeSpec::from_ms(50), null_mut());
+        assert_eq!(write(pipe_fd[1], b"late"), 4);
+        close(pipe_fd[1]);
+        return 0;
+    }
+    close(pipe_fd[1]);
+    assert_eq!(read(pipe_fd[0], &mut buffer), 4);
+    assert_eq!(&buffer[..4], b"late");
+    assert_eq!(read(pipe_fd[0], &mut buffer), 0);
+    close(pipe_fd[0]);
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+
+    // 超过缓冲区大小的写入分几次完成, 数据不丢也不乱
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    let pid = fork();
+    if pid == 0 {
+        close(pipe_fd[0]);
+        let mut chunk = [0u8; 1000];
+        let mut written = 0;
+        while written < LARGE_LEN {
+            let len = chunk.len().min(LARGE_LEN - written);
+            for (i, byte) in chunk[..len].iter_mut().enumerate() {
+                *byte = pattern(written + i);
+            }
+            assert_eq!(write(pipe_fd[1], &chunk[..len]), len as isize);
+            written += len;
+        }
+        close(pipe_fd[1]);
+        return 0;
+    }
+    close(pipe_fd[1]);
+    let mut total = 0;
+    let mut chunk = [0u8; 512];
+    loop {
+        let len = read(pipe_fd[0], &mut chunk);
+        assert!(len >= 0);
+        if len == 0 {
+            break;
+        }
+        for (i, byte) in chunk[..len as usize].iter().enumerate() {
+            assert_eq!(*byte, pattern(total + i));
+        }
+        total += len as usize;
+    }
+    assert_eq!(total, LARGE_LEN);
+    close(pipe_fd[0]);
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+
+    println!("pipe test passed!");
+    0
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::null;
+use user_lib::{close, exec, fork, open, waitpid, write, OpenFlags};
+
+/// 测试以 "#!" 开头的脚本: 本程序作为解释器运行, 检查内核传来的参数。
+/// 输出 script test passed! 就算正确。
+
+/// 创建内容为 `data` 的文件 `path`
+fn create(path: &str, data: &[u8]) {
+    let fd = open(path, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, data), data.len() as isize);
+    close(fd as usize);
+}
+
+/// 在子进程里执行脚本, 等它成功退出
+fn run(path: &str, args: &[*const u8]) {
+    let pid = fork();
+    if pid == 0 {
+        exec(path, args);
+        panic!("exec {} failed", path);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+}
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc > 1 {
+        // 作为解释器运行: 解释器, "#!" 行的参数, 脚本路径, 原来除 argv[0] 以外的参数
+        let expected: &[&str] = match argc {
+            4 => &["ch6b_script", "probe arg", "/ch6b_script_sh", "x"],
+            // 解释器本身也是脚本
+            5 => &[
+                "ch6b_script",
+                "probe arg",
+                "ch6b_script_sh",
+                "/ch6b_nested_sh",
+                "y",
+            ],
+            _ => panic!("unexpected argc={}", argc),
+        };
+        assert_eq!(argv, expected);
+        return 0;
+    }
+
+    create(
+        "ch6b_script_sh\0",
+        b"#!ch6b_script  probe arg \necho ignored\n",
+    );
+    run(
+        "ch6b_script_sh\0",
+        &["ch6b_script_sh\0".as_ptr(), "x\0".as_ptr(), null()],
+    );
+    create("ch6b_nested_sh\0", b"#! ch6b_script_sh\n");
+    run(
+        "ch6b_nested_sh\0",
+        &["ch6b_nested_sh\0".as_ptr(), "y\0".as_ptr(), null()],
+    );
+    // 解释器嵌套太深时失败
+    create("ch6b_loop_sh\0", b"#!ch6b_loop_sh\n");
+    assert_eq!(
+        exec("ch6b_loop_sh\0", &["ch6b_loop_sh\0".as_ptr(), null()]),
+        -1
+    );
+    println!("script test passed!");
+    0
+}
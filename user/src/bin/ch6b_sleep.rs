@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::null_mut;
+use user_lib::{exit, fork, get_time, nanosleep, sleep, sleep_blocking, wait, TimeSpec};
+
+/// 测试 nanosleep, 输出 sleep test passed! 就算正确。
+
+const SLEEP_MS: usize = 200;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let start = get_time();
+    assert_eq!(nanosleep(&TimeSpec::from_ms(SLEEP_MS), null_mut()), 0);
+    assert!(get_time() - start >= SLEEP_MS as isize);
+
+    // 旧的毫秒接口仍然可用
+    let start = get_time();
+    sleep_blocking(SLEEP_MS);
+    sleep(SLEEP_MS);
+    assert!(get_time() - start >= 2 * SLEEP_MS as isize);
+
+    assert_eq!(nanosleep(&TimeSpec::from_ms(0), null_mut()), 0);
+    let bad = TimeSpec {
+        sec: 0,
+        nsec: 1_000_000_000,
+    };
+    assert_eq!(nanosleep(&bad, null_mut()), -1);
+
+    // 睡眠不占用 CPU, 两个子进程同时睡眠的总时间接近一次睡眠
+    let start = get_time();
+    for _ in 0..2 {
+        if fork() == 0 {
+            nanosleep(&TimeSpec::from_ms(SLEEP_MS), null_mut());
+            exit(0);
+        }
+    }
+    let mut exit_code: i32 = 0;
+    for _ in 0..2 {
+        assert!(wait(&mut exit_code) > 0);
+        assert_eq!(exit_code, 0);
+    }
+    let elapsed = get_time() - start;
+    assert!(elapsed >= SLEEP_MS as isize);
+    assert!(elapsed < 2 * SLEEP_MS as isize);
+    println!("sleep test passed!");
+    0
+}
@@ -12,8 +12,40 @@ const DL: u8 = 0x7fu8;
 const BS: u8 = 0x08u8;
 
 use alloc::string::String;
+use alloc::vec::Vec;
 use user_lib::console::getchar;
-use user_lib::{exec, flush, fork, waitpid};
+use user_lib::{exec, flush, fork, fs_grow, fs_set_key, waitpid};
+
+/// Builtin `setkey <64 hex digits>`, run by the shell itself as only
+/// the init process and the shell it starts may set the filesystem master key
+fn set_key(args: &[&str]) {
+    let mut key = [0u8; 32];
+    if args.len() != 1 || args[0].len() != 2 * key.len() {
+        println!("usage: setkey <64 hex digits>");
+        return;
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        match u8::from_str_radix(args[0].get(2 * i..2 * i + 2).unwrap_or(""), 16) {
+            Ok(value) => *byte = value,
+            Err(_) => {
+                println!("usage: setkey <64 hex digits>");
+                return;
+            }
+        }
+    }
+    if fs_set_key(&key) != 0 {
+        println!("setkey: the key does not match the encrypted files");
+    }
+}
+
+/// Builtin `growfs`, letting the mounted filesystem use the whole disk after
+/// it has been grown, e.g. by `block_resize` in the QEMU monitor
+fn grow_fs() {
+    match fs_grow() {
+        -1 => println!("growfs: the disk can not be grown while mounted"),
+        blocks => println!("growfs: {} blocks", blocks),
+    }
+}
 
 #[no_mangle]
 pub fn main() -> i32 {
@@ -26,12 +58,27 @@ pub fn main() -> i32 {
         match c {
             LF | CR => {
                 print!("\n");
-                if !line.is_empty() {
-                    line.push('\0');
+                // the program and its arguments, each nul-terminated
+                let args: Vec<String> = line
+                    .split_whitespace()
+                    .map(|arg| {
+                        let mut arg = String::from(arg);
+                        arg.push('\0');
+                        arg
+                    })
+                    .collect();
+                let words: Vec<&str> = line.split_whitespace().collect();
+                if words.first() == Some(&"setkey") {
+                    set_key(&words[1..]);
+                } else if words.first() == Some(&"growfs") {
+                    grow_fs();
+                } else if !args.is_empty() {
+                    let mut args_addr: Vec<*const u8> = args.iter().map(|arg| arg.as_ptr()).collect();
+                    args_addr.push(0 as *const u8);
                     let pid = fork();
                     if pid == 0 {
                         // child process
-                        if exec(line.as_str(), &[0 as *const u8]) == -1 {
+                        if exec(args[0].as_str(), args_addr.as_slice()) == -1 {
                             println!("Error when executing!");
                             return -4;
                         }
@@ -42,8 +89,8 @@ pub fn main() -> i32 {
                         assert_eq!(pid, exit_pid);
                         println!("Shell: Process {} exited with code {}", pid, exit_code);
                     }
-                    line.clear();
                 }
+                line.clear();
                 print!(">> ");
                 flush();
             }
@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+static TESTS: &[&str] = &[
+    "ch6b_lseek\0",
+    "ch6b_dup\0",
+    "ch6b_pipe\0",
+    "ch6b_cwd\0",
+    "ch6b_argv\0",
+    "ch6b_exec_fail\0",
+    "ch6b_script\0",
+    "ch6b_waitpid\0",
+    "ch6b_pid_recycle\0",
+    "ch6b_sleep\0",
+];
+
+use user_lib::{spawn, waitpid};
+
+/// 辅助测例，依次运行 os6 新增系统调用的测例, 在 shell 中输入 ch6b_usertest 运行,
+/// 每个测例都要以 0 退出。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    for test in TESTS {
+        println!("Usertests: Running {}", test);
+        let pid = spawn(*test);
+        assert!(pid > 0);
+        let mut xstate: i32 = Default::default();
+        let wait_pid = waitpid(pid as usize, &mut xstate);
+        assert_eq!(pid, wait_pid);
+        println!(
+            "\x1b[32mUsertests: Test {} in Process {} exited with code {}\x1b[0m",
+            test, pid, xstate
+        );
+        assert_eq!(xstate, 0);
+    }
+    println!("ch6b Usertests passed!");
+    0
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::null_mut;
+use user_lib::{exit, fork, get_time, nanosleep, wait, waitpid, waitpid_nohang, TimeSpec};
+
+/// 测试阻塞的 waitpid 和 wait, 输出 waitpid test passed! 就算正确。
+
+const SLEEP_MS: usize = 100;
+
+/// 创建睡眠 `ms` 毫秒后以 `exit_code` 退出的子进程
+fn sleepy_child(ms: usize, exit_code: i32) -> isize {
+    let pid = fork();
+    if pid == 0 {
+        nanosleep(&TimeSpec::from_ms(ms), null_mut());
+        exit(exit_code);
+    }
+    assert!(pid > 0);
+    pid
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut exit_code: i32 = 0;
+    // 没有子进程时立即失败
+    assert_eq!(wait(&mut exit_code), -1);
+
+    let start = get_time();
+    let pid = sleepy_child(SLEEP_MS, 7);
+    assert_eq!(waitpid_nohang(pid, &mut exit_code), -2);
+    // 不是自己的子进程
+    assert_eq!(waitpid(pid as usize + 1000, &mut exit_code), -1);
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 7);
+    assert!(get_time() - start >= SLEEP_MS as isize);
+    // 已经回收过了
+    assert_eq!(waitpid(pid as usize, &mut exit_code), -1);
+
+    // 只回收指定的子进程, 先退出的其他子进程留给 wait
+    let slow = sleepy_child(2 * SLEEP_MS, 2);
+    let fast = sleepy_child(SLEEP_MS / 2, 1);
+    assert_eq!(waitpid(slow as usize, &mut exit_code), slow);
+    assert_eq!(exit_code, 2);
+    assert_eq!(waitpid_nohang(-1, &mut exit_code), fast);
+    assert_eq!(exit_code, 1);
+
+    // wait 回收任意一个子进程
+    let first = sleepy_child(SLEEP_MS / 2, 3);
+    let second = sleepy_child(SLEEP_MS, 4);
+    assert_eq!(wait(&mut exit_code), first);
+    assert_eq!(exit_code, 3);
+    assert_eq!(wait(&mut exit_code), second);
+    assert_eq!(exit_code, 4);
+    assert_eq!(wait(&mut exit_code), -1);
+    println!("waitpid test passed!");
+    0
+}
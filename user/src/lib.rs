@@ -13,7 +13,9 @@ extern crate core;
 #[macro_use]
 extern crate bitflags;
 
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::convert::TryInto;
 use buddy_system_allocator::LockedHeap;
 pub use console::{flush, STDIN, STDOUT};
 pub use syscall::*;
@@ -44,18 +46,25 @@ fn clear_bss() {
     }
 }
 
-#[no_mangle]
-#[link_section = ".text.entry"]
-pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
-    clear_bss();
-    unsafe {
-        HEAP.lock()
-            .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
-    }
+/// The environment passed to this program, as `NAME=value` strings
+static mut ENVIRON: Vec<&'static str> = Vec::new();
+
+/// Read `count` strings from an array of pointers to nul-terminated strings,
+/// or all of them up to a null pointer if `count` is `None`
+fn c_strings(array: usize, count: Option<usize>) -> Vec<&'static str> {
     let mut v: Vec<&'static str> = Vec::new();
-    for i in 0..argc {
+    if array == 0 {
+        return v;
+    }
+    for i in 0.. {
+        if count == Some(i) {
+            break;
+        }
         let str_start =
-            unsafe { ((argv + i * core::mem::size_of::<usize>()) as *const usize).read_volatile() };
+            unsafe { ((array + i * core::mem::size_of::<usize>()) as *const usize).read_volatile() };
+        if str_start == 0 {
+            break;
+        }
         let len = (0usize..)
             .find(|i| unsafe { ((str_start + *i) as *const u8).read_volatile() == 0 })
             .unwrap();
@@ -66,9 +75,47 @@ pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
             .unwrap(),
         );
     }
+    v
+}
+
+#[no_mangle]
+#[link_section = ".text.entry"]
+pub extern "C" fn _start(argc: usize, argv: usize, envp: usize) -> ! {
+    clear_bss();
+    unsafe {
+        HEAP.lock()
+            .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
+        ENVIRON = c_strings(envp, None);
+    }
+    let v = c_strings(argv, Some(argc));
     exit(main(argc, v.as_slice()));
 }
 
+/// Get the environment of this program, as `NAME=value` strings
+pub fn env() -> &'static [&'static str] {
+    unsafe { ENVIRON.as_slice() }
+}
+
+/// Get the value of the environment variable `name`
+pub fn getenv(name: &str) -> Option<&'static str> {
+    env().iter().find_map(|var| {
+        let (var_name, value) = var.split_once('=')?;
+        if var_name == name {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// Pointers to the environment strings, which are nul-terminated on the
+/// stack, ended by a null pointer
+fn environ_ptrs() -> Vec<*const u8> {
+    let mut ptrs: Vec<*const u8> = env().iter().map(|var| var.as_ptr()).collect();
+    ptrs.push(core::ptr::null());
+    ptrs
+}
+
 #[linkage = "weak"]
 #[no_mangle]
 fn main(_argc: usize, _argv: &[&str]) -> i32 {
@@ -82,6 +129,8 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const APPEND = 1 << 11;
+        const CLOEXEC = 1 << 19;
     }
 }
 
@@ -98,6 +147,22 @@ impl TimeVal {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+impl TimeSpec {
+    pub fn from_ms(ms: usize) -> Self {
+        Self {
+            sec: ms / 1000,
+            nsec: ms % 1000 * 1_000_000,
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TaskStatus {
     UnInit,
@@ -131,6 +196,25 @@ impl TaskInfo {
     }
 }
 
+/// 一个用户的磁盘用量与配额, 限额为 0 表示不限
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QuotaInfo {
+    pub uid: u32,
+    /// 占用的数据块与索引块数
+    pub blocks: u32,
+    /// 占用的 inode 数
+    pub inodes: u32,
+    pub block_soft: u32,
+    pub block_hard: u32,
+    pub inode_soft: u32,
+    pub inode_hard: u32,
+    /// 超出软限额后还能分配块的次数
+    pub block_grace: u32,
+    /// 超出软限额后还能分配 inode 的次数
+    pub inode_grace: u32,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Stat {
@@ -174,12 +258,32 @@ bitflags! {
     }
 }
 
-const AT_FDCWD: isize = -100;
+/// The `dirfd` making `*at` calls resolve relative paths from the working directory
+pub const AT_FDCWD: isize = -100;
 
 pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_openat(AT_FDCWD as usize, path, flags.bits, OpenFlags::RDWR.bits)
 }
 
+/// Open `path`, relative paths starting from the directory `dirfd` is open on
+pub fn openat(dirfd: usize, path: &str, flags: OpenFlags) -> isize {
+    sys_openat(dirfd, path, flags.bits, OpenFlags::RDWR.bits)
+}
+
+pub fn chdir(path: &str) -> isize {
+    sys_chdir(path)
+}
+
+pub fn fchdir(fd: usize) -> isize {
+    sys_fchdir(fd)
+}
+
+/// Write the path of the working directory and a '\0' to `buf`,
+/// return the length written or -1 if it does not fit
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    sys_getcwd(buf)
+}
+
 pub fn close(fd: usize) -> isize {
     if fd == STDOUT {
         console::flush();
@@ -187,6 +291,64 @@ pub fn close(fd: usize) -> isize {
     sys_close(fd)
 }
 
+/// Directory entry type of a directory
+pub const DT_DIR: u8 = 4;
+/// Directory entry type of a regular file
+pub const DT_REG: u8 = 8;
+
+/// An open directory stream, see [`opendir`] and [`readdir`]
+pub struct Dir {
+    fd: usize,
+    buf: [u8; 512],
+    pos: usize,
+    len: usize,
+}
+
+/// A directory entry returned by [`readdir`]
+#[derive(Debug)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub d_type: u8,
+    pub name: String,
+}
+
+pub fn opendir(path: &str) -> Option<Dir> {
+    let fd = open(path, OpenFlags::RDONLY);
+    if fd < 0 {
+        return None;
+    }
+    Some(Dir {
+        fd: fd as usize,
+        buf: [0; 512],
+        pos: 0,
+        len: 0,
+    })
+}
+
+pub fn readdir(dir: &mut Dir) -> Option<DirEntry> {
+    if dir.pos == dir.len {
+        let len = sys_getdents64(dir.fd, &mut dir.buf);
+        if len <= 0 {
+            return None;
+        }
+        dir.pos = 0;
+        dir.len = len as usize;
+    }
+    // struct linux_dirent64 { d_ino: u64, d_off: i64, d_reclen: u16, d_type: u8, d_name: [u8] }
+    let record = &dir.buf[dir.pos..dir.len];
+    let ino = u64::from_ne_bytes(record[0..8].try_into().unwrap());
+    let reclen = u16::from_ne_bytes(record[16..18].try_into().unwrap()) as usize;
+    let d_type = record[18];
+    let name_len = record[19..reclen].iter().position(|c| *c == 0).unwrap();
+    let name = String::from(core::str::from_utf8(&record[19..19 + name_len]).unwrap());
+    dir.pos += reclen;
+    Some(DirEntry { ino, d_type, name })
+}
+
+pub fn closedir(dir: Dir) -> isize {
+    close(dir.fd)
+}
+
 pub fn read(fd: usize, buf: &mut [u8]) -> isize {
     sys_read(fd, buf)
 }
@@ -195,6 +357,28 @@ pub fn write(fd: usize, buf: &[u8]) -> isize {
     sys_write(fd, buf)
 }
 
+/// Seek relative to the start of the file
+pub const SEEK_SET: usize = 0;
+/// Seek relative to the current offset
+pub const SEEK_CUR: usize = 1;
+/// Seek relative to the end of the file
+pub const SEEK_END: usize = 2;
+
+/// Move the offset of `fd`, return the new offset
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    sys_lseek(fd, offset, whence)
+}
+
+/// Read from `offset` in `fd`, leaving its offset alone
+pub fn pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+    sys_pread64(fd, buf, offset)
+}
+
+/// Write at `offset` in `fd`, leaving its offset alone
+pub fn pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+    sys_pwrite64(fd, buf, offset)
+}
+
 pub fn link(old_path: &str, new_path: &str) -> isize {
     sys_linkat(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path, 0)
 }
@@ -203,6 +387,33 @@ pub fn unlink(path: &str) -> isize {
     sys_unlinkat(AT_FDCWD as usize, path, 0)
 }
 
+/// 设置文件系统主密钥, 之后才能读写加密目录下的文件.
+/// 只有初始进程和它启动的 shell 可以设置, 参见 shell 的 `setkey` 命令
+pub fn fs_set_key(key: &[u8; 32]) -> isize {
+    sys_fs_set_key(key)
+}
+
+/// 读取各用户的磁盘用量, 返回用户总数, 可能多于 `quotas` 的长度
+pub fn quota_report(quotas: &mut [QuotaInfo]) -> isize {
+    sys_quota_report(quotas)
+}
+
+/// 文件的碎片化程度 (0~100), fd 为 -1 时为整个文件系统的
+pub fn frag_score(fd: isize) -> isize {
+    sys_frag_score(fd)
+}
+
+/// 整理文件的碎片, 返回整理之后的碎片化程度.
+/// fd 为 -1 时整理整个文件系统, 只有初始进程和它启动的 shell 可以这样做
+pub fn defrag(fd: isize) -> isize {
+    sys_defrag(fd)
+}
+
+/// 磁盘扩容之后让文件系统用上新增的块, 返回文件系统现在的块数
+pub fn fs_grow() -> isize {
+    sys_fs_grow()
+}
+
 pub fn fstat(fd: usize, st: &Stat) -> isize {
     sys_fstat(fd, st)
 }
@@ -240,17 +451,30 @@ pub fn fork() -> isize {
     sys_fork()
 }
 
+/// Run the program at `path` in place of this one, passing it the
+/// null-terminated argument pointers `args` and the current environment
 pub fn exec(path: &str, args: &[*const u8]) -> isize {
-    sys_exec(path, args)
+    execve(path, args, &environ_ptrs())
+}
+
+/// Like [`exec`], with the environment given as null-terminated
+/// pointers to `NAME=value` strings
+pub fn execve(path: &str, args: &[*const u8], envs: &[*const u8]) -> isize {
+    sys_exec(path, args, envs)
 }
 
 pub fn set_priority(prio: isize) -> isize {
     sys_set_priority(prio)
 }
 
+/// `waitpid` option: return -2 instead of blocking when no child has exited
+pub const WNOHANG: usize = 1;
+
+/// Wait until any child exits, return its pid or -1 if there are no children.
+/// Kernels that do not block in waitpid return -2 and are polled again
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
+        match sys_waitpid(-1, exit_code as *mut _, 0) {
             -2 => {
                 sys_yield();
             }
@@ -261,9 +485,10 @@ pub fn wait(exit_code: &mut i32) -> isize {
     }
 }
 
+/// Wait until child `pid` exits, return its pid or -1 if there is no such child
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(pid as isize, exit_code as *mut _) {
+        match sys_waitpid(pid as isize, exit_code as *mut _, 0) {
             -2 => {
                 sys_yield();
             }
@@ -274,16 +499,36 @@ pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     }
 }
 
+/// Like [`waitpid`], but return -2 at once when the child has not exited;
+/// `pid` of -1 waits for any child
+pub fn waitpid_nohang(pid: isize, exit_code: &mut i32) -> isize {
+    sys_waitpid(pid, exit_code as *mut _, WNOHANG)
+}
+
 pub fn sleep_blocking(sleep_ms: usize) {
     sys_sleep(sleep_ms);
 }
 
+#[cfg(not(feature = "nanosleep"))]
 pub fn sleep(period_ms: usize) {
     let start = get_time();
     while get_time() < start + period_ms as isize {
         sys_yield();
     }
 }
+
+/// Block for `period_ms` milliseconds without using the CPU
+#[cfg(feature = "nanosleep")]
+pub fn sleep(period_ms: usize) {
+    nanosleep(&TimeSpec::from_ms(period_ms), core::ptr::null_mut());
+}
+
+/// Block for `req` without using the CPU; `rem` is left untouched since
+/// sleeps are never interrupted. Only os6 has this syscall, built with
+/// the `nanosleep` feature its [`sleep`] uses it too
+pub fn nanosleep(req: &TimeSpec, rem: *mut TimeSpec) -> isize {
+    sys_nanosleep(req, rem)
+}
 pub fn mmap(start: usize, len: usize, prot: usize) -> isize {
     sys_mmap(start, len, prot)
 }
@@ -292,13 +537,38 @@ pub fn munmap(start: usize, len: usize) -> isize {
     sys_munmap(start, len)
 }
 
+/// Start the program at `path` as a child process, with `path` as its only
+/// argument and the current environment
 pub fn spawn(path: &str) -> isize {
-    sys_spawn(path)
+    spawnve(path, &[path.as_ptr(), core::ptr::null()], &environ_ptrs())
+}
+
+/// Like [`spawn`], with null-terminated argument and environment pointers
+pub fn spawnve(path: &str, args: &[*const u8], envs: &[*const u8]) -> isize {
+    sys_spawn(path, args, envs)
 }
 
 pub fn dup(fd: usize) -> isize {
     sys_dup(fd)
 }
+/// Make `newfd` a copy of `oldfd`, closing the file `newfd` had open
+pub fn dup2(oldfd: usize, newfd: usize) -> isize {
+    if oldfd == newfd {
+        // nothing to copy, but `oldfd` must still be open
+        let fd = sys_dup(oldfd);
+        if fd < 0 {
+            return -1;
+        }
+        sys_close(fd as usize);
+        return newfd as isize;
+    }
+    sys_dup3(oldfd, newfd, 0)
+}
+/// Like [`dup2`], with `OpenFlags::CLOEXEC` allowed in `flags`, and failing
+/// if `oldfd` and `newfd` are the same
+pub fn dup3(oldfd: usize, newfd: usize, flags: OpenFlags) -> isize {
+    sys_dup3(oldfd, newfd, flags.bits)
+}
 pub fn pipe(pipe_fd: &mut [usize]) -> isize {
     sys_pipe(pipe_fd)
 }